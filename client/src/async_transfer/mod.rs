@@ -0,0 +1,612 @@
+pub mod async_transfer {
+    use crate::crypto::crypto::{
+        unwrap_key_with_identity, wrap_key_for_recipients, StreamEncrypter, HEADER_SIZE,
+        RECIPIENTS_HEADER_SIZE,
+    };
+    use crate::protocol::protocol::{
+        check_ranged_download, data_message, download_request, end_message, hello_request,
+        hello_response, range_request, response, server_error, upload_request, upload_response,
+        Fields,
+    };
+    use crate::transfer::transfer::{
+        Payload, Progress, ProgressCallback, ShareOptions, TransferError,
+    };
+    use openssl::pkey::{PKey, Private, Public};
+    use openssl::rsa::{Padding, Rsa};
+    use openssl::sha::sha256;
+    use openssl::symm::{self, Cipher};
+    use rand::prelude::StdRng;
+    use rand::{RngCore, SeedableRng};
+    use simpletcp::simpletcp::Error::TcpError;
+    use std::convert::TryInto;
+    use std::future::poll_fn;
+    use std::io;
+    use std::io::ErrorKind;
+    use std::pin::Pin;
+    use std::task::{ready, Context, Poll};
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+    use tokio::net::{TcpStream, ToSocketAddrs};
+    use tokio::time::timeout;
+
+    // The framing below mirrors simpletcp's TcpStream, the dependency is pinned
+    // to the version it was written for and tested against simpletcp's TcpServer
+
+    /// Largest message simpletcp accepts
+    const MESSAGE_SIZE_LIMIT: usize = 4 * 1024 * 1024;
+    const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Contents of a message, encoded the same way as simpletcp's Message
+    struct Packet {
+        buffer: Vec<u8>,
+        read_pos: usize,
+    }
+
+    impl Packet {
+        fn read(&mut self, n: usize) -> Result<&[u8], TransferError> {
+            if self.buffer.len() - self.read_pos < n {
+                return Err(TransferError::CorruptedMessage);
+            }
+            let slice = &self.buffer[self.read_pos..self.read_pos + n];
+            self.read_pos += n;
+            Ok(slice)
+        }
+    }
+
+    impl Fields for Packet {
+        fn new() -> Self {
+            Self {
+                buffer: Vec::new(),
+                read_pos: 0,
+            }
+        }
+
+        fn write_u8(&mut self, n: u8) {
+            self.buffer.push(n);
+        }
+
+        fn write_i32(&mut self, n: i32) {
+            self.buffer.extend_from_slice(&n.to_le_bytes());
+        }
+
+        fn write_u32(&mut self, n: u32) {
+            self.buffer.extend_from_slice(&n.to_le_bytes());
+        }
+
+        fn write_u64(&mut self, n: u64) {
+            self.buffer.extend_from_slice(&n.to_le_bytes());
+        }
+
+        fn write_buffer(&mut self, buf: &[u8]) {
+            self.write_u32(buf.len() as u32);
+            self.buffer.extend_from_slice(buf);
+        }
+
+        fn read_i8(&mut self) -> Result<i8, TransferError> {
+            Ok(self.read(1)?[0] as i8)
+        }
+
+        fn read_u32(&mut self) -> Result<u32, TransferError> {
+            Ok(u32::from_le_bytes(self.read(4)?.try_into().unwrap()))
+        }
+
+        fn read_u64(&mut self) -> Result<u64, TransferError> {
+            Ok(u64::from_le_bytes(self.read(8)?.try_into().unwrap()))
+        }
+
+        fn read_buffer(&mut self) -> Result<&[u8], TransferError> {
+            let len = self.read_u32()? as usize;
+            self.read(len)
+        }
+    }
+
+    /// Connection to the server in simpletcp's wire format: length-prefixed frames,
+    /// an RSA key exchange and AES-256-CBC encrypted messages
+    struct Connection {
+        socket: TcpStream,
+        key: [u8; 32],
+        rand: StdRng,
+        read_buffer: Vec<u8>,
+        write_buffer: Vec<u8>,
+        written: usize,
+    }
+
+    impl Connection {
+        /// Connects to the server, verifies its fingerprint and negotiates the protocol
+        ///
+        /// Returns the connection and the server's feature bits
+        async fn connect<A: ToSocketAddrs>(
+            addr: A,
+            fingerprint: Option<Vec<u8>>,
+        ) -> Result<(Self, u64), TransferError> {
+            let mut conn = Self {
+                socket: TcpStream::connect(addr)
+                    .await
+                    .map_err(|err| TransferError::ConnectionFailed(TcpError(err)))?,
+                key: [0; 32],
+                rand: StdRng::from_entropy(),
+                read_buffer: Vec::new(),
+                write_buffer: Vec::new(),
+                written: 0,
+            };
+
+            // The server starts by sending its public key, the fingerprint is its hash
+            let public_key = poll_fn(|cx| conn.poll_frame(cx))
+                .await
+                .map_err(|err| TransferError::ConnectionFailed(TcpError(err)))?;
+            if let Some(expected) = fingerprint {
+                let received = sha256(&public_key);
+                if expected.len() != 32 || received != expected[..] {
+                    return Err(TransferError::FingerprintMismatch {
+                        expected,
+                        received: received.to_vec(),
+                    });
+                }
+            }
+            let rsa = Rsa::public_key_from_der(&public_key)?;
+            conn.rand.fill_bytes(&mut conn.key);
+            let mut encrypted_key = vec![0; rsa.size() as usize];
+            let size = rsa.public_encrypt(&conn.key, &mut encrypted_key, Padding::PKCS1_OAEP)?;
+            encrypted_key.truncate(size);
+            conn.queue_frame(&encrypted_key);
+
+            conn.send(&hello_request()).await?;
+            let features = hello_response(conn.receive_timeout().await?)?;
+            Ok((conn, features))
+        }
+
+        fn queue_frame(&mut self, body: &[u8]) {
+            self.write_buffer
+                .extend_from_slice(&(body.len() as u32).to_le_bytes());
+            self.write_buffer.extend_from_slice(body);
+        }
+
+        /// Encrypts `packet` and queues it, it is sent by [poll_send](#method.poll_send)
+        fn queue(&mut self, packet: &Packet) -> Result<(), TransferError> {
+            let mut iv = [0; 16];
+            self.rand.fill_bytes(&mut iv);
+            let encrypted =
+                symm::encrypt(Cipher::aes_256_cbc(), &self.key, Some(&iv), &packet.buffer)?;
+            let mut body = iv.to_vec();
+            body.extend_from_slice(&encrypted);
+            self.queue_frame(&body);
+            Ok(())
+        }
+
+        /// Writes everything queued to the socket
+        fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            while self.written < self.write_buffer.len() {
+                let n =
+                    ready!(Pin::new(&mut self.socket)
+                        .poll_write(cx, &self.write_buffer[self.written..]))?;
+                if n == 0 {
+                    return Poll::Ready(Err(io::Error::from(ErrorKind::WriteZero)));
+                }
+                self.written += n;
+            }
+            self.write_buffer.clear();
+            self.written = 0;
+            Poll::Ready(Ok(()))
+        }
+
+        async fn send(&mut self, packet: &Packet) -> Result<(), TransferError> {
+            self.queue(packet)?;
+            poll_fn(|cx| self.poll_send(cx))
+                .await
+                .map_err(|err| TransferError::NetworkError(TcpError(err)))?;
+            Ok(())
+        }
+
+        /// Reads a whole frame, data received so far is kept if it is not complete yet
+        fn poll_frame(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Vec<u8>>> {
+            loop {
+                if self.read_buffer.len() >= 4 {
+                    let len =
+                        u32::from_le_bytes(self.read_buffer[..4].try_into().unwrap()) as usize;
+                    if len > MESSAGE_SIZE_LIMIT {
+                        return Poll::Ready(Err(io::Error::new(
+                            ErrorKind::InvalidData,
+                            "message exceeds the size limit",
+                        )));
+                    }
+                    if self.read_buffer.len() >= 4 + len {
+                        let frame = self.read_buffer[4..4 + len].to_vec();
+                        self.read_buffer.drain(..4 + len);
+                        return Poll::Ready(Ok(frame));
+                    }
+                }
+
+                let start = self.read_buffer.len();
+                self.read_buffer.resize(start + 64 * 1024, 0);
+                let mut buf = ReadBuf::new(&mut self.read_buffer[start..]);
+                let result = Pin::new(&mut self.socket).poll_read(cx, &mut buf);
+                let bytes = buf.filled().len();
+                self.read_buffer.truncate(start + bytes);
+                match result {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                    Poll::Ready(Ok(())) if bytes == 0 => {
+                        return Poll::Ready(Err(io::Error::from(ErrorKind::UnexpectedEof)))
+                    }
+                    Poll::Ready(Ok(())) => {}
+                }
+            }
+        }
+
+        fn poll_receive(&mut self, cx: &mut Context<'_>) -> Poll<Result<Packet, TransferError>> {
+            let frame = ready!(self.poll_frame(cx))
+                .map_err(|err| TransferError::NetworkError(TcpError(err)))?;
+            if frame.len() < 16 {
+                return Poll::Ready(Err(TransferError::CorruptedMessage));
+            }
+            let buffer = symm::decrypt(
+                Cipher::aes_256_cbc(),
+                &self.key,
+                Some(&frame[..16]),
+                &frame[16..],
+            )?;
+            Poll::Ready(Ok(Packet {
+                buffer,
+                read_pos: 0,
+            }))
+        }
+
+        /// Waits for a message, `None` if none arrived in time
+        async fn receive_timeout(&mut self) -> Result<Option<Packet>, TransferError> {
+            match timeout(RESPONSE_TIMEOUT, poll_fn(|cx| self.poll_receive(cx))).await {
+                Ok(packet) => Ok(Some(packet?)),
+                Err(_) => Ok(None),
+            }
+        }
+    }
+
+    /// Counterpart of [Upload](../../transfer/transfer/struct.Upload.html) for tokio
+    ///
+    /// Everything written is uploaded, [finalize](#method.finalize) completes the upload.
+    /// Lost connections are not resumed.
+    pub struct AsyncUpload {
+        conn: Connection,
+        encrypter: Option<StreamEncrypter>,
+        id: Vec<u8>,
+        key: Option<[u8; 32]>,
+        uploaded: usize,
+        time: Instant,
+        progress: Option<ProgressCallback>,
+        expiration: Option<u64>,
+        delete_token: Option<Vec<u8>>,
+    }
+
+    impl AsyncUpload {
+        pub async fn new<A: ToSocketAddrs>(
+            addr: A,
+            encrypt: bool,
+            size: usize,
+            options: ShareOptions,
+            recipients: &[PKey<Public>],
+            fingerprint: Option<Vec<u8>>,
+        ) -> Result<Self, TransferError> {
+            let (mut conn, features) = Connection::connect(addr, fingerprint).await?;
+            conn.send(&upload_request(features, encrypt, &options)?)
+                .await?;
+            let response = upload_response(conn.receive_timeout().await?, features, size)?;
+
+            let mut upload = Self {
+                conn,
+                encrypter: None,
+                id: response.id,
+                key: None,
+                uploaded: 0,
+                time: Instant::now(),
+                progress: None,
+                expiration: response.expiration,
+                delete_token: response.delete_token,
+            };
+
+            if encrypt {
+                let mut key = [0; 32];
+                StdRng::from_entropy().fill_bytes(&mut key);
+                upload.key = Some(key);
+
+                // Recipients find the content key in the block before the stream
+                if !recipients.is_empty() {
+                    let block = wrap_key_for_recipients(&key, recipients)?;
+                    upload.uploaded += block.len();
+                    upload.queue_data(&block)?;
+                }
+
+                let encrypter = StreamEncrypter::new(&key);
+                let header = encrypter.header();
+                upload.encrypter = Some(encrypter);
+                upload.queue_data(&header)?;
+                upload.uploaded += header.len();
+                poll_fn(|cx| upload.conn.poll_send(cx)).await?;
+            }
+
+            Ok(upload)
+        }
+
+        pub async fn finalize(&mut self) -> Result<(), TransferError> {
+            if let Some(encrypter) = &mut self.encrypter {
+                let mut data = Vec::new();
+                encrypter.finalize(&mut data)?;
+                self.queue_data(&data)?;
+            }
+
+            self.conn.send(&end_message()).await?;
+            response(self.conn.receive_timeout().await?)?;
+            Ok(())
+        }
+
+        fn queue_data(&mut self, data: &[u8]) -> Result<(), TransferError> {
+            self.conn.queue(&data_message(data))
+        }
+
+        /// Fails if the server has already reported an error, does not wait for one
+        fn check_for_error(&mut self, cx: &mut Context<'_>) -> Result<(), TransferError> {
+            if let Poll::Ready(packet) = self.conn.poll_receive(cx) {
+                let mut packet = packet?;
+                if packet.read_i8()? == -1 {
+                    return Err(server_error(&mut packet));
+                }
+            }
+            Ok(())
+        }
+
+        /// Reports the progress of the upload to `progress`
+        pub fn set_progress(&mut self, progress: ProgressCallback) {
+            self.progress = Some(progress);
+        }
+
+        pub fn id(&self) -> &Vec<u8> {
+            &self.id
+        }
+
+        pub fn key(&self) -> Option<&[u8; 32]> {
+            self.key.as_ref()
+        }
+
+        /// Unix timestamp the upload expires at, `None` if the server does not report it
+        pub fn expiration(&self) -> Option<u64> {
+            self.expiration
+        }
+
+        /// Secret needed to delete the upload, `None` if the server does not support it
+        pub fn delete_token(&self) -> Option<&Vec<u8>> {
+            self.delete_token.as_ref()
+        }
+    }
+
+    impl AsyncWrite for AsyncUpload {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buffer: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let upload = &mut *self;
+
+            // Data of the previous write has to be sent before more is accepted
+            ready!(upload.conn.poll_send(cx))?;
+            upload.check_for_error(cx)?;
+
+            let data = match &mut upload.encrypter {
+                None => buffer.to_vec(),
+                Some(encrypter) => {
+                    let mut data = Vec::new();
+                    encrypter.update(buffer, &mut data)?;
+                    data
+                }
+            };
+
+            // Encrypted data is sent in whole chunks, skip writes that did not complete one
+            if !data.is_empty() {
+                upload.queue_data(&data)?;
+                if let Poll::Ready(Err(err)) = upload.conn.poll_send(cx) {
+                    return Poll::Ready(Err(err));
+                }
+            }
+
+            upload.uploaded += buffer.len();
+            if let Some(progress) = &mut upload.progress {
+                progress(Progress::Transferred {
+                    bytes: upload.uploaded as u64,
+                    elapsed: upload.time.elapsed(),
+                });
+            }
+
+            Poll::Ready(Ok(buffer.len()))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            let upload = &mut *self;
+            ready!(upload.conn.poll_send(cx))?;
+            Pin::new(&mut upload.conn.socket).poll_flush(cx)
+        }
+
+        /// Only flushes, the connection stays open for [finalize](#method.finalize)
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.poll_flush(cx)
+        }
+    }
+
+    /// Counterpart of [Download](../../transfer/transfer/struct.Download.html) for tokio
+    pub struct AsyncDownload {
+        conn: Connection,
+        payload: Payload,
+        downloaded: usize,
+        time: Instant,
+        progress: Option<ProgressCallback>,
+        position: u64,
+    }
+
+    impl AsyncDownload {
+        pub async fn new<A: ToSocketAddrs>(
+            addr: A,
+            id: &[u8; 32],
+            key: Option<[u8; 32]>,
+            fingerprint: Option<Vec<u8>>,
+        ) -> Result<Self, TransferError> {
+            let (mut conn, _) = Connection::connect(addr, fingerprint).await?;
+            conn.send(&download_request(id)).await?;
+            Ok(Self {
+                conn,
+                payload: Payload::new(key),
+                time: Instant::now(),
+                downloaded: 0,
+                progress: None,
+                position: 0,
+            })
+        }
+
+        /// Starts a download that skips the first `offset` bytes of the payload
+        ///
+        /// Encrypted payload can only be resumed at a chunk boundary, so the download
+        /// may start earlier, check [position](#method.position) for the actual start.
+        pub async fn resume<A: ToSocketAddrs>(
+            addr: A,
+            id: &[u8; 32],
+            key: Option<[u8; 32]>,
+            offset: u64,
+            fingerprint: Option<Vec<u8>>,
+        ) -> Result<Self, TransferError> {
+            let mut download = Self::ranged(addr, offset, fingerprint).await?;
+            download.start_at(id, key, 0, offset).await?;
+            Ok(download)
+        }
+
+        /// Same as [resume](#method.resume) for a download sent to recipients,
+        /// the content key is unwrapped from the recipient block with `identity`
+        pub async fn resume_with_identity<A: ToSocketAddrs>(
+            addr: A,
+            id: &[u8; 32],
+            identity: &PKey<Private>,
+            offset: u64,
+            fingerprint: Option<Vec<u8>>,
+        ) -> Result<Self, TransferError> {
+            let mut download = Self::ranged(addr, offset, fingerprint).await?;
+            let mut block = download
+                .read_range(id, 0, RECIPIENTS_HEADER_SIZE as u64)
+                .await?;
+            let size = Payload::recipient_block_size(&block)?;
+            block.extend(
+                download
+                    .read_range(
+                        id,
+                        RECIPIENTS_HEADER_SIZE as u64,
+                        (size - RECIPIENTS_HEADER_SIZE) as u64,
+                    )
+                    .await?,
+            );
+            let key = unwrap_key_with_identity(&block, identity)?;
+            download
+                .start_at(id, Some(key), size as u64, offset)
+                .await?;
+            Ok(download)
+        }
+
+        /// Connects for a download starting at `offset` of the payload
+        async fn ranged<A: ToSocketAddrs>(
+            addr: A,
+            offset: u64,
+            fingerprint: Option<Vec<u8>>,
+        ) -> Result<Self, TransferError> {
+            let (conn, features) = Connection::connect(addr, fingerprint).await?;
+            check_ranged_download(features)?;
+            Ok(Self {
+                conn,
+                payload: Payload::new(None),
+                time: Instant::now(),
+                downloaded: 0,
+                progress: None,
+                position: offset,
+            })
+        }
+
+        /// Requests the rest of the payload from `offset` of the stream, which starts
+        /// at `base` of the stored payload
+        async fn start_at(
+            &mut self,
+            id: &[u8; 32],
+            key: Option<[u8; 32]>,
+            base: u64,
+            offset: u64,
+        ) -> Result<(), TransferError> {
+            let mut start = offset;
+            if let Some(key) = key {
+                let header = self.read_range(id, base, HEADER_SIZE as u64).await?;
+                let (position, range_start) = self.payload.resume_at(&key, &header, offset)?;
+                self.position = position;
+                start = range_start;
+            }
+
+            self.conn.send(&range_request(id, base + start, 0)).await?;
+            Ok(())
+        }
+
+        /// Downloads `length` bytes of the stored payload from `offset`
+        async fn read_range(
+            &mut self,
+            id: &[u8; 32],
+            offset: u64,
+            length: u64,
+        ) -> Result<Vec<u8>, TransferError> {
+            self.conn.send(&range_request(id, offset, length)).await?;
+            while !self.payload.finalized() {
+                poll_fn(|cx| self.poll_receive(cx)).await?;
+            }
+            Ok(self.payload.take_range())
+        }
+
+        /// Reads the recipient block and decrypts the rest of the download
+        /// with the content key wrapped for `identity`
+        pub async fn unlock(&mut self, identity: &PKey<Private>) -> Result<(), TransferError> {
+            let mut header = [0; RECIPIENTS_HEADER_SIZE];
+            self.read_exact(&mut header).await?;
+            let mut block = header.to_vec();
+            block.resize(Payload::recipient_block_size(&header)?, 0);
+            self.read_exact(&mut block[RECIPIENTS_HEADER_SIZE..])
+                .await?;
+            self.payload.unlock(&block, identity)
+        }
+
+        /// Position in the payload the download started at
+        pub fn position(&self) -> u64 {
+            self.position
+        }
+
+        /// Reports the progress of the download to `progress`
+        pub fn set_progress(&mut self, progress: ProgressCallback) {
+            self.progress = Some(progress);
+        }
+
+        fn poll_receive(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            let mut packet = ready!(self.conn.poll_receive(cx))?;
+            Poll::Ready(self.payload.receive(&mut packet))
+        }
+    }
+
+    impl AsyncRead for AsyncDownload {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let download = &mut *self;
+
+            // Only plaintext of authenticated chunks ever gets into the payload
+            while download.payload.needs_message() {
+                ready!(download.poll_receive(cx))?;
+            }
+
+            let bytes = download.payload.read(buf.initialize_unfilled());
+            buf.advance(bytes);
+            download.downloaded += bytes;
+            if let Some(progress) = &mut download.progress {
+                progress(Progress::Transferred {
+                    bytes: download.downloaded as u64,
+                    elapsed: download.time.elapsed(),
+                });
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+}
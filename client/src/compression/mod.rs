@@ -0,0 +1,138 @@
+pub mod compression {
+    use flate2::read::GzDecoder;
+    use flate2::write::GzEncoder;
+    use std::io;
+    use std::io::{BufReader, ErrorKind, Read, Write};
+    use std::str::FromStr;
+
+    /// Compression of the archive, recorded in the manifest
+    #[derive(Clone, Copy, PartialEq)]
+    pub enum Codec {
+        None,
+        Zstd,
+        Gzip,
+    }
+
+    impl Codec {
+        pub fn from_u8(value: u8) -> io::Result<Self> {
+            match value {
+                0 => Ok(Codec::None),
+                1 => Ok(Codec::Zstd),
+                2 => Ok(Codec::Gzip),
+                _ => Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unsupported compression {}", value),
+                )),
+            }
+        }
+
+        pub fn to_u8(self) -> u8 {
+            match self {
+                Codec::None => 0,
+                Codec::Zstd => 1,
+                Codec::Gzip => 2,
+            }
+        }
+
+        pub fn default_level(self) -> i32 {
+            match self {
+                Codec::None => 0,
+                Codec::Zstd => 3,
+                Codec::Gzip => 6,
+            }
+        }
+
+        pub fn valid_level(self, level: i32) -> bool {
+            match self {
+                Codec::None => level == 0,
+                Codec::Zstd => (1..=22).contains(&level),
+                Codec::Gzip => (0..=9).contains(&level),
+            }
+        }
+    }
+
+    impl FromStr for Codec {
+        type Err = ();
+
+        fn from_str(str: &str) -> Result<Self, Self::Err> {
+            match str {
+                "none" => Ok(Codec::None),
+                "zstd" => Ok(Codec::Zstd),
+                "gzip" => Ok(Codec::Gzip),
+                _ => Err(()),
+            }
+        }
+    }
+
+    pub enum Compressor<W: Write> {
+        None(W),
+        Zstd(zstd::Encoder<'static, W>),
+        Gzip(GzEncoder<W>),
+    }
+
+    impl<W: Write> Compressor<W> {
+        pub fn new(codec: Codec, level: i32, writer: W) -> io::Result<Self> {
+            Ok(match codec {
+                Codec::None => Compressor::None(writer),
+                Codec::Zstd => Compressor::Zstd(zstd::Encoder::new(writer, level)?),
+                Codec::Gzip => Compressor::Gzip(GzEncoder::new(
+                    writer,
+                    flate2::Compression::new(level as u32),
+                )),
+            })
+        }
+
+        /// Writes the end of the compressed stream and returns the underlying writer
+        pub fn finish(self) -> io::Result<W> {
+            match self {
+                Compressor::None(writer) => Ok(writer),
+                Compressor::Zstd(encoder) => encoder.finish(),
+                Compressor::Gzip(encoder) => encoder.finish(),
+            }
+        }
+    }
+
+    impl<W: Write> Write for Compressor<W> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            match self {
+                Compressor::None(writer) => writer.write(buf),
+                Compressor::Zstd(encoder) => encoder.write(buf),
+                Compressor::Gzip(encoder) => encoder.write(buf),
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            match self {
+                Compressor::None(writer) => writer.flush(),
+                Compressor::Zstd(encoder) => encoder.flush(),
+                Compressor::Gzip(encoder) => encoder.flush(),
+            }
+        }
+    }
+
+    pub enum Decompressor<R: Read> {
+        None(R),
+        Zstd(zstd::Decoder<'static, BufReader<R>>),
+        Gzip(GzDecoder<R>),
+    }
+
+    impl<R: Read> Decompressor<R> {
+        pub fn new(codec: Codec, reader: R) -> io::Result<Self> {
+            Ok(match codec {
+                Codec::None => Decompressor::None(reader),
+                Codec::Zstd => Decompressor::Zstd(zstd::Decoder::new(reader)?),
+                Codec::Gzip => Decompressor::Gzip(GzDecoder::new(reader)),
+            })
+        }
+    }
+
+    impl<R: Read> Read for Decompressor<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self {
+                Decompressor::None(reader) => reader.read(buf),
+                Decompressor::Zstd(decoder) => decoder.read(buf),
+                Decompressor::Gzip(decoder) => decoder.read(buf),
+            }
+        }
+    }
+}
@@ -0,0 +1,449 @@
+pub mod crypto {
+    use openssl::derive::Deriver;
+    use openssl::error::ErrorStack;
+    use openssl::pkcs5::scrypt;
+    use openssl::pkey::{HasPublic, Id, PKey, Private, Public};
+    use openssl::sha::Sha256;
+    use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+    use rand::prelude::StdRng;
+    use rand::{RngCore, SeedableRng};
+    use std::error::Error;
+    use std::fmt::{Display, Formatter};
+    use std::io::ErrorKind;
+    use std::{fmt, io};
+
+    // Encrypted stream layout:
+    //   header: version (1 B) | nonce prefix (7 B)
+    //   chunks: AES-256-GCM(plaintext chunk) | tag (16 B)
+    // Every chunk except the last one carries exactly CHUNK_SIZE bytes of plaintext,
+    // the last one carries less (possibly nothing). The nonce of each chunk is
+    // nonce prefix | chunk counter (u32 BE) | last chunk flag, so reordered, dropped
+    // or truncated chunks fail authentication.
+    pub const STREAM_VERSION: u8 = 1;
+    pub const HEADER_SIZE: usize = 8;
+    pub const CHUNK_SIZE: usize = 64 * 1024;
+    pub const TAG_SIZE: usize = 16;
+    pub const ENCRYPTED_CHUNK_SIZE: usize = CHUNK_SIZE + TAG_SIZE;
+
+    const NONCE_PREFIX_SIZE: usize = HEADER_SIZE - 1;
+
+    // Password wrapped key layout:
+    //   version (1 B) | salt (16 B) | AES-256-GCM(content key) | tag (16 B)
+    // The wrapping key is derived from the password with scrypt, the salt is unique
+    // for every upload, so a fixed nonce is never reused with the same key.
+    pub const WRAPPED_KEY_VERSION: u8 = 1;
+    pub const WRAPPED_KEY_SIZE: usize = 1 + SALT_SIZE + 32 + TAG_SIZE;
+
+    // Recipient block, precedes the encrypted stream of uploads for X25519 recipients:
+    //   version (1 B) | ephemeral public key (32 B) | recipient count (1 B)
+    //   per recipient: AES-256-GCM(content key) | tag (16 B)
+    // Every recipient's wrapping key is SHA-256(shared secret | ephemeral key | recipient key).
+    pub const RECIPIENTS_VERSION: u8 = 1;
+    pub const RECIPIENTS_HEADER_SIZE: usize = 1 + 32 + 1;
+    pub const RECIPIENT_SIZE: usize = 32 + TAG_SIZE;
+    pub const MAX_RECIPIENTS: usize = 255;
+
+    const SALT_SIZE: usize = 16;
+    const SCRYPT_N: u64 = 1 << 15;
+    const SCRYPT_R: u64 = 8;
+    const SCRYPT_P: u64 = 1;
+    const SCRYPT_MAX_MEMORY: u64 = 64 * 1024 * 1024;
+
+    #[derive(Debug)]
+    pub enum CryptoError {
+        Backend(ErrorStack),
+        AuthenticationFailed,
+        Truncated,
+        UnsupportedVersion(u8),
+        TooLong,
+        WrongPassword,
+        InvalidKey,
+        NotARecipient,
+    }
+
+    impl Display for CryptoError {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            match self {
+                CryptoError::Backend(err) => f.write_str(&format!("{}", err)),
+                CryptoError::AuthenticationFailed => {
+                    f.write_str("AuthenticationFailed: data was modified or corrupted")
+                }
+                CryptoError::Truncated => f.write_str("Truncated: data ended before final chunk"),
+                CryptoError::UnsupportedVersion(version) => {
+                    f.write_str(&format!("UnsupportedVersion: stream version {}", version))
+                }
+                CryptoError::TooLong => f.write_str("TooLong: chunk counter overflow"),
+                CryptoError::WrongPassword => {
+                    f.write_str("WrongPassword: password does not match the download key")
+                }
+                CryptoError::InvalidKey => f.write_str("InvalidKey: key is not an X25519 key"),
+                CryptoError::NotARecipient => {
+                    f.write_str("NotARecipient: file was not encrypted for this identity")
+                }
+            }
+        }
+    }
+
+    impl Error for CryptoError {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            match self {
+                CryptoError::Backend(err) => Some(err),
+                _ => None,
+            }
+        }
+    }
+
+    impl From<ErrorStack> for CryptoError {
+        fn from(err: ErrorStack) -> Self {
+            CryptoError::Backend(err)
+        }
+    }
+
+    impl From<CryptoError> for io::Error {
+        fn from(err: CryptoError) -> Self {
+            io::Error::new(ErrorKind::InvalidData, err)
+        }
+    }
+
+    fn nonce(prefix: &[u8; NONCE_PREFIX_SIZE], counter: u32, last: bool) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[..NONCE_PREFIX_SIZE].copy_from_slice(prefix);
+        nonce[NONCE_PREFIX_SIZE..11].copy_from_slice(&counter.to_be_bytes());
+        nonce[11] = last as u8;
+        nonce
+    }
+
+    pub struct StreamEncrypter {
+        key: [u8; 32],
+        nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+        counter: u32,
+        pending: Vec<u8>,
+    }
+
+    impl StreamEncrypter {
+        pub fn new(key: &[u8; 32]) -> Self {
+            let mut nonce_prefix = [0; NONCE_PREFIX_SIZE];
+            StdRng::from_entropy().fill_bytes(&mut nonce_prefix);
+            Self {
+                key: *key,
+                nonce_prefix,
+                counter: 0,
+                pending: Vec::with_capacity(CHUNK_SIZE),
+            }
+        }
+
+        pub fn header(&self) -> [u8; HEADER_SIZE] {
+            let mut header = [0; HEADER_SIZE];
+            header[0] = STREAM_VERSION;
+            header[1..].copy_from_slice(&self.nonce_prefix);
+            header
+        }
+
+        /// Appends every chunk completed by `input` to `output`
+        pub fn update(
+            &mut self,
+            mut input: &[u8],
+            output: &mut Vec<u8>,
+        ) -> Result<(), CryptoError> {
+            while !input.is_empty() {
+                let take = (CHUNK_SIZE - self.pending.len()).min(input.len());
+                self.pending.extend_from_slice(&input[..take]);
+                input = &input[take..];
+
+                // A full chunk is sealed only once more data arrives,
+                // the last chunk must always be shorter than CHUNK_SIZE
+                if self.pending.len() == CHUNK_SIZE && !input.is_empty() {
+                    self.seal(false, output)?;
+                }
+            }
+            Ok(())
+        }
+
+        /// Seals the remaining data as the last chunk
+        pub fn finalize(&mut self, output: &mut Vec<u8>) -> Result<(), CryptoError> {
+            if self.pending.len() == CHUNK_SIZE {
+                self.seal(false, output)?;
+            }
+            self.seal(true, output)
+        }
+
+        fn seal(&mut self, last: bool, output: &mut Vec<u8>) -> Result<(), CryptoError> {
+            let mut tag = [0; TAG_SIZE];
+            let ciphertext = encrypt_aead(
+                Cipher::aes_256_gcm(),
+                &self.key,
+                Some(&nonce(&self.nonce_prefix, self.counter, last)),
+                &[STREAM_VERSION],
+                &self.pending,
+                &mut tag,
+            )?;
+            output.extend_from_slice(&ciphertext);
+            output.extend_from_slice(&tag);
+            self.pending.clear();
+            self.counter = self.counter.checked_add(1).ok_or(CryptoError::TooLong)?;
+            Ok(())
+        }
+    }
+
+    pub struct StreamDecrypter {
+        key: [u8; 32],
+        nonce_prefix: Option<[u8; NONCE_PREFIX_SIZE]>,
+        counter: u32,
+        pending: Vec<u8>,
+    }
+
+    impl StreamDecrypter {
+        pub fn new(key: &[u8; 32]) -> Self {
+            Self::starting_at(key, 0)
+        }
+
+        /// Creates a decrypter for a stream that continues with chunk number `chunk`
+        /// after the header
+        pub fn starting_at(key: &[u8; 32], chunk: u32) -> Self {
+            Self {
+                key: *key,
+                nonce_prefix: None,
+                counter: chunk,
+                pending: Vec::new(),
+            }
+        }
+
+        /// Appends plaintext of every chunk that is known not to be the last one
+        /// and passed authentication to `output`
+        pub fn update(&mut self, input: &[u8], output: &mut Vec<u8>) -> Result<(), CryptoError> {
+            self.pending.extend_from_slice(input);
+
+            if self.nonce_prefix.is_none() {
+                if self.pending.len() < HEADER_SIZE {
+                    return Ok(());
+                }
+                if self.pending[0] != STREAM_VERSION {
+                    return Err(CryptoError::UnsupportedVersion(self.pending[0]));
+                }
+                let mut prefix = [0; NONCE_PREFIX_SIZE];
+                prefix.copy_from_slice(&self.pending[1..HEADER_SIZE]);
+                self.nonce_prefix = Some(prefix);
+                self.pending.drain(..HEADER_SIZE);
+            }
+
+            let mut offset = 0;
+            while self.pending.len() - offset > ENCRYPTED_CHUNK_SIZE {
+                let chunk = &self.pending[offset..offset + ENCRYPTED_CHUNK_SIZE];
+                output.extend_from_slice(&open(
+                    &self.key,
+                    &nonce(self.nonce_prefix.as_ref().unwrap(), self.counter, false),
+                    chunk,
+                )?);
+                self.counter = self.counter.checked_add(1).ok_or(CryptoError::TooLong)?;
+                offset += ENCRYPTED_CHUNK_SIZE;
+            }
+            self.pending.drain(..offset);
+            Ok(())
+        }
+
+        /// Verifies and decrypts the last chunk
+        pub fn finalize(&mut self, output: &mut Vec<u8>) -> Result<(), CryptoError> {
+            let prefix = match &self.nonce_prefix {
+                Some(prefix) if self.pending.len() >= TAG_SIZE => prefix,
+                _ => return Err(CryptoError::Truncated),
+            };
+            output.extend_from_slice(&open(
+                &self.key,
+                &nonce(prefix, self.counter, true),
+                &self.pending,
+            )?);
+            self.pending.clear();
+            Ok(())
+        }
+    }
+
+    fn open(key: &[u8; 32], nonce: &[u8; 12], chunk: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let (ciphertext, tag) = chunk.split_at(chunk.len() - TAG_SIZE);
+        decrypt_aead(
+            Cipher::aes_256_gcm(),
+            key,
+            Some(nonce),
+            &[STREAM_VERSION],
+            ciphertext,
+            tag,
+        )
+        .map_err(|_| CryptoError::AuthenticationFailed)
+    }
+
+    /// Encrypts the content `key` with a key derived from `password`
+    pub fn wrap_key(key: &[u8; 32], password: &str) -> Result<Vec<u8>, CryptoError> {
+        let mut salt = [0; SALT_SIZE];
+        StdRng::from_entropy().fill_bytes(&mut salt);
+        let wrapping_key = derive_key(password, &salt)?;
+
+        let mut tag = [0; TAG_SIZE];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &wrapping_key,
+            Some(&[0; 12]),
+            &[WRAPPED_KEY_VERSION],
+            key,
+            &mut tag,
+        )?;
+
+        let mut wrapped = Vec::with_capacity(WRAPPED_KEY_SIZE);
+        wrapped.push(WRAPPED_KEY_VERSION);
+        wrapped.extend_from_slice(&salt);
+        wrapped.extend_from_slice(&ciphertext);
+        wrapped.extend_from_slice(&tag);
+        Ok(wrapped)
+    }
+
+    /// Decrypts a content key produced by [wrap_key](fn.wrap_key.html)
+    pub fn unwrap_key(wrapped: &[u8], password: &str) -> Result<[u8; 32], CryptoError> {
+        if wrapped.len() != WRAPPED_KEY_SIZE {
+            return Err(CryptoError::Truncated);
+        }
+        if wrapped[0] != WRAPPED_KEY_VERSION {
+            return Err(CryptoError::UnsupportedVersion(wrapped[0]));
+        }
+        let (salt, sealed) = wrapped[1..].split_at(SALT_SIZE);
+        let (ciphertext, tag) = sealed.split_at(sealed.len() - TAG_SIZE);
+        let wrapping_key = derive_key(password, salt)?;
+
+        let key = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &wrapping_key,
+            Some(&[0; 12]),
+            &[WRAPPED_KEY_VERSION],
+            ciphertext,
+            tag,
+        )
+        .map_err(|_| CryptoError::WrongPassword)?;
+
+        let mut res = [0; 32];
+        res.copy_from_slice(&key);
+        Ok(res)
+    }
+
+    fn derive_key(password: &str, salt: &[u8]) -> Result<[u8; 32], CryptoError> {
+        let mut key = [0; 32];
+        scrypt(
+            password.as_bytes(),
+            salt,
+            SCRYPT_N,
+            SCRYPT_R,
+            SCRYPT_P,
+            SCRYPT_MAX_MEMORY,
+            &mut key,
+        )?;
+        Ok(key)
+    }
+
+    /// Wraps the content `key` for every recipient, returns the recipient block
+    pub fn wrap_key_for_recipients(
+        key: &[u8; 32],
+        recipients: &[PKey<Public>],
+    ) -> Result<Vec<u8>, CryptoError> {
+        if recipients.len() > MAX_RECIPIENTS {
+            return Err(CryptoError::TooLong);
+        }
+        let ephemeral = PKey::generate_x25519()?;
+        let ephemeral_public = ephemeral.raw_public_key()?;
+
+        let mut block =
+            Vec::with_capacity(RECIPIENTS_HEADER_SIZE + RECIPIENT_SIZE * recipients.len());
+        block.push(RECIPIENTS_VERSION);
+        block.extend_from_slice(&ephemeral_public);
+        block.push(recipients.len() as u8);
+        for recipient in recipients {
+            let wrapping_key = recipient_wrapping_key(&ephemeral, recipient, &ephemeral_public)?;
+            let mut tag = [0; TAG_SIZE];
+            let ciphertext = encrypt_aead(
+                Cipher::aes_256_gcm(),
+                &wrapping_key,
+                Some(&[0; 12]),
+                &[RECIPIENTS_VERSION],
+                key,
+                &mut tag,
+            )?;
+            block.extend_from_slice(&ciphertext);
+            block.extend_from_slice(&tag);
+        }
+        Ok(block)
+    }
+
+    /// Returns the number of recipients from the start of a recipient block
+    pub fn recipient_count(header: &[u8; RECIPIENTS_HEADER_SIZE]) -> Result<usize, CryptoError> {
+        if header[0] != RECIPIENTS_VERSION {
+            return Err(CryptoError::UnsupportedVersion(header[0]));
+        }
+        Ok(header[RECIPIENTS_HEADER_SIZE - 1] as usize)
+    }
+
+    /// Finds the content key wrapped for `identity` in a recipient block
+    pub fn unwrap_key_with_identity(
+        block: &[u8],
+        identity: &PKey<Private>,
+    ) -> Result<[u8; 32], CryptoError> {
+        if block.len() < RECIPIENTS_HEADER_SIZE {
+            return Err(CryptoError::Truncated);
+        }
+        let mut header = [0; RECIPIENTS_HEADER_SIZE];
+        header.copy_from_slice(&block[..RECIPIENTS_HEADER_SIZE]);
+        let count = recipient_count(&header)?;
+        if block.len() != RECIPIENTS_HEADER_SIZE + count * RECIPIENT_SIZE {
+            return Err(CryptoError::Truncated);
+        }
+
+        let ephemeral_public = &block[1..33];
+        let ephemeral = PKey::public_key_from_raw_bytes(ephemeral_public, Id::X25519)?;
+        let wrapping_key = {
+            let mut deriver = Deriver::new(identity)?;
+            deriver.set_peer(&ephemeral)?;
+            hash_wrapping_key(&deriver.derive_to_vec()?, ephemeral_public, identity)?
+        };
+
+        for wrapped in block[RECIPIENTS_HEADER_SIZE..].chunks(RECIPIENT_SIZE) {
+            let (ciphertext, tag) = wrapped.split_at(32);
+            if let Ok(key) = decrypt_aead(
+                Cipher::aes_256_gcm(),
+                &wrapping_key,
+                Some(&[0; 12]),
+                &[RECIPIENTS_VERSION],
+                ciphertext,
+                tag,
+            ) {
+                let mut res = [0; 32];
+                res.copy_from_slice(&key);
+                return Ok(res);
+            }
+        }
+        Err(CryptoError::NotARecipient)
+    }
+
+    /// Checks that `key` is an X25519 key
+    pub fn check_x25519<T>(key: &PKey<T>) -> Result<(), CryptoError> {
+        if key.id() != Id::X25519 {
+            return Err(CryptoError::InvalidKey);
+        }
+        Ok(())
+    }
+
+    fn recipient_wrapping_key(
+        ephemeral: &PKey<Private>,
+        recipient: &PKey<Public>,
+        ephemeral_public: &[u8],
+    ) -> Result<[u8; 32], CryptoError> {
+        let mut deriver = Deriver::new(ephemeral)?;
+        deriver.set_peer(recipient)?;
+        hash_wrapping_key(&deriver.derive_to_vec()?, ephemeral_public, recipient)
+    }
+
+    fn hash_wrapping_key<T: HasPublic>(
+        shared: &[u8],
+        ephemeral_public: &[u8],
+        recipient: &PKey<T>,
+    ) -> Result<[u8; 32], CryptoError> {
+        let mut hasher = Sha256::new();
+        hasher.update(shared);
+        hasher.update(ephemeral_public);
+        hasher.update(&recipient.raw_public_key()?);
+        Ok(hasher.finish())
+    }
+}
@@ -0,0 +1,366 @@
+pub mod extract {
+    use std::error::Error;
+    use std::ffi::OsStr;
+    use std::fmt::{Display, Formatter};
+    use std::fs::{File, OpenOptions};
+    use std::io::{ErrorKind, Read};
+    use std::path::{Component, Path, PathBuf};
+    use std::{fmt, fs, io};
+    use tar::{Entry, EntryType};
+
+    /// What happens when a downloaded file already exists in the output directory
+    #[derive(Clone, Copy, PartialEq)]
+    pub enum ExistingPolicy {
+        /// Stop before anything is written if a top-level name is taken
+        Refuse,
+        Overwrite,
+        /// Keep the existing file and leave out the downloaded one
+        Skip,
+        /// Extract a taken top-level name as "name (1)", "name (2)" and so on
+        Rename,
+    }
+
+    #[derive(Debug)]
+    pub enum ExtractError {
+        Exists(PathBuf),
+        IOError(io::Error),
+    }
+
+    impl Display for ExtractError {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            match self {
+                ExtractError::Exists(path) => {
+                    write!(f, "Cannot write to {:?}. Destination already exists.", path)
+                }
+                ExtractError::IOError(err) => write!(f, "ExtractError::IOError ({})", err),
+            }
+        }
+    }
+
+    impl Error for ExtractError {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            match self {
+                ExtractError::Exists(_) => None,
+                ExtractError::IOError(err) => Some(err),
+            }
+        }
+    }
+
+    impl From<io::Error> for ExtractError {
+        fn from(err: io::Error) -> Self {
+            ExtractError::IOError(err)
+        }
+    }
+
+    /// Entry of the archive that was not extracted
+    #[derive(Clone)]
+    pub struct Rejection {
+        pub path: PathBuf,
+        pub reason: &'static str,
+    }
+
+    /// Unpacks archives from untrusted senders into one output directory
+    pub struct Extractor {
+        output_dir: PathBuf,
+        policy: ExistingPolicy,
+        /// Top-level names of the archive and the names they are extracted as
+        roots: Vec<(PathBuf, PathBuf)>,
+        roots_locked: bool,
+        rejected: Vec<Rejection>,
+        skipped: Vec<PathBuf>,
+        created: bool,
+    }
+
+    impl Extractor {
+        /// The output directory is created once the first file is extracted,
+        /// so claiming the roots writes nothing
+        pub fn new<P: AsRef<Path>>(output_dir: P, policy: ExistingPolicy) -> io::Result<Self> {
+            Ok(Self {
+                output_dir: output_dir.as_ref().to_path_buf(),
+                policy,
+                roots: Vec::new(),
+                roots_locked: false,
+                rejected: Vec::new(),
+                skipped: Vec::new(),
+                created: false,
+            })
+        }
+
+        fn create_output_dir(&mut self) -> io::Result<()> {
+            if !self.created {
+                fs::create_dir_all(&self.output_dir)?;
+                self.output_dir = self.output_dir.canonicalize()?;
+                self.created = true;
+            }
+            Ok(())
+        }
+
+        /// Claims a top-level name, returns the path it is extracted to
+        pub fn add_root(&mut self, root: &str) -> Result<PathBuf, ExtractError> {
+            let root_path = PathBuf::from(root);
+            if !is_plain_name(&root_path) {
+                return Err(ExtractError::IOError(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid file name {:?}", root),
+                )));
+            }
+            if let Some(name) = self.root_name(&root_path) {
+                return Ok(self.output_dir.join(name));
+            }
+
+            let mut name = root_path.clone();
+            if exists(&self.output_dir.join(&name)) {
+                match self.policy {
+                    ExistingPolicy::Refuse => return Err(ExtractError::Exists(name)),
+                    ExistingPolicy::Overwrite | ExistingPolicy::Skip => {}
+                    ExistingPolicy::Rename => {
+                        let mut n = 1;
+                        while exists(&self.output_dir.join(&name)) {
+                            name = PathBuf::from(format!("{} ({})", root, n));
+                            n += 1;
+                        }
+                    }
+                }
+            }
+            self.roots.push((root_path, name.clone()));
+            Ok(self.output_dir.join(name))
+        }
+
+        /// Rejects entries outside the roots added so far, used when the sender listed them
+        pub fn lock_roots(&mut self) {
+            self.roots_locked = true;
+        }
+
+        /// Opens the single file of a streamed download, None if it is skipped
+        pub fn create_file(&mut self, root: &str) -> Result<Option<File>, ExtractError> {
+            self.create_output_dir()?;
+            let path = self.add_root(root)?;
+            if exists(&path) {
+                match self.policy {
+                    ExistingPolicy::Skip => {
+                        self.skipped.push(PathBuf::from(root));
+                        return Ok(None);
+                    }
+                    _ => remove_existing(&path)?,
+                }
+            }
+            Ok(Some(
+                OpenOptions::new().write(true).create_new(true).open(path)?,
+            ))
+        }
+
+        pub fn unpack<R: Read>(&mut self, entry: &mut Entry<R>) -> Result<(), ExtractError> {
+            self.create_output_dir()?;
+            let path = entry.path()?.into_owned();
+            let relative = match self.check_path(&path) {
+                Ok(Some(relative)) => relative,
+                Ok(None) => return Ok(()),
+                Err(reason) => return self.reject(path, reason),
+            };
+
+            let kind = entry.header().entry_type();
+            match kind {
+                EntryType::Char | EntryType::Block => {
+                    return self.reject(path, "device node");
+                }
+                EntryType::Fifo => return self.reject(path, "named pipe"),
+                _ => {}
+            }
+
+            let dest = self.output_dir.join(&relative);
+            if let Some(parent) = dest.parent() {
+                // Symlinks extracted earlier or left in the output directory must not lead out,
+                // checked before any directory is created
+                if !self.inside_output_dir(parent) {
+                    return self.reject(path, "parent directory is outside the output directory");
+                }
+                fs::create_dir_all(parent)?;
+            }
+
+            let mut link_source = None;
+            if kind.is_symlink() {
+                let target = match entry.link_name()? {
+                    Some(target) => target.into_owned(),
+                    None => return self.reject(path, "symlink without a target"),
+                };
+                if target.has_root() {
+                    return self.reject(path, "symlink to an absolute path");
+                }
+                let parent = dest.parent().unwrap().canonicalize()?;
+                match resolve(&parent.join(&target)) {
+                    Some(resolved) if resolved.starts_with(&self.output_dir) => {}
+                    _ => return self.reject(path, "symlink points outside the output directory"),
+                }
+            } else if kind.is_hard_link() {
+                let target = match entry.link_name()? {
+                    Some(target) => target.into_owned(),
+                    None => return self.reject(path, "hard link without a target"),
+                };
+                let source = match self.link_source(&target) {
+                    Some(source) => source,
+                    None => {
+                        return self.reject(path, "hard link points outside the output directory")
+                    }
+                };
+                match source.canonicalize() {
+                    Ok(source) if source.starts_with(&self.output_dir) => {}
+                    _ => return self.reject(path, "hard link points outside the output directory"),
+                }
+                link_source = Some(source);
+            }
+
+            if exists(&dest) {
+                let is_dir = dest.symlink_metadata()?.is_dir();
+                if kind.is_dir() && is_dir {
+                    return Ok(());
+                }
+                match self.policy {
+                    ExistingPolicy::Skip => {
+                        self.skipped.push(path);
+                        return Ok(());
+                    }
+                    _ if is_dir => return self.reject(path, "would replace a directory"),
+                    _ => remove_existing(&dest)?,
+                }
+            }
+
+            // Entry::unpack would resolve the source against the working directory
+            if let Some(source) = link_source {
+                fs::hard_link(source, dest)?;
+                return Ok(());
+            }
+
+            entry.unpack(dest)?;
+            Ok(())
+        }
+
+        /// Maps a path of the archive to the output directory,
+        /// None for entries that have nothing to extract, like "./"
+        fn check_path(&mut self, path: &Path) -> Result<Option<PathBuf>, &'static str> {
+            let components = archive_components(path)?;
+            if components.is_empty() {
+                return Ok(None);
+            }
+
+            let root = PathBuf::from(components[0]);
+            let name = match self.root_name(&root) {
+                Some(name) => name,
+                None if self.roots_locked => return Err("not listed by the sender"),
+                None => match self.add_root(&root.to_string_lossy()) {
+                    Ok(path) => PathBuf::from(path.file_name().unwrap()),
+                    Err(_) => return Err("destination already exists"),
+                },
+            };
+            let mut relative = name;
+            relative.extend(&components[1..]);
+            Ok(Some(relative))
+        }
+
+        /// Maps the target of a hard link to the output directory. Unlike check_path
+        /// it claims no roots, so only names of this archive can be linked to.
+        fn link_source(&self, target: &Path) -> Option<PathBuf> {
+            let components = archive_components(target).ok()?;
+            let mut relative = self.root_name(Path::new(components.first()?))?;
+            relative.extend(&components[1..]);
+            Some(self.output_dir.join(relative))
+        }
+
+        /// Checks that the nearest existing ancestor of `path` resolves into the output directory
+        fn inside_output_dir(&self, path: &Path) -> bool {
+            let ancestor = match path.ancestors().find(|ancestor| exists(ancestor)) {
+                Some(ancestor) => ancestor,
+                None => return false,
+            };
+            ancestor
+                .canonicalize()
+                .is_ok_and(|ancestor| ancestor.starts_with(&self.output_dir))
+        }
+
+        fn reject(&mut self, path: PathBuf, reason: &'static str) -> Result<(), ExtractError> {
+            self.rejected.push(Rejection { path, reason });
+            Ok(())
+        }
+
+        fn root_name(&self, root: &Path) -> Option<PathBuf> {
+            self.roots
+                .iter()
+                .find(|(archive_root, _)| archive_root == root)
+                .map(|(_, name)| name.clone())
+        }
+
+        /// Names the top-level entries were extracted as
+        pub fn roots(&self) -> Vec<&PathBuf> {
+            self.roots.iter().map(|(_, name)| name).collect()
+        }
+
+        pub fn rejected(&self) -> &[Rejection] {
+            &self.rejected
+        }
+
+        pub fn skipped(&self) -> &[PathBuf] {
+            &self.skipped
+        }
+    }
+
+    /// Names of a path of the archive, fails for paths that could lead out
+    fn archive_components(path: &Path) -> Result<Vec<&OsStr>, &'static str> {
+        let mut components = Vec::new();
+        for component in path.components() {
+            match component {
+                Component::Prefix(_) | Component::RootDir => return Err("absolute path"),
+                Component::ParentDir => return Err("path contains '..'"),
+                Component::CurDir => {}
+                Component::Normal(name) => components.push(name),
+            }
+        }
+        Ok(components)
+    }
+
+    fn is_plain_name(path: &Path) -> bool {
+        let mut components = path.components();
+        matches!(components.next(), Some(Component::Normal(_))) && components.next().is_none()
+    }
+
+    /// Checks existence without following symlinks
+    fn exists(path: &Path) -> bool {
+        path.symlink_metadata().is_ok()
+    }
+
+    fn remove_existing(path: &Path) -> io::Result<()> {
+        if path.symlink_metadata()?.is_dir() {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{:?} is a directory", path),
+            ));
+        }
+        fs::remove_file(path)
+    }
+
+    /// Resolves a symlink target the way the filesystem will, following the links
+    /// already extracted. None if ".." would leave a name that does not exist yet,
+    /// a link extracted later under that name could lead anywhere.
+    fn resolve(path: &Path) -> Option<PathBuf> {
+        let mut resolved = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    if !resolved.symlink_metadata().ok()?.is_dir() {
+                        return None;
+                    }
+                    resolved.pop();
+                }
+                component => {
+                    resolved.push(component);
+                    let is_symlink = resolved
+                        .symlink_metadata()
+                        .is_ok_and(|metadata| metadata.file_type().is_symlink());
+                    if is_symlink {
+                        resolved = resolved.canonicalize().ok()?;
+                    }
+                }
+            }
+        }
+        Some(resolved)
+    }
+}
@@ -0,0 +1,33 @@
+//! Secure, end-to-end encrypted file sharing, the library behind the `sfshr` command-line tool.
+//!
+//! [share_path](fn.share_path.html) uploads files and directories and returns the download key,
+//! [fetch_to](fn.fetch_to.html) downloads a share into a directory, a file or a writer.
+//! [Upload](struct.Upload.html) and [Download](struct.Download.html) give access
+//! to the raw payload stream, with the `async` feature `AsyncUpload` and `AsyncDownload`
+//! implement tokio's `AsyncWrite` and `AsyncRead` for it.
+#[cfg(feature = "async")]
+pub mod async_transfer;
+pub mod compression;
+pub mod crypto;
+pub mod extract;
+pub mod manifest;
+pub mod protocol;
+pub mod share;
+pub mod transfer;
+
+extern crate openssl;
+extern crate tar;
+
+#[cfg(feature = "async")]
+pub use crate::async_transfer::async_transfer::{AsyncDownload, AsyncUpload};
+pub use crate::compression::compression::Codec;
+pub use crate::extract::extract::{ExistingPolicy, Rejection};
+pub use crate::manifest::manifest::Manifest;
+pub use crate::share::share::{
+    fetch_to, is_password_protected, share_path, share_reader, ConfirmCallback, Encryption,
+    FetchOptions, Fetched, Share, ShareError, Target, UploadOptions,
+};
+pub use crate::transfer::transfer::{
+    delete, info, Download, Progress, ProgressCallback, ShareInfo, ShareOptions, TransferError,
+    Upload,
+};
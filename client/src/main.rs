@@ -1,29 +1,105 @@
-#[macro_use]
-mod transfer;
-
 extern crate base64;
 extern crate openssl;
-extern crate tar;
+extern crate rpassword;
+extern crate sfshr;
 
-use crate::transfer::transfer::{Download, Upload};
-use std::convert::TryInto;
+use openssl::pkey::{PKey, Private, Public};
+use sfshr::compression::compression::Codec;
+use sfshr::crypto::crypto::{check_x25519, CryptoError};
+use sfshr::extract::extract::ExistingPolicy;
+use sfshr::manifest::manifest::{Manifest, MAX_MESSAGE_LENGTH};
+use sfshr::transfer::transfer::FormatSize;
+use sfshr::{
+    delete, fetch_to, info, is_password_protected, share_path, share_reader, Encryption,
+    FetchOptions, Progress, ProgressCallback, Share, ShareError, ShareOptions, Target,
+    TransferError, UploadOptions,
+};
+use std::env;
 use std::env::args;
-use std::fs::File;
-use std::net::ToSocketAddrs;
+use std::io::{BufRead, IsTerminal, Write};
 use std::path::{Path, PathBuf};
 use std::process::exit;
-use std::{fmt, fs, io};
-use tar::{Archive, Builder};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{fs, io};
+
+/// Exit codes, so scripts can tell the causes of a failure apart
+const EXIT_USAGE: i32 = 2;
+const EXIT_CONNECTION: i32 = 3;
+const EXIT_FINGERPRINT: i32 = 4;
+const EXIT_SERVER: i32 = 5;
+const EXIT_UNAVAILABLE: i32 = 6;
+const EXIT_CRYPTO: i32 = 7;
+const EXIT_ARCHIVE: i32 = 8;
+const EXIT_IO: i32 = 9;
+const EXIT_CANCELLED: i32 = 10;
+const EXIT_UNFINISHED: i32 = 11;
+
+static INFO_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Moves all informational output to stderr, so stdout only carries downloaded data
+fn redirect_info_to_stderr() {
+    INFO_TO_STDERR.store(true, Ordering::Relaxed);
+}
+
+fn info_to_stderr() -> bool {
+    INFO_TO_STDERR.load(Ordering::Relaxed)
+}
+
+macro_rules! printinfoln {
+    ($q:expr, $($x:expr), *) => {
+        if !$q {
+        printerrorln!($($x), *);
+        }
+    };
+}
+
+macro_rules! printinfo {
+    ($q:expr, $($x:expr), *) => {
+        if !$q {
+        if info_to_stderr() {
+        eprint!($($x,)*);
+        } else {
+        print!($($x,)*);
+        }
+        }
+    };
+}
+
+/// Prints regardless of --quiet, to stderr if informational output was redirected
+macro_rules! printerrorln {
+    ($($x:expr), *) => {
+        if info_to_stderr() {
+        eprintln!($($x,)*);
+        } else {
+        println!($($x,)*);
+        }
+    };
+}
 
 fn main() {
     let mut args = args();
     args.next();
 
     let mut encrypt = true;
+    let mut password = false;
+    let mut recipients = Vec::new();
+    let mut identity = None;
     let mut receive = false;
+    let mut show_info = false;
     let mut keep_tar = None;
+    let mut resume = false;
+    let mut delete_key = None;
+    let mut message = None;
+    let mut codec = Codec::Zstd;
+    let mut level = None;
+    let mut yes = false;
+    let mut stdout = false;
+    let mut output_dir = None;
+    let mut policy = None;
+    let mut options = ShareOptions::default();
     let mut quiet = false;
-    let mut main_arg = None;
+    let mut main_args = Vec::new();
     let mut server = String::from("ondralukes.cz:40788");
     let mut fingerprint = Some(
         hex::decode("bbda8c529a2911aff003977130f4bb96496b2c71c3c31f634932857dab7c66a6").unwrap(),
@@ -37,19 +113,80 @@ fn main() {
             Some(arg) => {
                 if arg == "-n" || arg == "--no-encryption" {
                     encrypt = false;
+                } else if arg == "-p" || arg == "--password" {
+                    password = true;
+                } else if arg == "--to" {
+                    match args.next() {
+                        None => {
+                            println!("Expected value for --to");
+                            exit(EXIT_USAGE);
+                        }
+                        Some(val) => {
+                            recipients.push(read_public_key(&val));
+                        }
+                    }
+                } else if arg == "--identity" {
+                    match args.next() {
+                        None => {
+                            println!("Expected value for --identity");
+                            exit(EXIT_USAGE);
+                        }
+                        Some(val) => {
+                            identity = Some(read_private_key(&val));
+                        }
+                    }
                 } else if arg == "-r" {
                     receive = true;
+                } else if arg == "--info" {
+                    show_info = true;
                 } else if arg == "--help" {
                     println!("Usage: sfshr [file] or sfshr -r [download key]");
+                    println!(" - - upload stdin instead of a file");
                     println!(" -r [download key] - download file");
+                    println!(" --info [download key] - show size and expiration of a file without downloading it");
+                    println!(" --delete [delete key] - remove uploaded file from the server");
                     println!(" -t --tar [tarname] - store downloaded tar as [tarname], instead of unpacking it");
+                    println!(
+                        " --stdout - write downloaded data to stdout, progress goes to stderr"
+                    );
+                    println!(" -o --output-dir [directory] - unpack into [directory] instead of the current one");
+                    println!(" --overwrite - replace files that already exist");
+                    println!(" --skip-existing - keep files that already exist");
+                    println!(" --rename - unpack as 'name (1)' if the name is already taken");
+                    println!(
+                        " --resume - continue an interrupted download into the file given by --tar"
+                    );
+                    println!(
+                        " --message [text] - message shown to the receiver before downloading"
+                    );
+                    println!(
+                        " --compression [zstd|gzip|none] - compress the upload (default: zstd)"
+                    );
+                    println!(" --compress-level [level] - compression level, 1-22 for zstd, 0-9 for gzip");
+                    println!(" -y --yes - download without asking for confirmation, required when stdin is not a terminal");
                     println!(" -n --no-encryption - do not encrypt or decrypt the file");
-                    println!(" -q --quiet - do not print anything (except download key)");
+                    println!(" -p --password - protect the download key with a password");
+                    println!(" --to [public key file] - encrypt for the owner of an X25519 key (PEM), can be repeated");
+                    println!(" --identity [private key file] - decrypt a file sent --to your X25519 key (PEM)");
+                    println!(" -e --expire [time] - request expiration time of the upload, e.g. 30m, 1h or 7d (default: server's choice)");
+                    println!(" -m --max-downloads [count] - remove the upload after it was downloaded [count] times");
+                    println!(" -q --quiet - do not print anything (except download key and the download confirmation)");
                     println!(" -s --server [hostname:port] - specify sfshr server (default: 'ondralukes.cz:40788')");
                     println!(" --no-fingerprint - do not verify server fingerprint");
                     println!(
                         " -f --fingerprint [fingerprint] - specify expected server fingerprint  (default: 'bbda8c52...')"
                     );
+                    println!("Exit codes:");
+                    println!(" 2 - invalid arguments");
+                    println!(" 3 - server cannot be reached or the connection was lost");
+                    println!(" 4 - server fingerprint does not match");
+                    println!(" 5 - server refused the request");
+                    println!(" 6 - share expired, was deleted or does not exist");
+                    println!(" 7 - decryption failed or the password is wrong");
+                    println!(" 8 - archive is invalid or some of its entries were rejected");
+                    println!(" 9 - local file cannot be read or written");
+                    println!(" 10 - download was cancelled");
+                    println!(" 11 - share is still being uploaded");
                     exit(0);
                 } else if arg == "-q" || arg == "--quiet" {
                     quiet = true;
@@ -57,7 +194,7 @@ fn main() {
                     match args.next() {
                         None => {
                             println!("Expected value for --server");
-                            exit(1);
+                            exit(EXIT_USAGE);
                         }
                         Some(val) => {
                             server = val;
@@ -67,221 +204,604 @@ fn main() {
                     match args.next() {
                         None => {
                             println!("Expected value for --tar");
-                            exit(1);
+                            exit(EXIT_USAGE);
                         }
                         Some(val) => {
                             keep_tar = Some(val);
                         }
                     }
+                } else if arg == "-e" || arg == "--expire" {
+                    match args.next() {
+                        None => {
+                            println!("Expected value for --expire");
+                            exit(EXIT_USAGE);
+                        }
+                        Some(val) => match parse_duration(&val) {
+                            None => {
+                                println!("Invalid expiration time {}", val);
+                                exit(EXIT_USAGE);
+                            }
+                            Some(val) => {
+                                options.lifetime = val;
+                            }
+                        },
+                    }
+                } else if arg == "-m" || arg == "--max-downloads" {
+                    match args.next().map(|val| u64::from_str(&val)) {
+                        Some(Ok(val)) if val != 0 => {
+                            options.max_downloads = val;
+                        }
+                        _ => {
+                            println!("Expected positive number for --max-downloads");
+                            exit(EXIT_USAGE);
+                        }
+                    }
+                } else if arg == "--delete" {
+                    match args.next() {
+                        None => {
+                            println!("Expected value for --delete");
+                            exit(EXIT_USAGE);
+                        }
+                        Some(val) => {
+                            delete_key = Some(val);
+                        }
+                    }
+                } else if arg == "--message" {
+                    match args.next() {
+                        None => {
+                            println!("Expected value for --message");
+                            exit(EXIT_USAGE);
+                        }
+                        Some(val) if val.len() > MAX_MESSAGE_LENGTH => {
+                            println!("Message is too long");
+                            exit(EXIT_USAGE);
+                        }
+                        Some(val) => {
+                            message = Some(val);
+                        }
+                    }
+                } else if arg == "--compression" {
+                    match args.next().map(|val| Codec::from_str(&val)) {
+                        Some(Ok(val)) => {
+                            codec = val;
+                        }
+                        _ => {
+                            println!("Expected zstd, gzip or none for --compression");
+                            exit(EXIT_USAGE);
+                        }
+                    }
+                } else if arg == "--compress-level" {
+                    match args.next().map(|val| i32::from_str(&val)) {
+                        Some(Ok(val)) => {
+                            level = Some(val);
+                        }
+                        _ => {
+                            println!("Expected number for --compress-level");
+                            exit(EXIT_USAGE);
+                        }
+                    }
+                } else if arg == "-y" || arg == "--yes" {
+                    yes = true;
+                } else if arg == "--stdout" {
+                    stdout = true;
+                } else if arg == "-o" || arg == "--output-dir" {
+                    match args.next() {
+                        None => {
+                            println!("Expected value for --output-dir");
+                            exit(EXIT_USAGE);
+                        }
+                        Some(val) => {
+                            output_dir = Some(PathBuf::from(val));
+                        }
+                    }
+                } else if arg == "--overwrite" || arg == "--skip-existing" || arg == "--rename" {
+                    if policy.is_some() {
+                        println!(
+                            "Only one of --overwrite, --skip-existing and --rename can be used"
+                        );
+                        exit(EXIT_USAGE);
+                    }
+                    policy = Some(match arg.as_str() {
+                        "--overwrite" => ExistingPolicy::Overwrite,
+                        "--skip-existing" => ExistingPolicy::Skip,
+                        _ => ExistingPolicy::Rename,
+                    });
+                } else if arg == "--resume" {
+                    resume = true;
                 } else if arg == "--no-fingerprint" {
                     fingerprint = None;
                 } else if arg == "-f" || arg == "--fingerprint" {
                     match args.next() {
                         None => {
                             println!("Expected value for --fingerprint");
-                            exit(1);
-                        }
-                        Some(val) => {
-                            fingerprint = Some(hex::decode(val).unwrap());
+                            exit(EXIT_USAGE);
                         }
+                        Some(val) => match hex::decode(&val) {
+                            Ok(val) => {
+                                fingerprint = Some(val);
+                            }
+                            Err(_) => {
+                                println!("Invalid fingerprint {}", val);
+                                exit(EXIT_USAGE);
+                            }
+                        },
                     }
                 } else {
-                    main_arg = Some(arg);
+                    main_args.push(arg);
                 }
             }
         }
     }
 
-    if !receive {
+    if (receive || show_info) && main_args.len() > 1 {
+        printinfoln!(quiet, "Expected a single download key!");
+        exit(EXIT_USAGE);
+    }
+    let main_arg = main_args.first().cloned();
+
+    if let Some(delete_key) = delete_key {
+        let delete_key = base64::decode(delete_key.as_bytes());
+        if delete_key.is_err() || delete_key.as_ref().unwrap().len() != 64 {
+            printinfoln!(quiet, "Invalid delete key!");
+            exit(EXIT_USAGE);
+        }
+        let delete_key = delete_key.unwrap();
+        delete(server, &delete_key[..32], &delete_key[32..], fingerprint).unwrap_or_else(on_error);
+        printinfoln!(quiet, "Succesfully deleted");
+    } else if show_info {
         if main_arg.is_none() {
+            printinfoln!(quiet, "No download key specified!");
+            exit(EXIT_USAGE);
+        }
+        let download_key = base64::decode(main_arg.unwrap().as_bytes());
+        if download_key.is_err() || download_key.as_ref().unwrap().len() < 32 {
+            printinfoln!(quiet, "Invalid download key!");
+            exit(EXIT_USAGE);
+        }
+        let info = info(server, &download_key.unwrap()[..32], fingerprint).unwrap_or_else(on_error);
+        println!("Size: {}", info.size.format_size());
+        if info.created != 0 {
+            println!("Uploaded at {}", format_timestamp(info.created));
+        }
+        println!("Expires at {}", format_timestamp(info.expires));
+        if info.downloads_left == 0 {
+            println!("Downloads left: unlimited");
+        } else {
+            println!("Downloads left: {}", info.downloads_left);
+        }
+    } else if !receive {
+        if main_args.is_empty() {
             printinfoln!(quiet, "No file specified!");
-            exit(1);
+            exit(EXIT_USAGE);
         }
 
-        let path = PathBuf::from(main_arg.unwrap());
-        upload(server, path, encrypt, quiet, keep_tar, fingerprint);
+        if let Some(level) = level {
+            if !codec.valid_level(level) {
+                printinfoln!(quiet, "Invalid compression level {}", level);
+                exit(EXIT_USAGE);
+            }
+        }
+        let paths = if main_args == ["-"] {
+            None
+        } else {
+            Some(expand_paths(&main_args, quiet))
+        };
+        let encryption = if !recipients.is_empty() {
+            if !encrypt || password {
+                printinfoln!(
+                    quiet,
+                    "--to cannot be used with --no-encryption or --password"
+                );
+                exit(EXIT_USAGE);
+            }
+            Encryption::Recipients(recipients)
+        } else if password {
+            if !encrypt {
+                printinfoln!(quiet, "--password cannot be used with --no-encryption");
+                exit(EXIT_USAGE);
+            }
+            let password = read_password("Password: ");
+            if password.is_empty() || read_password("Repeat password: ") != password {
+                printinfoln!(quiet, "Passwords are empty or do not match!");
+                exit(EXIT_USAGE);
+            }
+            Encryption::Password(password)
+        } else if encrypt {
+            Encryption::Key
+        } else {
+            Encryption::None
+        };
+        let for_recipients = matches!(encryption, Encryption::Recipients(_));
+        let upload_options = UploadOptions {
+            encryption,
+            codec,
+            level,
+            message,
+            share: options,
+            fingerprint,
+        };
+        let progress = print_progress(quiet, "Uploaded");
+        let share = match paths {
+            None => share_reader(
+                &server,
+                "stdin",
+                io::stdin().lock(),
+                upload_options,
+                progress,
+            ),
+            Some(paths) => share_path(&server, &paths, upload_options, progress),
+        }
+        .unwrap_or_else(on_error);
+        print_share(&server, &share, keep_tar, encrypt, for_recipients, quiet);
     } else {
         if main_arg.is_none() {
             printinfoln!(quiet, "No download key specified!");
-            exit(1);
+            exit(EXIT_USAGE);
         }
+        if stdout {
+            if keep_tar.is_some() || resume {
+                printinfoln!(quiet, "--stdout cannot be used with --tar or --resume");
+                exit(EXIT_USAGE);
+            }
+            redirect_info_to_stderr();
+        }
+        if (output_dir.is_some() || policy.is_some()) && (stdout || keep_tar.is_some()) {
+            printinfoln!(
+                quiet,
+                "--output-dir, --overwrite, --skip-existing and --rename only apply when unpacking"
+            );
+            exit(EXIT_USAGE);
+        }
+        let target = match keep_tar {
+            None if stdout => Target::Writer(Box::new(io::stdout())),
+            Some(tar) if resume => Target::ResumeFile(PathBuf::from(tar)),
+            Some(tar) => Target::File(PathBuf::from(tar)),
+            None if resume => {
+                printinfoln!(quiet, "--resume requires --tar");
+                exit(EXIT_USAGE);
+            }
+            None => Target::Directory {
+                path: output_dir.unwrap_or_else(|| PathBuf::from(".")),
+                policy: policy.unwrap_or(ExistingPolicy::Refuse),
+            },
+        };
         let download_key = base64::decode(main_arg.unwrap().as_bytes());
         if download_key.is_err() {
             printinfoln!(quiet, "Invalid download key format!");
-            exit(1);
+            exit(EXIT_USAGE);
         }
+        let download_key = download_key.unwrap();
+        let encryption = match identity {
+            Some(_) if !encrypt => {
+                printinfoln!(quiet, "--identity cannot be used with --no-encryption");
+                exit(EXIT_USAGE);
+            }
+            Some(identity) => Encryption::Identity(identity),
+            None if encrypt && is_password_protected(&download_key) => {
+                Encryption::Password(read_password("Password: "))
+            }
+            None if encrypt => Encryption::Key,
+            None => Encryption::None,
+        };
         download(
-            server,
-            download_key.unwrap(),
-            encrypt,
+            &server,
+            &download_key,
+            encryption,
             quiet,
-            keep_tar,
+            yes,
+            target,
             fingerprint,
         );
     }
 }
 
-fn upload(
-    addr: String,
-    mut filepath: PathBuf,
+fn print_share(
+    addr: &str,
+    share: &Share,
+    keep_tar: Option<String>,
     encrypt: bool,
+    for_recipients: bool,
     quiet: bool,
-    keep_tar: Option<String>,
-    fingerprint: Option<Vec<u8>>,
 ) {
-    let size = dir_size(&filepath);
-    let mut upload = Upload::new(&addr, encrypt, quiet, size, fingerprint).unwrap_or_else(on_error);
-    let mut archive = Builder::new(upload);
-
-    match filepath.canonicalize() {
-        Ok(p) => {
-            filepath = p;
-        }
-        Err(err) => {
-            printinfoln!(quiet, "Failed to open file: {}", err);
-            exit(1);
-        }
-    }
-    filepath = filepath.canonicalize().unwrap();
-    let root_path = filepath.components().last().unwrap();
-    if filepath.is_dir() {
-        archive
-            .append_dir_all(root_path, &filepath)
-            .unwrap_or_else(on_error);
-    } else {
-        archive
-            .append_file(
-                root_path,
-                &mut File::open(&filepath).unwrap_or_else(on_error),
-            )
-            .unwrap_or_else(on_error);
-    }
-
-    upload = archive.into_inner().unwrap_or_else(on_error);
-
-    upload.finalize().unwrap_or_else(on_error);
-
-    let mut download_key = Vec::new();
-    download_key.extend_from_slice(&upload.id());
-    if encrypt {
-        download_key.extend_from_slice(upload.key().unwrap());
-    }
-
     let mut extras = String::new();
-    if &addr != "ondralukes.cz:40788" {
+    if addr != "ondralukes.cz:40788" {
         extras = format!(" --server {}", addr);
     }
+    let delete_command = share
+        .delete_key
+        .as_ref()
+        .map(|delete_key| format!("sfshr{} --delete {}", extras, base64::encode(delete_key)));
 
-    if keep_tar.is_some() {
-        extras.push_str(&format!(" --tar {}", keep_tar.unwrap()));
+    if let Some(keep_tar) = keep_tar {
+        extras.push_str(&format!(" --tar {}", keep_tar));
     }
-    if encrypt {
-        println!("sfshr{} -r {}", extras, base64::encode(&download_key));
+    if for_recipients {
+        println!("sfshr{} -r {}", extras, base64::encode(&share.download_key));
+        printinfoln!(
+            quiet,
+            "Recipients have to add --identity with their private key file"
+        );
+    } else if encrypt {
+        println!("sfshr{} -r {}", extras, base64::encode(&share.download_key));
     } else {
         println!(
             "sfshr --no-encryption{} -r {}",
             extras,
-            base64::encode(&download_key)
+            base64::encode(&share.download_key)
         );
     }
-}
-
-fn dir_size(path: &PathBuf) -> usize {
-    if path.is_file() {
-        return path.metadata().unwrap().len() as usize;
+    if let Some(delete_command) = delete_command {
+        printinfoln!(quiet, "Delete with {}", delete_command);
     }
-    let mut size = 0;
-    let dir = fs::read_dir(path).unwrap();
-    for entry in dir {
-        let entry = entry.unwrap();
-        size += dir_size(&entry.path());
+    if let Some(expiration) = share.expiration {
+        printinfoln!(quiet, "Expires at {}", format_timestamp(expiration));
     }
-    size
 }
 
-fn download<A: ToSocketAddrs>(
-    addr: A,
-    download_key: Vec<u8>,
-    encrypt: bool,
-    quiet: bool,
-    keep_tar: Option<String>,
-    fingerprint: Option<Vec<u8>>,
-) {
-    if (encrypt && download_key.len() != 64) || (!encrypt && download_key.len() != 32) {
-        printinfoln!(quiet, "Invalid download key size!");
-        exit(1);
+/// Parses duration such as 90, 30m, 1h or 7d into seconds
+fn parse_duration(str: &str) -> Option<u64> {
+    let (value, unit) = match str.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => str.split_at(index),
+        None => (str, "s"),
+    };
+    let multiplier = match unit.to_lowercase().as_str() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    let value: u64 = value.parse().ok()?;
+    if value == 0 {
+        return None;
     }
+    value.checked_mul(multiplier)
+}
 
-    let mut key: Option<[u8; 32]> = None;
-    if encrypt {
-        key = Some(download_key[32..].try_into().unwrap());
-    }
-    let mut download = Download::new(
-        addr,
-        &download_key[..32].try_into().unwrap(),
-        key,
-        quiet,
-        fingerprint,
-    )
-    .unwrap_or_else(on_error);
+/// Formats unix timestamp as UTC date and time
+fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let seconds = timestamp % 86400;
 
-    match keep_tar {
-        None => {
-            let mut archive = Archive::new(download);
-            let mut iter = archive.entries().unwrap();
-            let mut first = iter.next().unwrap().unwrap();
+    // Converts days since 1970-01-01 to a civil date
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
 
-            let archive_root;
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60
+    )
+}
 
-            let first_path = Path::new(".").join(first.path().unwrap());
-            let mut fp_iter = first_path.iter();
+/// Expands glob patterns and resolves the paths to upload
+fn expand_paths(args: &[String], quiet: bool) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    for arg in args {
+        if arg == "-" {
+            printinfoln!(quiet, "'-' cannot be combined with other paths");
+            exit(EXIT_USAGE);
+        }
+        let is_pattern = arg.contains(['*', '?', '[']);
+        if !is_pattern || Path::new(arg).exists() {
+            paths.push(PathBuf::from(arg));
+            continue;
+        }
+        let matches: Vec<PathBuf> = glob::glob(arg)
+            .unwrap_or_else(|err| {
+                printinfoln!(quiet, "Invalid pattern {}: {}", arg, err);
+                exit(EXIT_USAGE);
+            })
+            .collect::<Result<_, _>>()
+            .unwrap_or_else(|err| on_error(io::Error::from(err)));
+        if matches.is_empty() {
+            printinfoln!(quiet, "No files match {}", arg);
+            exit(EXIT_USAGE);
+        }
+        paths.extend(matches);
+    }
+    paths
+        .into_iter()
+        .map(|path| {
+            path.canonicalize().unwrap_or_else(|err| {
+                printinfoln!(quiet, "Failed to open file {:?}: {}", path, err);
+                exit(EXIT_IO);
+            })
+        })
+        .collect()
+}
 
-            //Skip '.'
-            fp_iter.next();
-            let first_path = Path::new(fp_iter.next().unwrap());
-            if first_path.exists() {
-                printinfoln!(
-                    quiet,
-                    "Cannot write to {:?}. Destination already exists.",
-                    first_path
-                );
-                exit(1);
-            } else {
-                archive_root = first_path.to_str().unwrap().to_string();
+fn download(
+    addr: &str,
+    download_key: &[u8],
+    encryption: Encryption,
+    quiet: bool,
+    yes: bool,
+    target: Target,
+    fingerprint: Option<Vec<u8>>,
+) {
+    let destination = match &target {
+        Target::File(path) | Target::ResumeFile(path) => format!(" {:?}", path),
+        _ => String::new(),
+    };
+    let options = FetchOptions {
+        encryption,
+        fingerprint,
+        confirm: Some(Box::new(move |manifest| {
+            // The manifest is shown even with --quiet when the receiver has to decide
+            print_manifest(manifest, quiet && yes);
+            if yes {
+                return true;
+            }
+            if !io::stdin().is_terminal() {
+                printerrorln!("Cannot ask for confirmation, stdin is not a terminal. Use -y to download without asking");
+                exit(EXIT_USAGE);
             }
+            confirm("Download? [y/N] ")
+        })),
+    };
+    let progress = print_progress(quiet, "Downloaded");
+    let fetched = match fetch_to(addr, download_key, target, options, progress) {
+        Ok(fetched) => fetched,
+        Err(ShareError::Cancelled) => {
+            printerrorln!("Download cancelled");
+            exit(EXIT_CANCELLED);
+        }
+        Err(err) => on_error(err),
+    };
 
-            first.unpack_in(".").unwrap();
+    let downloaded = if fetched.roots.is_empty() {
+        destination
+    } else {
+        let roots: Vec<String> = fetched
+            .roots
+            .iter()
+            .map(|root| format!("{:?}", root))
+            .collect();
+        format!(" {}", roots.join(", "))
+    };
+    printinfoln!(quiet, "");
+    printinfoln!(
+        quiet,
+        "\x1b[1A\x1b[0G\x1b[KSuccesfully downloaded{}",
+        downloaded
+    );
+    for path in &fetched.skipped {
+        printinfoln!(quiet, "Skipped {:?}, it already exists", path);
+    }
+    for rejection in &fetched.rejected {
+        printerrorln!("Rejected {:?}: {}", rejection.path, rejection.reason);
+    }
+    if !fetched.rejected.is_empty() {
+        exit(EXIT_ARCHIVE);
+    }
+}
 
-            for entry in iter {
-                let mut entry = entry.unwrap();
-                entry.unpack_in(".").unwrap();
-            }
+fn print_manifest(manifest: &Manifest, quiet: bool) {
+    printinfoln!(quiet, "");
+    printinfoln!(
+        quiet,
+        "\x1b[1A\x1b[0G\x1b[KName: {}",
+        manifest.roots.join(", ")
+    );
+    if manifest.stream {
+        printinfoln!(quiet, "Size: unknown, streamed from the sender");
+    } else {
+        printinfoln!(
+            quiet,
+            "Size: {} in {} file(s)",
+            manifest.size.format_size(),
+            manifest.file_count
+        );
+    }
+    if let Some(message) = &manifest.message {
+        printinfoln!(quiet, "Message: {}", message);
+    }
+}
 
-            printinfoln!(quiet, "");
+/// Prints the progress of a transfer over a single line
+fn print_progress(quiet: bool, action: &'static str) -> Option<ProgressCallback> {
+    if quiet {
+        return None;
+    }
+    Some(Box::new(move |progress| match progress {
+        Progress::Transferred { bytes, elapsed } => {
+            let speed = bytes as f64 / elapsed.as_micros() as f64 * 1000000.0;
             printinfoln!(
-                quiet,
-                "\x1b[1A\x1b[0G\x1b[KSuccesfully downloaded {:?}",
-                archive_root
+                false,
+                "{} {:^12} @ {:^12}  \x1b[1A\x1b[0G",
+                action,
+                bytes.format_size(),
+                format!("{}/s", speed.format_size())
             );
         }
-        Some(dest) => {
-            let mut file = File::create(&dest).unwrap_or_else(on_error);
-            io::copy(&mut download, &mut file).unwrap_or_else(on_error);
-            printinfoln!(quiet, "");
-            printinfoln!(
-                quiet,
-                "\x1b[1A\x1b[0G\x1b[KSuccesfully downloaded {:?}",
-                dest
-            );
+        Progress::Reconnecting => {
+            printinfoln!(false, "\x1b[KConnection lost, resuming upload...");
         }
+    }))
+}
+
+/// Asks the user a yes/no question on the terminal
+fn confirm(prompt: &str) -> bool {
+    printinfo!(false, "{}", prompt);
+    io::stdout().flush().unwrap_or_else(on_error);
+    let mut answer = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .unwrap_or_else(on_error);
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+fn read_public_key(path: &str) -> PKey<Public> {
+    let pem = fs::read(path).unwrap_or_else(|err| on_error(ShareError::File(path.into(), err)));
+    let key =
+        PKey::public_key_from_pem(&pem).unwrap_or_else(|err| on_error(CryptoError::from(err)));
+    check_x25519(&key).unwrap_or_else(on_error);
+    key
+}
+
+fn read_private_key(path: &str) -> PKey<Private> {
+    let pem = fs::read(path).unwrap_or_else(|err| on_error(ShareError::File(path.into(), err)));
+    let key =
+        PKey::private_key_from_pem(&pem).unwrap_or_else(|err| on_error(CryptoError::from(err)));
+    check_x25519(&key).unwrap_or_else(on_error);
+    key
+}
+
+/// Reads a password from the terminal without echoing it,
+/// SFSHR_PASSWORD environment variable is used instead if it is set
+fn read_password(prompt: &str) -> String {
+    if let Ok(password) = env::var("SFSHR_PASSWORD") {
+        return password;
     }
+    rpassword::read_password_from_tty(Some(prompt)).unwrap_or_else(on_error)
 }
 
-fn on_error<E: fmt::Display, T>(err: E) -> T {
-    let temp = Path::new(".sfshr-temp");
-    if temp.exists() {
-        fs::remove_file(temp).unwrap();
+fn on_error<E: Into<ShareError>, T>(err: E) -> T {
+    let err = err.into();
+    printerrorln!("\x1b[31mTerminating due to an error ({})\x1b[0m", err);
+    exit(exit_code(&err));
+}
+
+fn exit_code(err: &ShareError) -> i32 {
+    match err {
+        ShareError::Transfer(err) => match err {
+            TransferError::ConnectionFailed(_)
+            | TransferError::NetworkError(_)
+            | TransferError::ResumeFailed => EXIT_CONNECTION,
+            TransferError::FingerprintMismatch { .. } => EXIT_FINGERPRINT,
+            TransferError::ShareUnavailable(_) => EXIT_UNAVAILABLE,
+            TransferError::UploadNotFinished(_) => EXIT_UNFINISHED,
+            TransferError::EncryptionError(_) => EXIT_CRYPTO,
+            TransferError::IOError(_) => EXIT_IO,
+            TransferError::ServerError
+            | TransferError::ServerMessage(_)
+            | TransferError::CorruptedMessage
+            | TransferError::SizeLimitExceeded
+            | TransferError::IncompatibleServer(_) => EXIT_SERVER,
+        },
+        ShareError::Crypto(_) | ShareError::InvalidKey | ShareError::PasswordRequired => {
+            EXIT_CRYPTO
+        }
+        ShareError::Archive(_) => EXIT_ARCHIVE,
+        ShareError::Extract(_) | ShareError::File(..) | ShareError::IOError(_) => EXIT_IO,
+        ShareError::Cancelled => EXIT_CANCELLED,
+        ShareError::InvalidPath(_)
+        | ShareError::DuplicateName(_)
+        | ShareError::InvalidCompressionLevel(_)
+        | ShareError::Unsupported(_) => EXIT_USAGE,
     }
-    println!("\x1b[31mTerminating due to an error ({})\x1b[0m", err);
-    exit(1);
 }
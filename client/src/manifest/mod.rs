@@ -0,0 +1,213 @@
+pub mod manifest {
+    use crate::compression::compression::Codec;
+    use std::convert::{TryFrom, TryInto};
+    use std::io;
+    use std::io::{ErrorKind, Read, Write};
+
+    // Frame at the start of the payload, before the tar archive. Integers are little-endian:
+    //   [u8; 7] magic | u8 version | u32 body length
+    //   u64 total size | u64 file count | u16 name length | name | u32 message length | message
+    //   u8 codec | u8 flags | u16 additional root count | (u16 name length | name) per root
+    // Fields may be appended to the body, readers skip what they do not know.
+    const MAGIC: [u8; 7] = *b"SFSHMAN";
+    const VERSION: u8 = 1;
+    const PREFIX_SIZE: usize = 12;
+    const MAX_BODY_SIZE: usize = 1 << 20;
+
+    const FLAG_STREAM: u8 = 1 << 0;
+
+    pub const MAX_MESSAGE_LENGTH: usize = 64 * 1024;
+
+    pub struct Manifest {
+        /// Names of the uploaded files and directories at the top of the archive
+        pub roots: Vec<String>,
+        /// Total size of the files before archiving
+        pub size: u64,
+        pub file_count: u64,
+        /// Message from the sender
+        pub message: Option<String>,
+        /// Compression of the archive following the manifest
+        pub codec: Codec,
+        /// Payload is a single file streamed without an archive, its size is not known
+        pub stream: bool,
+        frame_size: u64,
+    }
+
+    /// Start of a payload
+    pub enum Frame {
+        Manifest(Manifest),
+        /// Payload uploaded without a manifest, holds the bytes read while looking for it
+        Legacy(Vec<u8>),
+    }
+
+    impl Manifest {
+        /// Fails if the names or the message do not fit into the manifest
+        pub fn new(
+            roots: Vec<String>,
+            size: u64,
+            file_count: u64,
+            message: Option<String>,
+            codec: Codec,
+        ) -> io::Result<Self> {
+            let mut manifest = Self {
+                roots,
+                size,
+                file_count,
+                message,
+                codec,
+                stream: false,
+                frame_size: 0,
+            };
+            manifest.frame_size = manifest.encode()?.len() as u64;
+            Ok(manifest)
+        }
+
+        /// Manifest of a single file of unknown size, such as stdin
+        pub fn stream(name: String, message: Option<String>, codec: Codec) -> io::Result<Self> {
+            let mut manifest = Self::new(vec![name], 0, 1, message, codec)?;
+            manifest.stream = true;
+            Ok(manifest)
+        }
+
+        pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+            writer.write_all(&self.encode()?)
+        }
+
+        /// Reads the manifest from the start of the payload
+        pub fn read<R: Read>(reader: &mut R) -> io::Result<Frame> {
+            let mut prefix = [0; PREFIX_SIZE];
+            reader.read_exact(&mut prefix[..8])?;
+            if prefix[..7] != MAGIC {
+                return Ok(Frame::Legacy(prefix[..8].to_vec()));
+            }
+            if prefix[7] != VERSION {
+                return Err(invalid_data(format!(
+                    "unsupported manifest version {}",
+                    prefix[7]
+                )));
+            }
+            reader.read_exact(&mut prefix[8..])?;
+
+            let length = u32::from_le_bytes(prefix[8..].try_into().unwrap()) as usize;
+            if length > MAX_BODY_SIZE {
+                return Err(invalid_data(String::from("manifest is too long")));
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body)?;
+
+            let mut body = &body[..];
+            let size = u64::from_le_bytes(take(&mut body, 8)?.try_into().unwrap());
+            let file_count = u64::from_le_bytes(take(&mut body, 8)?.try_into().unwrap());
+            let name_length = u16::from_le_bytes(take(&mut body, 2)?.try_into().unwrap());
+            let name = string(take(&mut body, name_length as usize)?)?;
+            let message_length = u32::from_le_bytes(take(&mut body, 4)?.try_into().unwrap());
+            let message = string(take(&mut body, message_length as usize)?)?;
+            // Manifests written by older versions end before these fields
+            let codec = match body.first() {
+                Some(&codec) => Codec::from_u8(codec)?,
+                None => Codec::None,
+            };
+            let flags = body.get(1).copied().unwrap_or(0);
+            let mut roots = vec![name];
+            if body.len() > 2 {
+                body = &body[2..];
+                let count = u16::from_le_bytes(take(&mut body, 2)?.try_into().unwrap());
+                for _ in 0..count {
+                    let length = u16::from_le_bytes(take(&mut body, 2)?.try_into().unwrap());
+                    roots.push(string(take(&mut body, length as usize)?)?);
+                }
+            }
+
+            Ok(Frame::Manifest(Self {
+                roots,
+                size,
+                file_count,
+                message: if message.is_empty() {
+                    None
+                } else {
+                    Some(message)
+                },
+                codec,
+                stream: flags & FLAG_STREAM != 0,
+                frame_size: (PREFIX_SIZE + length) as u64,
+            }))
+        }
+
+        /// Number of payload bytes taken by the manifest, the archive starts right after it
+        pub fn frame_size(&self) -> u64 {
+            self.frame_size
+        }
+
+        fn encode(&self) -> io::Result<Vec<u8>> {
+            let message = self.message.as_deref().unwrap_or("");
+            let mut body = Vec::new();
+            body.extend_from_slice(&self.size.to_le_bytes());
+            body.extend_from_slice(&self.file_count.to_le_bytes());
+            let name = &self.roots[0];
+            body.extend_from_slice(&name_length(name)?.to_le_bytes());
+            body.extend_from_slice(name.as_bytes());
+            let message_length = u32::try_from(message.len())
+                .map_err(|_| invalid_input(String::from("message is too long")))?;
+            body.extend_from_slice(&message_length.to_le_bytes());
+            body.extend_from_slice(message.as_bytes());
+            body.push(self.codec.to_u8());
+            body.push(if self.stream { FLAG_STREAM } else { 0 });
+            let count = u16::try_from(self.roots.len() - 1).map_err(|_| {
+                invalid_input(format!(
+                    "cannot share more than {} files and directories at once",
+                    u16::MAX as usize + 1
+                ))
+            })?;
+            body.extend_from_slice(&count.to_le_bytes());
+            for root in &self.roots[1..] {
+                body.extend_from_slice(&name_length(root)?.to_le_bytes());
+                body.extend_from_slice(root.as_bytes());
+            }
+            // Receivers refuse longer manifests
+            if body.len() > MAX_BODY_SIZE {
+                return Err(invalid_input(String::from(
+                    "names and message do not fit into the manifest",
+                )));
+            }
+
+            let mut frame = Vec::with_capacity(PREFIX_SIZE + body.len());
+            frame.extend_from_slice(&MAGIC);
+            frame.push(VERSION);
+            frame.extend_from_slice(&(body.len() as u32).to_le_bytes());
+            frame.extend_from_slice(&body);
+            Ok(frame)
+        }
+    }
+
+    fn name_length(name: &str) -> io::Result<u16> {
+        u16::try_from(name.len()).map_err(|_| {
+            invalid_input(format!(
+                "file name {:?} is longer than {} bytes",
+                name,
+                u16::MAX
+            ))
+        })
+    }
+
+    fn take<'a>(body: &mut &'a [u8], length: usize) -> io::Result<&'a [u8]> {
+        if body.len() < length {
+            return Err(invalid_data(String::from("manifest is truncated")));
+        }
+        let (value, rest) = body.split_at(length);
+        *body = rest;
+        Ok(value)
+    }
+
+    fn string(bytes: &[u8]) -> io::Result<String> {
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| invalid_data(String::from("manifest is not valid UTF-8")))
+    }
+
+    fn invalid_data(description: String) -> io::Error {
+        io::Error::new(ErrorKind::InvalidData, description)
+    }
+
+    fn invalid_input(description: String) -> io::Error {
+        io::Error::new(ErrorKind::InvalidInput, description)
+    }
+}
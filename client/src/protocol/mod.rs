@@ -0,0 +1,268 @@
+pub mod protocol {
+    use crate::transfer::transfer::{ShareOptions, TransferError};
+    use simpletcp::simpletcp::Message;
+
+    // Must stay in sync with sfshr-server's protocol module
+    pub const PROTOCOL_VERSION: u32 = 1;
+    pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+    pub const FEATURE_RESUMABLE_UPLOAD: u64 = 1 << 0;
+    pub const FEATURE_RANGED_DOWNLOAD: u64 = 1 << 1;
+    pub const FEATURE_UPLOAD_EXPIRATION: u64 = 1 << 2;
+    pub const FEATURE_DOWNLOAD_LIMIT: u64 = 1 << 3;
+    pub const FEATURE_DELETE: u64 = 1 << 4;
+    pub const FEATURE_INFO: u64 = 1 << 5;
+    pub const FEATURES: u64 = FEATURE_RESUMABLE_UPLOAD
+        | FEATURE_RANGED_DOWNLOAD
+        | FEATURE_UPLOAD_EXPIRATION
+        | FEATURE_DOWNLOAD_LIMIT
+        | FEATURE_DELETE
+        | FEATURE_INFO;
+
+    pub const COMMAND_UPLOAD: i32 = 0;
+    pub const COMMAND_DOWNLOAD: i32 = 1;
+    pub const COMMAND_HELLO: i32 = 2;
+    pub const COMMAND_RESUME_UPLOAD: i32 = 3;
+    pub const COMMAND_DOWNLOAD_RANGE: i32 = 4;
+    pub const COMMAND_DELETE: i32 = 5;
+    pub const COMMAND_INFO: i32 = 6;
+
+    pub const ERROR_INVALID_MESSAGE: u32 = 1;
+    pub const ERROR_IO: u32 = 2;
+    pub const ERROR_NETWORK: u32 = 3;
+    pub const ERROR_SIZE_LIMIT_EXCEEDED: u32 = 4;
+    pub const ERROR_HANDSHAKE_REQUIRED: u32 = 5;
+    pub const ERROR_INCOMPATIBLE_PROTOCOL: u32 = 6;
+    pub const ERROR_UNKNOWN_RESUME_TOKEN: u32 = 7;
+    pub const ERROR_DOWNLOAD_LIMIT_REACHED: u32 = 8;
+    pub const ERROR_INVALID_DELETE_TOKEN: u32 = 9;
+    pub const ERROR_UPLOAD_NOT_FINISHED: u32 = 10;
+    pub const ERROR_NOT_FOUND: u32 = 11;
+
+    /// Fields of a message, implemented for simpletcp's Message and for the messages
+    /// of the async transfers, so both build requests and parse responses with the same code
+    pub(crate) trait Fields: Sized {
+        fn new() -> Self;
+        fn write_u8(&mut self, n: u8);
+        fn write_i32(&mut self, n: i32);
+        fn write_u32(&mut self, n: u32);
+        fn write_u64(&mut self, n: u64);
+        fn write_buffer(&mut self, buf: &[u8]);
+        fn read_i8(&mut self) -> Result<i8, TransferError>;
+        fn read_u32(&mut self) -> Result<u32, TransferError>;
+        fn read_u64(&mut self) -> Result<u64, TransferError>;
+        fn read_buffer(&mut self) -> Result<&[u8], TransferError>;
+    }
+
+    impl Fields for Message {
+        fn new() -> Self {
+            Message::new()
+        }
+
+        fn write_u8(&mut self, n: u8) {
+            Message::write_u8(self, n)
+        }
+
+        fn write_i32(&mut self, n: i32) {
+            Message::write_i32(self, n)
+        }
+
+        fn write_u32(&mut self, n: u32) {
+            Message::write_u32(self, n)
+        }
+
+        fn write_u64(&mut self, n: u64) {
+            Message::write_u64(self, n)
+        }
+
+        fn write_buffer(&mut self, buf: &[u8]) {
+            Message::write_buffer(self, buf)
+        }
+
+        fn read_i8(&mut self) -> Result<i8, TransferError> {
+            Ok(Message::read_i8(self)?)
+        }
+
+        fn read_u32(&mut self) -> Result<u32, TransferError> {
+            Ok(Message::read_u32(self)?)
+        }
+
+        fn read_u64(&mut self) -> Result<u64, TransferError> {
+            Ok(Message::read_u64(self)?)
+        }
+
+        fn read_buffer(&mut self) -> Result<&[u8], TransferError> {
+            Ok(Message::read_buffer(self)?)
+        }
+    }
+
+    /// First message of every connection, announces the client's version and features
+    pub(crate) fn hello_request<M: Fields>() -> M {
+        let mut message = M::new();
+        message.write_i32(COMMAND_HELLO);
+        message.write_u32(PROTOCOL_VERSION);
+        message.write_u64(FEATURES);
+        message
+    }
+
+    /// Checks the answer to [hello_request](fn.hello_request.html), `None` if none arrived
+    ///
+    /// Returns the server's feature bits
+    pub(crate) fn hello_response<M: Fields>(response: Option<M>) -> Result<u64, TransferError> {
+        match response {
+            None => Err(TransferError::IncompatibleServer(String::from(
+                "server did not answer the protocol handshake, it probably runs an older version",
+            ))),
+            Some(mut msg) => {
+                if msg.read_i8()? != 1 {
+                    let description = String::from_utf8(msg.read_buffer()?.to_vec())?;
+                    return Err(TransferError::IncompatibleServer(description));
+                }
+                let version = msg.read_u32()?;
+                let features = msg.read_u64()?;
+                if version < MIN_PROTOCOL_VERSION {
+                    return Err(TransferError::IncompatibleServer(format!(
+                        "server protocol version {} is not supported, client supports versions {} to {}",
+                        version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                    )));
+                }
+                Ok(features)
+            }
+        }
+    }
+
+    /// Starts an upload, fails if the server does not support `options`
+    pub(crate) fn upload_request<M: Fields>(
+        features: u64,
+        encrypt: bool,
+        options: &ShareOptions,
+    ) -> Result<M, TransferError> {
+        if options.lifetime != 0 && features & FEATURE_UPLOAD_EXPIRATION == 0 {
+            return Err(TransferError::IncompatibleServer(String::from(
+                "server does not support choosing the expiration time",
+            )));
+        }
+        if options.max_downloads != 0 && features & FEATURE_DOWNLOAD_LIMIT == 0 {
+            return Err(TransferError::IncompatibleServer(String::from(
+                "server does not support limiting downloads",
+            )));
+        }
+
+        let mut message = M::new();
+        message.write_i32(COMMAND_UPLOAD);
+        message.write_u64(options.lifetime);
+        message.write_u64(options.max_downloads);
+        message.write_u8(encrypt as u8);
+        Ok(message)
+    }
+
+    /// Answer to [upload_request](fn.upload_request.html)
+    pub(crate) struct UploadResponse {
+        pub id: Vec<u8>,
+        pub resume_token: Option<Vec<u8>>,
+        pub expiration: Option<u64>,
+        pub delete_token: Option<Vec<u8>>,
+    }
+
+    /// Parses the answer to an upload of `size` bytes, `None` if none arrived
+    pub(crate) fn upload_response<M: Fields>(
+        response: Option<M>,
+        features: u64,
+        size: usize,
+    ) -> Result<UploadResponse, TransferError> {
+        let mut msg = match response {
+            None => return Err(TransferError::ServerError),
+            Some(msg) => msg,
+        };
+        let id = msg.read_buffer()?.to_vec();
+        let max_size = msg.read_u64()?;
+        if size > max_size as usize {
+            return Err(TransferError::SizeLimitExceeded);
+        }
+        let mut resume_token = None;
+        if features & FEATURE_RESUMABLE_UPLOAD != 0 {
+            resume_token = Some(msg.read_buffer()?.to_vec());
+        }
+        let mut expiration = None;
+        if features & FEATURE_UPLOAD_EXPIRATION != 0 {
+            expiration = Some(msg.read_u64()?);
+        }
+        let mut delete_token = None;
+        if features & FEATURE_DELETE != 0 {
+            delete_token = Some(msg.read_buffer()?.to_vec());
+        }
+        Ok(UploadResponse {
+            id,
+            resume_token,
+            expiration,
+            delete_token,
+        })
+    }
+
+    /// Part of the payload of an upload or a download
+    pub(crate) fn data_message<M: Fields>(data: &[u8]) -> M {
+        let mut message = M::new();
+        message.write_u8(1);
+        message.write_buffer(data);
+        message
+    }
+
+    /// Ends the payload of an upload or a download
+    pub(crate) fn end_message<M: Fields>() -> M {
+        let mut message = M::new();
+        message.write_u8(0);
+        message
+    }
+
+    pub(crate) fn download_request<M: Fields>(id: &[u8; 32]) -> M {
+        let mut message = M::new();
+        message.write_i32(COMMAND_DOWNLOAD);
+        message.write_buffer(id);
+        message
+    }
+
+    /// Downloads `length` bytes of the stored payload from `offset`, 0 means until the end
+    pub(crate) fn range_request<M: Fields>(id: &[u8; 32], offset: u64, length: u64) -> M {
+        let mut message = M::new();
+        message.write_i32(COMMAND_DOWNLOAD_RANGE);
+        message.write_buffer(id);
+        message.write_u64(offset);
+        message.write_u64(length);
+        message
+    }
+
+    /// Fails if the server cannot resume downloads
+    pub(crate) fn check_ranged_download(features: u64) -> Result<(), TransferError> {
+        if features & FEATURE_RANGED_DOWNLOAD == 0 {
+            return Err(TransferError::IncompatibleServer(String::from(
+                "server does not support resuming downloads",
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checks a response to a command, which starts with 1 on success
+    /// or with -1 followed by an error description and code, `None` if none arrived
+    pub(crate) fn response<M: Fields>(response: Option<M>) -> Result<M, TransferError> {
+        match response {
+            None => Err(TransferError::ServerError),
+            Some(mut msg) => {
+                if msg.read_i8()? != 1 {
+                    return Err(server_error(&mut msg));
+                }
+                Ok(msg)
+            }
+        }
+    }
+
+    /// Reads the description and code following a failed response, if the server sent them
+    pub(crate) fn server_error<M: Fields>(msg: &mut M) -> TransferError {
+        let description = match msg.read_buffer() {
+            Ok(description) => String::from_utf8_lossy(description).into_owned(),
+            Err(_) => return TransferError::ServerError,
+        };
+        // Older servers send the description only
+        let code = msg.read_u32().ok();
+        TransferError::from_server(code, description)
+    }
+}
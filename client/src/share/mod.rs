@@ -0,0 +1,659 @@
+pub mod share {
+    use crate::compression::compression::{Codec, Compressor, Decompressor};
+    use crate::crypto::crypto::{unwrap_key, wrap_key, CryptoError, WRAPPED_KEY_SIZE};
+    use crate::extract::extract::{ExistingPolicy, ExtractError, Extractor, Rejection};
+    use crate::manifest::manifest::{Frame, Manifest};
+    use crate::transfer::transfer::{
+        Download, ProgressCallback, ShareOptions, TransferError, Upload,
+    };
+    use openssl::pkey::{PKey, Private, Public};
+    use simpletcp::simpletcp::Error::TcpError;
+    use std::convert::TryInto;
+    use std::error::Error;
+    use std::fmt::{Display, Formatter};
+    use std::fs::{File, OpenOptions};
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::net::{SocketAddr, ToSocketAddrs};
+    use std::path::{Path, PathBuf};
+    use std::{fmt, fs, io};
+    use tar::{Archive, Builder};
+
+    #[derive(Debug)]
+    pub enum ShareError {
+        Transfer(TransferError),
+        Crypto(CryptoError),
+        Extract(ExtractError),
+        /// Tar archive could not be built or read
+        Archive(io::Error),
+        /// Local file could not be read or written
+        File(PathBuf, io::Error),
+        IOError(io::Error),
+        /// Path has no file name to share it under
+        InvalidPath(PathBuf),
+        /// Two shared paths have the same file name
+        DuplicateName(String),
+        InvalidCompressionLevel(i32),
+        /// Download key does not match the chosen encryption
+        InvalidKey,
+        /// Download key is protected by a password, but none was given
+        PasswordRequired,
+        /// Confirmation callback declined the download
+        Cancelled,
+        /// Combination of settings the transfer cannot be done with
+        Unsupported(&'static str),
+    }
+
+    impl Display for ShareError {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            match &self {
+                ShareError::Transfer(err) => f.write_str(&format!("{}", err)),
+                ShareError::Crypto(err) => f.write_str(&format!("{}", err)),
+                ShareError::Extract(err) => f.write_str(&format!("{}", err)),
+                ShareError::Archive(err) => f.write_str(&format!("Archive error: {}", err)),
+                ShareError::File(path, err) => {
+                    f.write_str(&format!("Cannot access {:?}: {}", path, err))
+                }
+                ShareError::IOError(err) => f.write_str(&format!("{}", err)),
+                ShareError::InvalidPath(path) => f.write_str(&format!("Cannot share {:?}", path)),
+                ShareError::DuplicateName(name) => {
+                    f.write_str(&format!("Cannot share two files named {:?}", name))
+                }
+                ShareError::InvalidCompressionLevel(level) => {
+                    f.write_str(&format!("Invalid compression level {}", level))
+                }
+                ShareError::InvalidKey => f.write_str("Invalid download key size"),
+                ShareError::PasswordRequired => {
+                    f.write_str("Download key is protected by a password")
+                }
+                ShareError::Cancelled => f.write_str("Download cancelled"),
+                ShareError::Unsupported(reason) => f.write_str(reason),
+            }
+        }
+    }
+
+    impl Error for ShareError {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            match &self {
+                ShareError::Transfer(err) => Some(err),
+                ShareError::Crypto(err) => Some(err),
+                ShareError::Extract(err) => Some(err),
+                ShareError::Archive(err) | ShareError::File(_, err) | ShareError::IOError(err) => {
+                    Some(err)
+                }
+                _ => None,
+            }
+        }
+    }
+
+    impl From<TransferError> for ShareError {
+        fn from(err: TransferError) -> Self {
+            ShareError::Transfer(err)
+        }
+    }
+
+    impl From<CryptoError> for ShareError {
+        fn from(err: CryptoError) -> Self {
+            ShareError::Crypto(err)
+        }
+    }
+
+    impl From<ExtractError> for ShareError {
+        fn from(err: ExtractError) -> Self {
+            match err {
+                ExtractError::IOError(err) if carries_cause(&err) => ShareError::from(err),
+                err => ShareError::Extract(err),
+            }
+        }
+    }
+
+    /// Errors of the transfer and of decryption get here wrapped by Read and Write,
+    /// they are unwrapped so the cause is not lost
+    impl From<io::Error> for ShareError {
+        fn from(err: io::Error) -> Self {
+            if !carries_cause(&err) {
+                return ShareError::IOError(err);
+            }
+            match err.into_inner().unwrap().downcast::<TransferError>() {
+                Ok(err) => ShareError::Transfer(*err),
+                Err(err) => ShareError::Crypto(*err.downcast::<CryptoError>().unwrap()),
+            }
+        }
+    }
+
+    fn carries_cause(err: &io::Error) -> bool {
+        err.get_ref()
+            .is_some_and(|inner| inner.is::<TransferError>() || inner.is::<CryptoError>())
+    }
+
+    /// Error of the tar archive, unless it was caused by the transfer
+    fn archive_error(err: io::Error) -> ShareError {
+        match ShareError::from(err) {
+            ShareError::IOError(err) => ShareError::Archive(err),
+            err => err,
+        }
+    }
+
+    pub enum Encryption {
+        None,
+        /// Content key is part of the download key
+        Key,
+        /// Content key is part of the download key, wrapped with a key derived from the password
+        Password(String),
+        /// Content key is wrapped for the recipients in the uploaded data,
+        /// the download key is just the id
+        Recipients(Vec<PKey<Public>>),
+        /// Private key of a recipient, only for downloading
+        Identity(PKey<Private>),
+    }
+
+    /// Settings of [share_path](fn.share_path.html) and [share_reader](fn.share_reader.html)
+    pub struct UploadOptions {
+        pub encryption: Encryption,
+        pub codec: Codec,
+        /// Compression level, `None` uses the default level of the codec
+        pub level: Option<i32>,
+        /// Message shown to the receiver before downloading
+        pub message: Option<String>,
+        pub share: ShareOptions,
+        /// Expected fingerprint of the server, `None` skips the check
+        pub fingerprint: Option<Vec<u8>>,
+    }
+
+    impl Default for UploadOptions {
+        fn default() -> Self {
+            Self {
+                encryption: Encryption::Key,
+                codec: Codec::Zstd,
+                level: None,
+                message: None,
+                share: ShareOptions::default(),
+                fingerprint: None,
+            }
+        }
+    }
+
+    /// Keys of a finished upload
+    pub struct Share {
+        /// Key the receiver downloads the share with, base64 encoded by the command-line tool
+        pub download_key: Vec<u8>,
+        /// Key the share can be deleted with, `None` if the server does not support it
+        pub delete_key: Option<Vec<u8>>,
+        /// Unix timestamp the share expires at, `None` if the server does not report it
+        pub expiration: Option<u64>,
+    }
+
+    /// Uploads files and directories as one share, each under its file name
+    pub fn share_path<A: ToSocketAddrs>(
+        addr: A,
+        paths: &[PathBuf],
+        options: UploadOptions,
+        progress: Option<ProgressCallback>,
+    ) -> Result<Share, ShareError> {
+        let mut canonical = Vec::new();
+        let mut roots = Vec::new();
+        for path in paths {
+            let path = path
+                .canonicalize()
+                .map_err(|err| ShareError::File(path.clone(), err))?;
+            let root = match path.file_name() {
+                Some(root) => root.to_string_lossy().into_owned(),
+                None => return Err(ShareError::InvalidPath(path)),
+            };
+            if roots.contains(&root) {
+                return Err(ShareError::DuplicateName(root));
+            }
+            roots.push(root);
+            canonical.push(path);
+        }
+
+        let mut size = 0;
+        let mut count = 0;
+        for path in &canonical {
+            size += dir_size(path).map_err(|err| ShareError::File(path.clone(), err))?;
+            count += file_count(path).map_err(|err| ShareError::File(path.clone(), err))?;
+        }
+        let manifest = Manifest::new(
+            roots.clone(),
+            size,
+            count,
+            options.message.clone(),
+            options.codec,
+        )?;
+
+        upload(addr, manifest, options, progress, |compressor| {
+            let mut archive = Builder::new(compressor);
+            for (path, root) in canonical.iter().zip(&roots) {
+                if path.is_dir() {
+                    archive.append_dir_all(root, path).map_err(archive_error)?;
+                } else {
+                    let mut file =
+                        File::open(path).map_err(|err| ShareError::File(path.clone(), err))?;
+                    archive
+                        .append_file(root, &mut file)
+                        .map_err(archive_error)?;
+                }
+            }
+            archive.finish().map_err(archive_error)
+        })
+    }
+
+    /// Uploads everything `reader` produces as a single file called `name`
+    pub fn share_reader<A: ToSocketAddrs, R: Read>(
+        addr: A,
+        name: &str,
+        mut reader: R,
+        options: UploadOptions,
+        progress: Option<ProgressCallback>,
+    ) -> Result<Share, ShareError> {
+        let manifest =
+            Manifest::stream(String::from(name), options.message.clone(), options.codec)?;
+        upload(addr, manifest, options, progress, |compressor| {
+            io::copy(&mut reader, compressor)?;
+            Ok(())
+        })
+    }
+
+    fn upload<A, F>(
+        addr: A,
+        manifest: Manifest,
+        options: UploadOptions,
+        progress: Option<ProgressCallback>,
+        write_payload: F,
+    ) -> Result<Share, ShareError>
+    where
+        A: ToSocketAddrs,
+        F: FnOnce(&mut Compressor<Upload>) -> Result<(), ShareError>,
+    {
+        // Nobody could decrypt the share, the download key would carry no content key
+        if let Encryption::Identity(_) = options.encryption {
+            return Err(ShareError::Unsupported(
+                "Identity can only be used for downloading, upload to recipients instead",
+            ));
+        }
+        let level = options
+            .level
+            .unwrap_or_else(|| manifest.codec.default_level());
+        if !manifest.codec.valid_level(level) {
+            return Err(ShareError::InvalidCompressionLevel(level));
+        }
+
+        // Compressed size is not known yet, the server checks the limit while uploading
+        let size = if manifest.codec == Codec::None && !manifest.stream {
+            manifest.size as usize
+        } else {
+            0
+        };
+        let encryption = options.encryption;
+        let recipients = match &encryption {
+            Encryption::Recipients(recipients) => &recipients[..],
+            _ => &[],
+        };
+        let mut upload = Upload::new(
+            addr,
+            !matches!(encryption, Encryption::None),
+            size,
+            options.share,
+            recipients,
+            options.fingerprint,
+        )?;
+        if let Some(progress) = progress {
+            upload.set_progress(progress);
+        }
+        manifest.write(&mut upload)?;
+        let mut compressor = Compressor::new(manifest.codec, level, upload)?;
+        write_payload(&mut compressor)?;
+        let mut upload = compressor.finish()?;
+        upload.finalize()?;
+
+        let mut download_key = upload.id().clone();
+        match &encryption {
+            Encryption::None | Encryption::Recipients(_) | Encryption::Identity(_) => {}
+            Encryption::Key => {
+                download_key.extend_from_slice(upload.key().unwrap());
+            }
+            Encryption::Password(password) => {
+                download_key.extend_from_slice(&wrap_key(upload.key().unwrap(), password)?);
+            }
+        }
+        let delete_key = upload.delete_token().map(|token| {
+            let mut delete_key = upload.id().clone();
+            delete_key.extend_from_slice(token);
+            delete_key
+        });
+
+        Ok(Share {
+            download_key,
+            delete_key,
+            expiration: upload.expiration(),
+        })
+    }
+
+    /// Where [fetch_to](fn.fetch_to.html) puts the downloaded share
+    pub enum Target {
+        /// Unpack into a directory, taking care of files that already exist there
+        Directory {
+            path: PathBuf,
+            policy: ExistingPolicy,
+        },
+        /// Store the archive, or the streamed file, as a file
+        File(PathBuf),
+        /// Continue storing the archive into a partially downloaded file,
+        /// a compressed share continues from the payload kept next to the file
+        ResumeFile(PathBuf),
+        /// Write the archive, or the streamed file, into a writer
+        Writer(Box<dyn Write>),
+    }
+
+    /// Decides from the manifest whether a download of [fetch_to](fn.fetch_to.html) continues
+    pub type ConfirmCallback = Box<dyn FnMut(&Manifest) -> bool>;
+
+    /// Settings of [fetch_to](fn.fetch_to.html)
+    pub struct FetchOptions {
+        /// `Key` for encrypted shares, `Password` if the download key is protected
+        /// by a password and `Identity` for shares sent to recipients
+        pub encryption: Encryption,
+        /// Expected fingerprint of the server, `None` skips the check
+        pub fingerprint: Option<Vec<u8>>,
+        /// Called with the manifest before anything is written,
+        /// the download is cancelled if it returns false
+        pub confirm: Option<ConfirmCallback>,
+    }
+
+    impl Default for FetchOptions {
+        fn default() -> Self {
+            Self {
+                encryption: Encryption::Key,
+                fingerprint: None,
+                confirm: None,
+            }
+        }
+    }
+
+    /// Outcome of a finished download
+    pub struct Fetched {
+        /// Manifest of the share, `None` for shares uploaded by older clients
+        pub manifest: Option<Manifest>,
+        /// Paths the share was unpacked to, empty unless unpacking into a directory
+        pub roots: Vec<PathBuf>,
+        /// Files that were kept because they already existed
+        pub skipped: Vec<PathBuf>,
+        /// Archive entries that were not unpacked
+        pub rejected: Vec<Rejection>,
+    }
+
+    impl Fetched {
+        fn new(manifest: Option<Manifest>) -> Self {
+            Self {
+                manifest,
+                roots: Vec::new(),
+                skipped: Vec::new(),
+                rejected: Vec::new(),
+            }
+        }
+    }
+
+    /// Checks whether `download_key` has to be unlocked with `Encryption::Password`
+    pub fn is_password_protected(download_key: &[u8]) -> bool {
+        download_key.len() == 32 + WRAPPED_KEY_SIZE
+    }
+
+    /// Downloads the share of `download_key` into `target`
+    pub fn fetch_to<A: ToSocketAddrs>(
+        addr: A,
+        download_key: &[u8],
+        target: Target,
+        options: FetchOptions,
+        progress: Option<ProgressCallback>,
+    ) -> Result<Fetched, ShareError> {
+        let protected = is_password_protected(download_key);
+        let key: Option<[u8; 32]> = match &options.encryption {
+            Encryption::Key if protected => return Err(ShareError::PasswordRequired),
+            Encryption::Password(password) if protected => {
+                Some(unwrap_key(&download_key[32..], password)?)
+            }
+            Encryption::Key | Encryption::Password(_) if download_key.len() == 64 => {
+                Some(download_key[32..].try_into().unwrap())
+            }
+            Encryption::None | Encryption::Identity(_) if download_key.len() == 32 => None,
+            _ => return Err(ShareError::InvalidKey),
+        };
+        let id = download_key[..32].try_into().unwrap();
+
+        if let Target::ResumeFile(path) = &target {
+            return resume(addr, id, key, path, options, progress);
+        }
+
+        let mut download = Download::new(addr, id, key, options.fingerprint)?;
+        if let Some(progress) = progress {
+            download.set_progress(progress);
+        }
+        if let Encryption::Identity(identity) = &options.encryption {
+            download.unlock(identity)?;
+        }
+
+        let (manifest, legacy_prefix) = match Manifest::read(&mut download)? {
+            Frame::Manifest(manifest) => (Some(manifest), Vec::new()),
+            Frame::Legacy(prefix) => (None, prefix),
+        };
+
+        // Names listed by the sender are claimed before anything is downloaded,
+        // archives without a manifest are checked as their entries arrive
+        let extractor = match &target {
+            Target::Directory { path, policy } => {
+                let mut extractor = Extractor::new(path, *policy)?;
+                if let Some(manifest) = &manifest {
+                    for root in &manifest.roots {
+                        extractor.add_root(root)?;
+                    }
+                    extractor.lock_roots();
+                }
+                Some(extractor)
+            }
+            _ => None,
+        };
+
+        if let (Some(manifest), Some(mut confirm)) = (&manifest, options.confirm) {
+            if !confirm(manifest) {
+                return Err(ShareError::Cancelled);
+            }
+        }
+        let codec = manifest
+            .as_ref()
+            .map_or(Codec::None, |manifest| manifest.codec);
+        let download = io::Cursor::new(legacy_prefix).chain(download);
+
+        if let Target::File(path) | Target::ResumeFile(path) = &target {
+            if codec != Codec::None {
+                let partial = partial_path(path);
+                let copy = File::create(&partial).map_err(|err| ShareError::File(partial, err))?;
+                decompress_to(codec, Tee::new(download, copy), path)?;
+                return Ok(Fetched::new(manifest));
+            }
+        }
+        let mut download = Decompressor::new(codec, download)?;
+
+        match target {
+            Target::Directory { .. } => {
+                let mut extractor = extractor.unwrap();
+                match &manifest {
+                    Some(manifest) if manifest.stream => {
+                        if let Some(mut file) = extractor.create_file(&manifest.roots[0])? {
+                            io::copy(&mut download, &mut file)?;
+                        }
+                    }
+                    _ => {
+                        let mut archive = Archive::new(download);
+                        for entry in archive.entries().map_err(archive_error)? {
+                            extractor.unpack(&mut entry.map_err(archive_error)?)?;
+                        }
+                    }
+                }
+
+                let mut fetched = Fetched::new(manifest);
+                fetched.roots = extractor.roots().into_iter().cloned().collect();
+                fetched.skipped = extractor.skipped().to_vec();
+                fetched.rejected = extractor.rejected().to_vec();
+                Ok(fetched)
+            }
+            Target::File(path) | Target::ResumeFile(path) => {
+                let mut file = File::create(&path).map_err(|err| ShareError::File(path, err))?;
+                io::copy(&mut download, &mut file)?;
+                Ok(Fetched::new(manifest))
+            }
+            Target::Writer(mut writer) => {
+                io::copy(&mut download, &mut writer)?;
+                Ok(Fetched::new(manifest))
+            }
+        }
+    }
+
+    fn resume<A: ToSocketAddrs>(
+        addr: A,
+        id: &[u8; 32],
+        key: Option<[u8; 32]>,
+        path: &Path,
+        options: FetchOptions,
+        progress: Option<ProgressCallback>,
+    ) -> Result<Fetched, ShareError> {
+        let addrs: Vec<SocketAddr> = addr
+            .to_socket_addrs()
+            .map_err(|err| TransferError::ConnectionFailed(TcpError(err)))?
+            .collect();
+        let fingerprint = options.fingerprint;
+        let identity = match &options.encryption {
+            Encryption::Identity(identity) => Some(identity),
+            _ => None,
+        };
+        let mut download = Download::new(&addrs[..], id, key, fingerprint.clone())?;
+        if let Some(identity) = identity {
+            download.unlock(identity)?;
+        }
+        let manifest = match Manifest::read(&mut download)? {
+            Frame::Manifest(manifest) => Some(manifest),
+            Frame::Legacy(_) => None,
+        };
+        // Offsets in the archive do not map to the compressed payload,
+        // so a compressed share continues the payload kept next to the file
+        let codec = manifest
+            .as_ref()
+            .map_or(Codec::None, |manifest| manifest.codec);
+        let stored = match codec {
+            Codec::None => path.to_path_buf(),
+            _ => partial_path(path),
+        };
+        let started = path.metadata().is_ok_and(|metadata| metadata.len() > 0);
+        if codec != Codec::None && started && !stored.exists() {
+            return Err(ShareError::Unsupported(
+                "Compressed payload of the interrupted download is missing, download the share again without --resume",
+            ));
+        }
+        if let (Some(manifest), Some(mut confirm)) = (&manifest, options.confirm) {
+            if !confirm(manifest) {
+                return Err(ShareError::Cancelled);
+            }
+        }
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&stored)
+            .map_err(|err| ShareError::File(stored.clone(), err))?;
+        let offset = file.metadata()?.len();
+
+        let skip = manifest
+            .as_ref()
+            .map_or(0, |manifest| manifest.frame_size());
+        drop(download);
+
+        let mut download = match identity {
+            Some(identity) => Download::resume_with_identity(
+                &addrs[..],
+                id,
+                identity,
+                offset + skip,
+                fingerprint,
+            )?,
+            None => Download::resume(&addrs[..], id, key, offset + skip, fingerprint)?,
+        };
+        if let Some(progress) = progress {
+            download.set_progress(progress);
+        }
+        let start = download.position().max(skip);
+        let discard = start - download.position();
+        io::copy(&mut (&mut download).take(discard), &mut io::sink())?;
+
+        // Drop anything past the position the server resumed from
+        file.set_len(start - skip)?;
+        file.seek(SeekFrom::End(0))?;
+        if codec == Codec::None {
+            io::copy(&mut download, &mut file)?;
+        } else {
+            // The archive is decompressed again from the start of the payload
+            let kept = File::open(&stored).map_err(|err| ShareError::File(stored, err))?;
+            let payload = kept.take(start - skip).chain(Tee::new(download, file));
+            decompress_to(codec, payload, path)?;
+        }
+        Ok(Fetched::new(manifest))
+    }
+
+    /// Path of the compressed payload kept while a compressed share is stored as a file
+    fn partial_path(path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_os_string();
+        name.push(".sfshr-partial");
+        PathBuf::from(name)
+    }
+
+    /// Decompresses `payload` into `path` and removes the kept compressed payload
+    /// once the whole archive is written
+    fn decompress_to<R: Read>(codec: Codec, payload: R, path: &Path) -> Result<(), ShareError> {
+        let mut payload = Decompressor::new(codec, payload)?;
+        let mut file =
+            File::create(path).map_err(|err| ShareError::File(path.to_path_buf(), err))?;
+        io::copy(&mut payload, &mut file)?;
+        let partial = partial_path(path);
+        fs::remove_file(&partial).map_err(|err| ShareError::File(partial, err))
+    }
+
+    /// Reader that writes everything read from `reader` into `copy`
+    struct Tee<R: Read, W: Write> {
+        reader: R,
+        copy: W,
+    }
+
+    impl<R: Read, W: Write> Tee<R, W> {
+        fn new(reader: R, copy: W) -> Self {
+            Self { reader, copy }
+        }
+    }
+
+    impl<R: Read, W: Write> Read for Tee<R, W> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let bytes_read = self.reader.read(buf)?;
+            self.copy.write_all(&buf[..bytes_read])?;
+            Ok(bytes_read)
+        }
+    }
+
+    fn dir_size(path: &Path) -> io::Result<u64> {
+        if path.is_file() {
+            return Ok(path.metadata()?.len());
+        }
+        let mut size = 0;
+        for entry in fs::read_dir(path)? {
+            size += dir_size(&entry?.path())?;
+        }
+        Ok(size)
+    }
+
+    fn file_count(path: &Path) -> io::Result<u64> {
+        if !path.is_dir() {
+            return Ok(1);
+        }
+        let mut count = 0;
+        for entry in fs::read_dir(path)? {
+            count += file_count(&entry?.path())?;
+        }
+        Ok(count)
+    }
+}
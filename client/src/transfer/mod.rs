@@ -1,53 +1,178 @@
 pub mod transfer {
+    use crate::crypto::crypto::{
+        recipient_count, unwrap_key_with_identity, wrap_key_for_recipients, CryptoError,
+        StreamDecrypter, StreamEncrypter, CHUNK_SIZE, ENCRYPTED_CHUNK_SIZE, HEADER_SIZE,
+        RECIPIENTS_HEADER_SIZE, RECIPIENT_SIZE,
+    };
+    use crate::protocol::protocol::{
+        check_ranged_download, data_message, download_request, end_message, hello_request,
+        hello_response, range_request, response, server_error, upload_request, upload_response,
+        Fields, COMMAND_DELETE, COMMAND_INFO, COMMAND_RESUME_UPLOAD, ERROR_DOWNLOAD_LIMIT_REACHED,
+        ERROR_NOT_FOUND, ERROR_UPLOAD_NOT_FINISHED, FEATURE_DELETE, FEATURE_INFO,
+    };
     use openssl::error::ErrorStack;
-    use openssl::symm::{Cipher, Crypter, Mode};
+    use openssl::pkey::{PKey, Private, Public};
     use rand::prelude::StdRng;
     use rand::{RngCore, SeedableRng};
     use simpletcp::simpletcp::{Message, MessageError, TcpStream};
+    use simpletcp::utils::{poll, EV_POLLOUT};
+    use std::collections::VecDeque;
+    use std::convert::TryInto;
+    use std::error::Error;
     use std::fmt::{Display, Formatter};
     use std::io::{ErrorKind, Read, Write};
-    use std::net::ToSocketAddrs;
+    use std::net::{SocketAddr, ToSocketAddrs};
     use std::string::FromUtf8Error;
-    use std::time::Instant;
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
     use std::{fmt, io};
 
-    #[macro_export]
-    macro_rules! printinfoln {
-    ($q:expr, $($x:expr), *) => {
-        if !$q {
-        println!($($x,)*);
-        }
-    };
-}
+    /// Amount of sent data kept for retransmission after an upload is resumed
+    const REPLAY_LIMIT: u64 = 16 * 1024 * 1024;
+    const RESUME_ATTEMPTS: u32 = 5;
+    const RESUME_DELAY: Duration = Duration::from_secs(2);
+
+    /// Progress of a running transfer
+    pub enum Progress {
+        /// Bytes of payload transferred so far and the time since the transfer started
+        Transferred { bytes: u64, elapsed: Duration },
+        /// The connection was lost and the upload is being resumed
+        Reconnecting,
+    }
 
-    #[macro_export]
-    macro_rules! printinfo {
-    ($q:expr, $($x:expr), *) => {
-        if !$q {
-        print!($($x,)*);
-        }
-    };
-}
+    /// Receives progress of an [Upload](struct.Upload.html) or a [Download](struct.Download.html)
+    pub type ProgressCallback = Box<dyn FnMut(Progress) + Send>;
+
+    /// Limits of an uploaded share, zero leaves the value to the server
+    #[derive(Clone, Copy, Default)]
+    pub struct ShareOptions {
+        /// Expiration time in seconds
+        pub lifetime: u64,
+        /// Number of times the share can be downloaded before it is removed
+        pub max_downloads: u64,
+    }
 
+    #[derive(Debug)]
     pub enum TransferError {
+        /// Connection to the server could not be established
+        ConnectionFailed(simpletcp::simpletcp::Error),
+        /// Established connection was lost
         NetworkError(simpletcp::simpletcp::Error),
         ServerError,
-        EncryptionError,
+        /// The server refused the request and described why
+        ServerMessage(String),
+        /// The share expired, was deleted or reached its download limit
+        ShareUnavailable(String),
+        /// The share is still being uploaded, it can be downloaded once the upload finishes
+        UploadNotFinished(String),
+        EncryptionError(CryptoError),
         CorruptedMessage,
         SizeLimitExceeded,
-        FingerprintMismatch,
+        FingerprintMismatch {
+            expected: Vec<u8>,
+            received: Vec<u8>,
+        },
+        IncompatibleServer(String),
+        ResumeFailed,
+        IOError(io::Error),
+    }
+
+    impl TransferError {
+        /// Error for a failure the server reported with `code` and described in `description`
+        pub fn from_server(code: Option<u32>, description: String) -> Self {
+            match code {
+                Some(ERROR_NOT_FOUND) | Some(ERROR_DOWNLOAD_LIMIT_REACHED) => {
+                    TransferError::ShareUnavailable(description)
+                }
+                Some(ERROR_UPLOAD_NOT_FINISHED) => TransferError::UploadNotFinished(description),
+                _ => TransferError::ServerMessage(description),
+            }
+        }
     }
 
     impl Display for TransferError {
         fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
             match &self {
-                TransferError::NetworkError(net_err) => f.write_str(&format!("{:?}", net_err)),
-                TransferError::ServerError => f.write_str("ServerError"),
-                TransferError::EncryptionError => f.write_str("EncryptionError"),
+                TransferError::ConnectionFailed(net_err) => f.write_str(&format!(
+                    "Cannot connect to the server: {}",
+                    describe(net_err)
+                )),
+                TransferError::NetworkError(net_err) => {
+                    f.write_str(&format!("Network error: {}", describe(net_err)))
+                }
+                TransferError::ServerError => f.write_str("ServerError: server did not respond"),
+                TransferError::ServerMessage(description) => {
+                    f.write_str(&format!("Received an error message: {}", description))
+                }
+                TransferError::ShareUnavailable(description) => f.write_str(&format!(
+                    "Share expired, was deleted or does not exist ({})",
+                    description
+                )),
+                TransferError::UploadNotFinished(description) => f.write_str(&format!(
+                    "Share is still being uploaded, try again later ({})",
+                    description
+                )),
+                TransferError::EncryptionError(err) => {
+                    f.write_str(&format!("EncryptionError: {}", err))
+                }
                 TransferError::CorruptedMessage => f.write_str("CorruptedMessage"),
                 TransferError::SizeLimitExceeded => f.write_str("SizeLimitExceeded"),
-                TransferError::FingerprintMismatch => f.write_str("FingerprintMismatch"),
+                TransferError::FingerprintMismatch { expected, received } => f.write_str(&format!(
+                    "FingerprintMismatch: expected {}, received {}",
+                    hex::encode(expected),
+                    hex::encode(received)
+                )),
+                TransferError::IncompatibleServer(reason) => {
+                    f.write_str(&format!("IncompatibleServer: {}", reason))
+                }
+                TransferError::ResumeFailed => f.write_str("ResumeFailed"),
+                TransferError::IOError(err) => f.write_str(&format!("{}", err)),
+            }
+        }
+    }
+
+    impl Error for TransferError {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            match &self {
+                TransferError::ConnectionFailed(simpletcp::simpletcp::Error::TcpError(err))
+                | TransferError::NetworkError(simpletcp::simpletcp::Error::TcpError(err))
+                | TransferError::IOError(err) => Some(err),
+                TransferError::EncryptionError(err) => Some(err),
+                _ => None,
+            }
+        }
+    }
+
+    /// Describes an error of the connection, simpletcp only implements Debug for it
+    fn describe(err: &simpletcp::simpletcp::Error) -> String {
+        match err {
+            simpletcp::simpletcp::Error::NotReady => String::from("connection is not ready"),
+            simpletcp::simpletcp::Error::EncryptionError(err) => {
+                format!("securing the connection failed ({})", err)
+            }
+            simpletcp::simpletcp::Error::TcpError(err) => format!("{}", err),
+            simpletcp::simpletcp::Error::ConnectionClosed => {
+                String::from("connection closed by the server")
             }
+            simpletcp::simpletcp::Error::SizeLimitExceeded => {
+                String::from("server sent a message over the size limit")
+            }
+        }
+    }
+
+    /// Keeps the error, so it can be recovered after passing through Read or Write
+    impl From<TransferError> for io::Error {
+        fn from(err: TransferError) -> Self {
+            match err {
+                TransferError::IOError(err) => err,
+                err => io::Error::new(ErrorKind::ConnectionReset, err),
+            }
+        }
+    }
+
+    impl From<io::Error> for TransferError {
+        fn from(err: io::Error) -> Self {
+            TransferError::IOError(err)
         }
     }
 
@@ -58,8 +183,14 @@ pub mod transfer {
     }
 
     impl From<ErrorStack> for TransferError {
-        fn from(_: ErrorStack) -> Self {
-            TransferError::EncryptionError
+        fn from(err: ErrorStack) -> Self {
+            TransferError::EncryptionError(CryptoError::Backend(err))
+        }
+    }
+
+    impl From<CryptoError> for TransferError {
+        fn from(err: CryptoError) -> Self {
+            TransferError::EncryptionError(err)
         }
     }
 
@@ -128,117 +259,96 @@ pub mod transfer {
 
     pub struct Upload {
         conn: TcpStream,
-        crypter: Option<Crypter>,
-        encrypt_buffer: Vec<u8>,
+        addrs: Vec<SocketAddr>,
+        fingerprint: Option<Vec<u8>>,
+        resume_token: Option<Vec<u8>>,
+        encrypter: Option<StreamEncrypter>,
+        sent: VecDeque<Vec<u8>>,
+        sent_start: u64,
+        sent_end: u64,
         id: Vec<u8>,
         key: Option<[u8; 32]>,
         uploaded: usize,
         time: Instant,
-        quiet: bool,
+        progress: Option<ProgressCallback>,
+        expiration: Option<u64>,
+        delete_token: Option<Vec<u8>>,
     }
 
     impl Upload {
         pub fn new<A: ToSocketAddrs>(
             addr: A,
             encrypt: bool,
-            quiet: bool,
             size: usize,
+            options: ShareOptions,
+            recipients: &[PKey<Public>],
             fingerprint: Option<Vec<u8>>,
         ) -> Result<Self, TransferError> {
-            let mut conn = TcpStream::connect(&addr)?;
-            conn.wait_until_ready()?;
-            verify_fingerprint(&conn, fingerprint)?;
-            let mut message = Message::new();
-            message.write_i32(0);
-            conn.write_blocking(&message)?;
-
-            let msg = conn.read_timeout(5000)?;
-            let id;
-            match msg {
-                None => {
-                    return Err(TransferError::ServerError);
-                }
-                Some(mut msg) => {
-                    id = msg.read_buffer()?.to_vec();
-                    let max_size = msg.read_u64()?;
-                    if size > max_size as usize {
-                        return Err(TransferError::SizeLimitExceeded);
-                    }
-                }
-            }
+            let addrs: Vec<SocketAddr> = addr
+                .to_socket_addrs()
+                .map_err(simpletcp::simpletcp::Error::from)?
+                .collect();
+            let (mut conn, features) = connect(&addrs[..], fingerprint.clone())?;
+            conn.write_blocking(&upload_request(features, encrypt, &options)?)?;
+            let response = upload_response(conn.read_timeout(5000)?, features, size)?;
+
+            let mut upload = Self {
+                conn,
+                addrs,
+                fingerprint,
+                resume_token: response.resume_token,
+                encrypter: None,
+                sent: VecDeque::new(),
+                sent_start: 0,
+                sent_end: 0,
+                id: response.id,
+                key: None,
+                uploaded: 0,
+                progress: None,
+                time: Instant::now(),
+                expiration: response.expiration,
+                delete_token: response.delete_token,
+            };
 
-            let mut crypter = None;
-            let mut key_opt = None;
-            let mut uploaded = 0;
             if encrypt {
-                let mut rng = StdRng::from_entropy();
                 let mut key = [0; 32];
-                let mut iv = [0; 16];
-
-                rng.fill_bytes(&mut key);
-                rng.fill_bytes(&mut iv);
-                key_opt = Some(key);
-
-                crypter = Some(Crypter::new(
-                    Cipher::aes_256_cbc(),
-                    Mode::Encrypt,
-                    &key,
-                    Some(&iv),
-                )?);
+                StdRng::from_entropy().fill_bytes(&mut key);
+                upload.key = Some(key);
+
+                // Recipients find the content key in the block before the stream
+                if !recipients.is_empty() {
+                    let block = wrap_key_for_recipients(&key, recipients)?;
+                    upload.uploaded += block.len();
+                    upload.send(block)?;
+                }
 
-                let mut message = Message::new();
-                message.write_u8(1);
-                message.write_buffer(&iv);
-                conn.write_blocking(&message)?;
-                uploaded += iv.len();
+                let encrypter = StreamEncrypter::new(&key);
+                let header = encrypter.header();
+                upload.encrypter = Some(encrypter);
+                upload.send(header.to_vec())?;
+                upload.uploaded += header.len();
             }
 
-            Ok(Self {
-                conn,
-                crypter,
-                encrypt_buffer: vec![0; 256],
-                id,
-                key: key_opt,
-                uploaded,
-                quiet,
-                time: Instant::now(),
-            })
+            Ok(upload)
         }
 
         pub fn finalize(&mut self) -> Result<(), TransferError> {
-            match &mut self.crypter {
+            match &mut self.encrypter {
                 None => {}
-                Some(crypter) => {
-                    let bytes_encrypted = crypter.finalize(&mut self.encrypt_buffer)?;
-                    let mut message = Message::new();
-                    message.write_u8(1);
-                    message.write_buffer(&self.encrypt_buffer[..bytes_encrypted]);
-
-                    self.conn.write_blocking(&message)?;
+                Some(encrypter) => {
+                    let mut data = Vec::new();
+                    encrypter.finalize(&mut data)?;
+                    self.send(data)?;
                 }
             }
 
-            let mut message = Message::new();
-            message.write_u8(0);
-            self.conn.write_blocking(&message)?;
-
-            let confirmation = self.conn.read_timeout(5000)?;
-            match confirmation {
-                None => return Err(TransferError::ServerError),
-                Some(mut msg) => {
-                    if msg.read_i8().unwrap() != 1 {
-                        match msg.read_buffer() {
-                            Ok(description) => {
-                                println!("\x1b[KReceived an error message:");
-                                println!("\n{}\n", String::from_utf8(description.to_vec())?);
-                            }
-                            _ => {}
-                        }
-                        return Err(TransferError::ServerError);
-                    }
-                }
+            let message = end_message();
+            if let Err(err) = write_blocking(&mut self.conn, &message) {
+                self.reconnect(err)?;
+                write_blocking(&mut self.conn, &message)?;
             }
 
+            response(self.conn.read_timeout(5000)?)?;
             Ok(())
         }
 
@@ -246,21 +356,91 @@ pub mod transfer {
             match self.conn.read_timeout(0)? {
                 None => {}
                 Some(mut msg) => {
-                    if msg.read_i8().unwrap() == -1 {
-                        match msg.read_buffer() {
-                            Ok(description) => {
-                                println!("\x1b[KReceived an error message:");
-                                println!("\n{}\n", String::from_utf8(description.to_vec())?);
-                            }
-                            _ => {}
-                        }
-                        return Err(TransferError::ServerError);
+                    if msg.read_i8()? == -1 {
+                        return Err(server_error(&mut msg));
                     }
                 }
             }
             Ok(())
         }
 
+        /// Sends a part of the payload, resuming the upload if the connection was lost
+        fn send(&mut self, data: Vec<u8>) -> Result<(), TransferError> {
+            let message = data_message(&data);
+
+            self.sent_end += data.len() as u64;
+            self.sent.push_back(data);
+            while self.sent_end - self.sent_start > REPLAY_LIMIT && self.sent.len() > 1 {
+                self.sent_start += self.sent.pop_front().unwrap().len() as u64;
+            }
+
+            match write_blocking(&mut self.conn, &message) {
+                Ok(_) => Ok(()),
+                Err(err) => self.reconnect(err),
+            }
+        }
+
+        fn reconnect(&mut self, cause: simpletcp::simpletcp::Error) -> Result<(), TransferError> {
+            let token = match &self.resume_token {
+                None => return Err(TransferError::NetworkError(cause)),
+                Some(token) => token.clone(),
+            };
+
+            let mut error = TransferError::NetworkError(cause);
+            for _ in 0..RESUME_ATTEMPTS {
+                if let Some(progress) = &mut self.progress {
+                    progress(Progress::Reconnecting);
+                }
+                sleep(RESUME_DELAY);
+                match self.resume(&token) {
+                    Ok(_) => return Ok(()),
+                    Err(TransferError::ResumeFailed) => return Err(TransferError::ResumeFailed),
+                    Err(err) => error = err,
+                }
+            }
+
+            Err(error)
+        }
+
+        fn resume(&mut self, token: &[u8]) -> Result<(), TransferError> {
+            let (mut conn, _) = connect(&self.addrs[..], self.fingerprint.clone())?;
+            let mut message = Message::new();
+            message.write_i32(COMMAND_RESUME_UPLOAD);
+            message.write_buffer(token);
+            write_blocking(&mut conn, &message)?;
+
+            let mut msg = match conn.read_timeout(5000)? {
+                None => return Err(TransferError::ServerError),
+                Some(msg) => msg,
+            };
+            if msg.read_i8()? != 1 {
+                return Err(TransferError::ServerError);
+            }
+
+            // Send again everything the server did not store before the connection was lost
+            let offset = msg.read_u64()?;
+            if offset < self.sent_start || offset > self.sent_end {
+                return Err(TransferError::ResumeFailed);
+            }
+            let mut position = self.sent_start;
+            for data in &self.sent {
+                let end = position + data.len() as u64;
+                if end > offset {
+                    let skip = offset.saturating_sub(position) as usize;
+                    write_blocking(&mut conn, &data_message(&data[skip..]))?;
+                }
+                position = end;
+            }
+
+            self.conn = conn;
+            Ok(())
+        }
+
+        /// Reports the progress of the upload to `progress`
+        pub fn set_progress(&mut self, progress: ProgressCallback) {
+            self.progress = Some(progress);
+        }
+
         pub fn id(&self) -> &Vec<u8> {
             &self.id
         }
@@ -268,6 +448,108 @@ pub mod transfer {
         pub fn key(&self) -> Option<&[u8; 32]> {
             self.key.as_ref()
         }
+
+        /// Unix timestamp the upload expires at, `None` if the server does not report it
+        pub fn expiration(&self) -> Option<u64> {
+            self.expiration
+        }
+
+        /// Secret needed to delete the upload, `None` if the server does not support it
+        pub fn delete_token(&self) -> Option<&Vec<u8>> {
+            self.delete_token.as_ref()
+        }
+    }
+
+    /// Asks the server to remove upload `id` before it expires
+    pub fn delete<A: ToSocketAddrs>(
+        addr: A,
+        id: &[u8],
+        token: &[u8],
+        fingerprint: Option<Vec<u8>>,
+    ) -> Result<(), TransferError> {
+        let (mut conn, features) = connect(addr, fingerprint)?;
+        if features & FEATURE_DELETE == 0 {
+            return Err(TransferError::IncompatibleServer(String::from(
+                "server does not support deleting uploads",
+            )));
+        }
+
+        let mut message = Message::new();
+        message.write_i32(COMMAND_DELETE);
+        message.write_buffer(id);
+        message.write_buffer(token);
+        conn.write_blocking(&message)?;
+        response(conn.read_timeout(5000)?)?;
+        Ok(())
+    }
+
+    /// Metadata of an uploaded file
+    pub struct ShareInfo {
+        /// Size of the stored payload in bytes
+        pub size: u64,
+        /// Unix timestamp of the upload, 0 if the server does not know it
+        pub created: u64,
+        /// Unix timestamp the upload expires at
+        pub expires: u64,
+        /// Number of downloads before the upload is removed, 0 means unlimited
+        pub downloads_left: u64,
+    }
+
+    /// Asks the server for metadata of upload `id` without downloading it
+    pub fn info<A: ToSocketAddrs>(
+        addr: A,
+        id: &[u8],
+        fingerprint: Option<Vec<u8>>,
+    ) -> Result<ShareInfo, TransferError> {
+        let (mut conn, features) = connect(addr, fingerprint)?;
+        if features & FEATURE_INFO == 0 {
+            return Err(TransferError::IncompatibleServer(String::from(
+                "server does not support querying uploads",
+            )));
+        }
+
+        let mut message = Message::new();
+        message.write_i32(COMMAND_INFO);
+        message.write_buffer(id);
+        conn.write_blocking(&message)?;
+
+        let mut msg = response(conn.read_timeout(5000)?)?;
+        Ok(ShareInfo {
+            size: msg.read_u64()?,
+            created: msg.read_u64()?,
+            expires: msg.read_u64()?,
+            downloads_left: msg.read_u64()?,
+        })
+    }
+
+    /// Connects to the server, verifies its fingerprint and negotiates the protocol
+    ///
+    /// Returns the connection and the server's feature bits
+    fn connect<A: ToSocketAddrs>(
+        addr: A,
+        fingerprint: Option<Vec<u8>>,
+    ) -> Result<(TcpStream, u64), TransferError> {
+        let mut conn = TcpStream::connect(addr).map_err(TransferError::ConnectionFailed)?;
+        conn.wait_until_ready()
+            .map_err(TransferError::ConnectionFailed)?;
+        verify_fingerprint(&conn, fingerprint)?;
+
+        conn.write_blocking(&hello_request())?;
+        let features = hello_response(conn.read_timeout(5000)?)?;
+        Ok((conn, features))
+    }
+
+    /// Same as TcpStream::write_blocking, but returns an error instead of panicking
+    /// when the connection is lost
+    fn write_blocking(
+        conn: &mut TcpStream,
+        message: &Message,
+    ) -> Result<(), simpletcp::simpletcp::Error> {
+        conn.write(message)?;
+        while !conn.flush()? {
+            poll(conn, EV_POLLOUT);
+        }
+        Ok(())
     }
 
     fn verify_fingerprint(
@@ -278,11 +560,11 @@ pub mod transfer {
             None => {}
             Some(f) => {
                 let received = conn.fingerprint();
-                if received != f[..32] || f.len() != 32 {
-                    println!("Fingerprint mismatch!");
-                    println!("-expected {}", hex::encode(f));
-                    println!("-received {}", hex::encode(received));
-                    return Err(TransferError::FingerprintMismatch);
+                if f.len() != 32 || received != f[..] {
+                    return Err(TransferError::FingerprintMismatch {
+                        expected: f,
+                        received: received.to_vec(),
+                    });
                 }
             }
         }
@@ -292,48 +574,41 @@ pub mod transfer {
 
     impl Write for Upload {
         fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
-            if buffer.len() > self.encrypt_buffer.len() - 256 {
-                self.encrypt_buffer.resize(buffer.len() + 256, 0);
-            }
-            let mut message = Message::new();
-            message.write_u8(1);
-            match &mut self.crypter {
-                None => {
-                    message.write_buffer(buffer);
+            let data = match &mut self.encrypter {
+                None => buffer.to_vec(),
+                Some(encrypter) => {
+                    let mut data = Vec::new();
+                    encrypter.update(buffer, &mut data)?;
+                    data
                 }
-                Some(crypter) => {
-                    let bytes_encrypted = crypter.update(buffer, &mut self.encrypt_buffer)?;
-                    message.write_buffer(&self.encrypt_buffer[..bytes_encrypted]);
-                }
-            }
+            };
 
-            match self.conn.write_blocking(&message) {
-                Err(_) => {
-                    return Err(io::Error::new(ErrorKind::ConnectionReset, "NetworkError"));
-                }
-                _ => {}
+            // Encrypted data is sent in whole chunks, skip writes that did not complete one
+            if !data.is_empty() {
+                self.send(data)?;
             }
 
             self.uploaded += buffer.len();
-            let time = self.time.elapsed().as_micros() as f64;
-            let speed = self.uploaded as f64 / time * 1000000.0;
-
-            printinfoln!(
-                self.quiet,
-                "Uploaded {:^12} @ {:^12}  \x1b[1A\x1b[0G",
-                self.uploaded.format_size(),
-                format!("{}/s", speed.format_size())
-            );
+            if let Some(progress) = &mut self.progress {
+                progress(Progress::Transferred {
+                    bytes: self.uploaded as u64,
+                    elapsed: self.time.elapsed(),
+                });
+            }
 
-            if self.check_for_error().is_err() {
-                return Err(io::Error::new(ErrorKind::ConnectionReset, "NetworkError"));
+            match self.check_for_error() {
+                Ok(_) => {}
+                Err(TransferError::NetworkError(err)) => self.reconnect(err)?,
+                Err(err) => return Err(io::Error::from(err)),
             }
 
             Ok(buffer.len())
         }
 
+        /// Written data is sent right away, except encrypted data that does not
+        /// complete a chunk yet, which is sent by [finalize](#method.finalize)
         fn flush(&mut self) -> io::Result<()> {
-            unimplemented!();
+            Ok(())
         }
 
         fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
@@ -344,15 +619,130 @@ pub mod transfer {
         }
     }
 
+    /// Received payload of a download, shared by [Download](struct.Download.html)
+    /// and the async download
+    pub(crate) struct Payload {
+        decrypter: Option<StreamDecrypter>,
+        decrypt_buffer: Vec<u8>,
+        finalized: bool,
+    }
+
+    impl Payload {
+        pub(crate) fn new(key: Option<[u8; 32]>) -> Self {
+            Self {
+                decrypter: key.map(|key| StreamDecrypter::new(&key)),
+                decrypt_buffer: Vec::new(),
+                finalized: false,
+            }
+        }
+
+        /// Whether the server sent the end of the payload
+        pub(crate) fn finalized(&self) -> bool {
+            self.finalized
+        }
+
+        /// Whether a read has to wait for another message
+        pub(crate) fn needs_message(&self) -> bool {
+            self.decrypt_buffer.is_empty() && !self.finalized
+        }
+
+        /// Handles a message of the download: a part of the payload, its end or an error
+        pub(crate) fn receive<M: Fields>(&mut self, message: &mut M) -> io::Result<()> {
+            let cont = message.read_i8()?;
+            if cont == -1 {
+                return Err(io::Error::from(server_error(message)));
+            }
+
+            if cont == 0 {
+                self.finalized = true;
+                if let Some(decrypter) = &mut self.decrypter {
+                    decrypter.finalize(&mut self.decrypt_buffer)?;
+                }
+                return Ok(());
+            }
+
+            let buffer = message.read_buffer()?;
+            match &mut self.decrypter {
+                None => self.decrypt_buffer.extend_from_slice(buffer),
+                Some(decrypter) => decrypter.update(buffer, &mut self.decrypt_buffer)?,
+            }
+            Ok(())
+        }
+
+        /// Takes a received range of the stored payload, so another one can be requested
+        pub(crate) fn take_range(&mut self) -> Vec<u8> {
+            self.finalized = false;
+            std::mem::take(&mut self.decrypt_buffer)
+        }
+
+        /// Continues decrypting at the chunk containing `offset` of the stream
+        /// starting with `header`
+        ///
+        /// Returns the position in the payload the download starts at
+        /// and the offset of the stream to request from there.
+        pub(crate) fn resume_at(
+            &mut self,
+            key: &[u8; 32],
+            header: &[u8],
+            offset: u64,
+        ) -> Result<(u64, u64), TransferError> {
+            let chunk = offset / CHUNK_SIZE as u64;
+            let counter = chunk.try_into().map_err(|_| CryptoError::TooLong)?;
+
+            // The header holds the nonce prefix needed for all following chunks
+            let mut decrypter = StreamDecrypter::starting_at(key, counter);
+            decrypter.update(header, &mut Vec::new())?;
+
+            self.decrypter = Some(decrypter);
+            Ok((
+                chunk * CHUNK_SIZE as u64,
+                HEADER_SIZE as u64 + chunk * ENCRYPTED_CHUNK_SIZE as u64,
+            ))
+        }
+
+        /// Size of the recipient block starting with `header`
+        pub(crate) fn recipient_block_size(header: &[u8]) -> Result<usize, TransferError> {
+            let header = header
+                .try_into()
+                .map_err(|_| TransferError::CorruptedMessage)?;
+            Ok(RECIPIENTS_HEADER_SIZE + recipient_count(header)? * RECIPIENT_SIZE)
+        }
+
+        /// Decrypts the rest of the payload with the content key `block` wraps for `identity`
+        pub(crate) fn unlock(
+            &mut self,
+            block: &[u8],
+            identity: &PKey<Private>,
+        ) -> Result<(), TransferError> {
+            let key = unwrap_key_with_identity(block, identity)?;
+
+            // Data received past the block is still encrypted
+            let mut decrypter = StreamDecrypter::new(&key);
+            let received = std::mem::take(&mut self.decrypt_buffer);
+            decrypter.update(&received, &mut self.decrypt_buffer)?;
+            if self.finalized {
+                decrypter.finalize(&mut self.decrypt_buffer)?;
+            }
+            self.decrypter = Some(decrypter);
+            Ok(())
+        }
+
+        /// Moves received plaintext into `buf`, returns the number of bytes moved
+        pub(crate) fn read(&mut self, buf: &mut [u8]) -> usize {
+            let bytes = self.decrypt_buffer.len().min(buf.len());
+            buf[..bytes].copy_from_slice(&self.decrypt_buffer[..bytes]);
+            self.decrypt_buffer.drain(..bytes);
+            bytes
+        }
+    }
+
     pub struct Download {
         conn: TcpStream,
-        crypter: Option<Crypter>,
-        key: Option<[u8; 32]>,
-        decrypt_buffer: Vec<u8>,
+        payload: Payload,
         downloaded: usize,
         time: Instant,
-        finalized: bool,
-        quiet: bool,
+        progress: Option<ProgressCallback>,
+        position: u64,
     }
 
     impl Download {
@@ -360,183 +750,163 @@ pub mod transfer {
             addr: A,
             id: &[u8; 32],
             key: Option<[u8; 32]>,
-            quiet: bool,
             fingerprint: Option<Vec<u8>>,
         ) -> Result<Self, TransferError> {
-            let mut conn = TcpStream::connect(addr)?;
-            let mut message = Message::new();
-            message.write_i32(1);
-            message.write_buffer(id);
-            conn.wait_until_ready()?;
-            verify_fingerprint(&conn, fingerprint)?;
-            conn.write_blocking(&message)?;
+            let (mut conn, _) = connect(addr, fingerprint)?;
+            conn.write_blocking(&download_request(id))?;
             Ok(Self {
                 conn,
-                crypter: None,
-                key,
-                decrypt_buffer: Vec::new(),
-                finalized: false,
+                payload: Payload::new(key),
                 time: Instant::now(),
                 downloaded: 0,
-                quiet,
+                progress: None,
+                position: 0,
             })
         }
 
-        fn print_stats(&mut self, n: usize) {
-            self.downloaded += n;
-            let time = self.time.elapsed().as_micros() as f64;
-            let size = self.downloaded;
-            let speed = size as f64 / time * 1000000.0;
+        /// Starts a download that skips the first `offset` bytes of the payload
+        ///
+        /// Encrypted payload can only be resumed at a chunk boundary, so the download
+        /// may start earlier, check [position](#method.position) for the actual start.
+        pub fn resume<A: ToSocketAddrs>(
+            addr: A,
+            id: &[u8; 32],
+            key: Option<[u8; 32]>,
+            offset: u64,
+            fingerprint: Option<Vec<u8>>,
+        ) -> Result<Self, TransferError> {
+            let mut download = Self::ranged(addr, offset, fingerprint)?;
+            download.start_at(id, key, 0, offset)?;
+            Ok(download)
+        }
 
-            printinfoln!(
-                self.quiet,
-                "Downloaded {:^12} @ {:^12}\x1b[1A\x1b[0G",
-                size.format_size(),
-                format!("{}/s", speed.format_size())
-            );
+        /// Same as [resume](#method.resume) for a download sent to recipients,
+        /// the content key is unwrapped from the recipient block with `identity`
+        pub fn resume_with_identity<A: ToSocketAddrs>(
+            addr: A,
+            id: &[u8; 32],
+            identity: &PKey<Private>,
+            offset: u64,
+            fingerprint: Option<Vec<u8>>,
+        ) -> Result<Self, TransferError> {
+            let mut download = Self::ranged(addr, offset, fingerprint)?;
+            let mut block = download.read_range(id, 0, RECIPIENTS_HEADER_SIZE as u64)?;
+            let size = Payload::recipient_block_size(&block)?;
+            block.extend(download.read_range(
+                id,
+                RECIPIENTS_HEADER_SIZE as u64,
+                (size - RECIPIENTS_HEADER_SIZE) as u64,
+            )?);
+            let key = unwrap_key_with_identity(&block, identity)?;
+            download.start_at(id, Some(key), size as u64, offset)?;
+            Ok(download)
         }
-    }
 
-    impl Read for Download {
-        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-            if self.decrypt_buffer.len() != 0 {
-                let mut bytes = self.decrypt_buffer.len();
-                if self.decrypt_buffer.len() > buf.len() {
-                    bytes = buf.len();
-                }
-                buf[..bytes].copy_from_slice(&self.decrypt_buffer[..bytes]);
-                self.decrypt_buffer.drain(..bytes);
-                self.print_stats(bytes);
-                return Ok(bytes);
-            }
+        /// Connects for a download starting at `offset` of the payload
+        fn ranged<A: ToSocketAddrs>(
+            addr: A,
+            offset: u64,
+            fingerprint: Option<Vec<u8>>,
+        ) -> Result<Self, TransferError> {
+            let (conn, features) = connect(addr, fingerprint)?;
+            check_ranged_download(features)?;
+            Ok(Self {
+                conn,
+                payload: Payload::new(None),
+                time: Instant::now(),
+                downloaded: 0,
+                progress: None,
+                position: offset,
+            })
+        }
 
-            if self.finalized {
-                self.print_stats(0);
-                return Ok(0);
+        /// Requests the rest of the payload from `offset` of the stream, which starts
+        /// at `base` of the stored payload
+        fn start_at(
+            &mut self,
+            id: &[u8; 32],
+            key: Option<[u8; 32]>,
+            base: u64,
+            offset: u64,
+        ) -> Result<(), TransferError> {
+            let mut start = offset;
+            if let Some(key) = key {
+                let header = self.read_range(id, base, HEADER_SIZE as u64)?;
+                let (position, range_start) = self.payload.resume_at(&key, &header, offset)?;
+                self.position = position;
+                start = range_start;
             }
 
-            let mut buffer;
-            let mut message;
-            match self.conn.read_blocking() {
-                Ok(msg) => {
-                    message = msg;
-                }
-                Err(_) => {
-                    return Err(io::Error::new(ErrorKind::ConnectionReset, "NetworkError"));
-                }
+            self.conn
+                .write_blocking(&range_request(id, base + start, 0))?;
+            Ok(())
+        }
+
+        /// Downloads `length` bytes of the stored payload from `offset`
+        fn read_range(
+            &mut self,
+            id: &[u8; 32],
+            offset: u64,
+            length: u64,
+        ) -> Result<Vec<u8>, TransferError> {
+            self.conn
+                .write_blocking(&range_request(id, offset, length))?;
+            while !self.payload.finalized() {
+                self.receive()?;
             }
-            let cont;
-            match message.read_i8() {
-                Ok(v) => {
-                    cont = v;
-                }
-                Err(_) => {
-                    return Err(io::Error::new(ErrorKind::ConnectionReset, "NetworkError"));
-                }
+            Ok(self.payload.take_range())
+        }
+
+        /// Reads the recipient block and decrypts the rest of the download
+        /// with the content key wrapped for `identity`
+        pub fn unlock(&mut self, identity: &PKey<Private>) -> Result<(), TransferError> {
+            let mut header = [0; RECIPIENTS_HEADER_SIZE];
+            self.read_exact(&mut header)?;
+            let mut block = header.to_vec();
+            block.resize(Payload::recipient_block_size(&header)?, 0);
+            self.read_exact(&mut block[RECIPIENTS_HEADER_SIZE..])?;
+            self.payload.unlock(&block, identity)
+        }
+
+        /// Position in the payload the download started at
+        pub fn position(&self) -> u64 {
+            self.position
+        }
+
+        /// Reports the progress of the download to `progress`
+        pub fn set_progress(&mut self, progress: ProgressCallback) {
+            self.progress = Some(progress);
+        }
+
+        fn report_progress(&mut self, n: usize) {
+            self.downloaded += n;
+            if let Some(progress) = &mut self.progress {
+                progress(Progress::Transferred {
+                    bytes: self.downloaded as u64,
+                    elapsed: self.time.elapsed(),
+                });
             }
-            return if cont == -1 {
-                match message.read_buffer() {
-                    Ok(description) => {
-                        println!("Received an error message:");
-                        println!("\n{}\n", String::from_utf8(description.to_vec()).unwrap());
-                    }
-                    _ => {}
-                }
-                Err(io::Error::new(ErrorKind::ConnectionReset, "NetworkError"))
-            } else if cont == 0 {
-                match &mut self.crypter {
-                    None => {
-                        self.finalized = true;
-                        self.print_stats(0);
-                        Ok(0)
-                    }
-                    Some(crypter) => {
-                        self.finalized = true;
-                        if buf.len() < 16 {
-                            self.decrypt_buffer.resize(16, 0);
-                            crypter.finalize(&mut self.decrypt_buffer)?;
-                            buf.copy_from_slice(&self.decrypt_buffer[..buf.len()]);
-                            self.decrypt_buffer.drain(..buf.len());
-                            self.print_stats(buf.len());
-                            Ok(buf.len())
-                        } else {
-                            let bytes_decrypted = crypter.finalize(buf)?;
-                            self.print_stats(bytes_decrypted);
-                            Ok(bytes_decrypted)
-                        }
-                    }
-                }
-            } else {
-                match message.read_buffer() {
-                    Ok(b) => {
-                        buffer = b;
-                    }
-                    Err(_) => {
-                        return Err(io::Error::new(ErrorKind::ConnectionReset, "NetworkError"));
-                    }
-                }
-                if self.key.is_some() && self.crypter.is_none() {
-                    if buffer.len() < 16 {
-                        panic!("IV split");
-                    }
+        }
 
-                    self.crypter = Some(Crypter::new(
-                        Cipher::aes_256_cbc(),
-                        Mode::Decrypt,
-                        &self.key.unwrap(),
-                        Some(&buffer[..16]),
-                    )?);
+        fn receive(&mut self) -> io::Result<()> {
+            let mut message = self
+                .conn
+                .read_blocking()
+                .map_err(|err| io::Error::from(TransferError::NetworkError(err)))?;
+            self.payload.receive(&mut message)
+        }
+    }
 
-                    buffer = &buffer[16..];
-                }
+    impl Read for Download {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            // Only plaintext of authenticated chunks ever gets into the payload
+            while self.payload.needs_message() {
+                self.receive()?;
+            }
 
-                match &mut self.crypter {
-                    None => {
-                        return if buffer.len() <= buf.len() {
-                            buf[..buffer.len()].copy_from_slice(&buffer);
-                            self.print_stats(buffer.len());
-                            Ok(buffer.len())
-                        } else {
-                            buf.copy_from_slice(&buffer[..buf.len()]);
-                            self.decrypt_buffer.resize(buffer.len() - buf.len(), 0);
-                            self.decrypt_buffer.copy_from_slice(&buffer[buf.len()..]);
-                            self.print_stats(buf.len());
-                            Ok(buf.len())
-                        }
-                    }
-                    Some(crypter) => {
-                        if buf.len() < buffer.len() + 16 {
-                            self.decrypt_buffer.resize(buffer.len() + 16, 0);
-                            let bytes_decrypted =
-                                crypter.update(&buffer, &mut self.decrypt_buffer)?;
-                            self.decrypt_buffer.truncate(bytes_decrypted);
-
-                            if bytes_decrypted == 0 {
-                                return self.read(buf);
-                            }
-
-                            if bytes_decrypted > buf.len() {
-                                buf.copy_from_slice(&self.decrypt_buffer[..buf.len()]);
-                                self.decrypt_buffer.drain(..buf.len());
-                                self.print_stats(buf.len());
-                                Ok(buf.len())
-                            } else {
-                                buf[..bytes_decrypted].copy_from_slice(&self.decrypt_buffer);
-                                self.decrypt_buffer.clear();
-                                self.print_stats(bytes_decrypted);
-                                Ok(bytes_decrypted)
-                            }
-                        } else {
-                            let bytes_decrypted = crypter.update(&buffer, buf)?;
-                            if bytes_decrypted == 0 {
-                                return self.read(buf);
-                            }
-                            self.print_stats(bytes_decrypted);
-                            Ok(bytes_decrypted)
-                        }
-                    }
-                }
-            };
+            let bytes = self.payload.read(buf);
+            self.report_progress(bytes);
+            Ok(bytes)
         }
     }
 }
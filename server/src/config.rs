@@ -1,122 +1,246 @@
 pub mod config {
-    use std::fs::File;
-    use std::io::Read;
-    use std::path::Path;
-    use std::process::exit;
+    use std::fmt::{Display, Formatter};
+    use std::path::{Path, PathBuf};
     use std::str::FromStr;
+    use std::sync::{Arc, RwLock};
+    use std::{env, fmt, fs, io};
+    use toml::Value;
+
+    /// Keys of the config, each can be overridden by an environment variable
+    /// named SFSHR_ followed by the key
+    const KEYS: [&str; 10] = [
+        "EXPIRATION_TIME",
+        "MAX_EXPIRATION_TIME",
+        "THREAD_COUNT",
+        "UPLOADS",
+        "MAX_SIZE",
+        "MAX_TOTAL_SIZE",
+        "KEY_FILE",
+        "RESUME_GRACE_TIME",
+        "SHUTDOWN_TIMEOUT",
+        "LISTEN",
+    ];
+
+    pub enum ConfigError {
+        IOError(PathBuf, io::Error),
+        /// Line of the legacy format is not a KEY=VALUE pair
+        Syntax(usize),
+        Toml(toml::de::Error),
+        InvalidValue {
+            key: String,
+            value: String,
+            expected: &'static str,
+        },
+        Invalid(&'static str),
+    }
+
+    impl Display for ConfigError {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            match self {
+                ConfigError::IOError(path, err) => {
+                    write!(f, "failed to read config file {:?} ({})", path, err)
+                }
+                ConfigError::Syntax(line) => write!(f, "not a key-pair value at line {}", line),
+                ConfigError::Toml(err) => write!(f, "invalid TOML ({})", err),
+                ConfigError::InvalidValue {
+                    key,
+                    value,
+                    expected,
+                } => write!(
+                    f,
+                    "failed to parse \"{}\" as {} for {}",
+                    value, expected, key
+                ),
+                ConfigError::Invalid(reason) => f.write_str(reason),
+            }
+        }
+    }
 
     pub struct Config {
         expiration: u64,
+        max_expiration: u64,
         thread_count: u64,
         uploads: String,
         max_size: u64,
         max_total_size: u64,
         key_file: String,
+        resume_grace_time: u64,
+        shutdown_timeout: u64,
+        listen: Vec<String>,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Self {
+                expiration: 10800,
+                max_expiration: 604800,
+                thread_count: 8,
+                uploads: String::from("uploads"),
+                max_size: 1048576,
+                max_total_size: 268435456,
+                key_file: String::from("key"),
+                resume_grace_time: 600,
+                shutdown_timeout: 60,
+                listen: Vec::new(),
+            }
+        }
     }
 
     impl Config {
-        pub fn new<P: AsRef<Path>>(config_file: P) -> Self {
-            let file = File::open(config_file);
-            if file.is_err() {
-                println!("Failed to open config file.");
-            }
-
-            let mut expiration = 10800;
-            let mut thread_count = 8;
-            let mut max_size = 1048576;
-            let mut max_total_size = 268435456;
-            let mut uploads = String::from("uploads");
-            let mut key_file = String::from("key");
-            let mut file = file.unwrap();
-            let mut str = String::new();
-            file.read_to_string(&mut str).unwrap();
-            let mut line = 0;
-            for pair in str.split('\n') {
-                line += 1;
-                let mut pair = pair.to_owned();
-                pair.retain(|x| !x.is_whitespace());
-                if pair.starts_with('#') || pair.len() == 0 {
-                    continue;
-                }
-                let mut split = pair.splitn(2, '=').into_iter();
-
-                let key = split.next().unwrap();
-                let value = split.next();
-                if value.is_none() {
-                    println!(
-                        "Config parsing failed: not a key-pair value at line {}",
-                        line
-                    );
-                    exit(1);
+        /// Loads the config file, files ending with .toml are parsed as TOML,
+        /// others as KEY=VALUE lines. Without a file only the defaults
+        /// and environment variables are used.
+        pub fn load<P: AsRef<Path>>(config_file: Option<P>) -> Result<Self, ConfigError> {
+            let mut config = Self::default();
+            if let Some(config_file) = config_file {
+                let path = config_file.as_ref();
+                let str = fs::read_to_string(path)
+                    .map_err(|err| ConfigError::IOError(path.to_path_buf(), err))?;
+                if path.extension().is_some_and(|ext| ext == "toml") {
+                    config.parse_toml(&str)?;
+                } else {
+                    config.parse_legacy(&str)?;
                 }
-                let value = value.unwrap();
-
-                if key == "EXPIRATION_TIME" {
-                    let parse = u64::from_str(value);
-                    if parse.is_err() {
-                        println!(
-                            "Config parsing failed: failed to parse \"{}\" as u64 at line {}",
-                            value, line
-                        );
-                        exit(1);
-                    }
+            }
 
-                    expiration = parse.unwrap();
-                } else if key == "THREAD_COUNT" {
-                    let parse = u64::from_str(value);
-                    if parse.is_err() {
-                        println!(
-                            "Config parsing failed: failed to parse \"{}\" as u64 at line {}",
-                            value, line
-                        );
-                        exit(1);
+            for key in KEYS.iter() {
+                if let Ok(value) = env::var(format!("SFSHR_{}", key)) {
+                    // Listen addresses are comma-separated and replace those of the file
+                    if *key == "LISTEN" {
+                        config.listen.clear();
+                        for addr in value.split(',') {
+                            config.set(key, addr.trim())?;
+                        }
+                    } else {
+                        config.set(key, value.trim())?;
                     }
+                }
+            }
 
-                    thread_count = parse.unwrap();
-                } else if key == "MAX_SIZE" {
-                    let parse = u64::from_str(value);
-                    if parse.is_err() {
-                        println!(
-                            "Config parsing failed: failed to parse \"{}\" as u64 at line {}",
-                            value, line
-                        );
-                        exit(1);
-                    }
+            if config.listen.is_empty() {
+                config.listen.push(String::from("0.0.0.0:40788"));
+            }
+            // Configs written before MAX_EXPIRATION_TIME existed may have a longer EXPIRATION_TIME
+            if config.expiration > config.max_expiration {
+                println!(
+                    "Warning! EXPIRATION_TIME is longer than MAX_EXPIRATION_TIME, using {} seconds.",
+                    config.max_expiration
+                );
+                config.expiration = config.max_expiration;
+            }
+            config.validate()?;
+            Ok(config)
+        }
 
-                    max_size = parse.unwrap();
-                } else if key == "MAX_TOTAL_SIZE" {
-                    let parse = u64::from_str(value);
-                    if parse.is_err() {
-                        println!(
-                            "Config parsing failed: failed to parse \"{}\" as u64 at line {}",
-                            value, line
-                        );
-                        exit(1);
-                    }
+        fn parse_legacy(&mut self, str: &str) -> Result<(), ConfigError> {
+            for (index, pair) in str.lines().enumerate() {
+                let pair = pair.trim();
+                if pair.starts_with('#') || pair.is_empty() {
+                    continue;
+                }
+                let mut split = pair.splitn(2, '=');
+                let key = split.next().unwrap().trim();
+                let value = match split.next() {
+                    Some(value) => value.trim(),
+                    None => return Err(ConfigError::Syntax(index + 1)),
+                };
+                if !self.set(key, value)? {
+                    println!("Warning! Found unknown key {} in config file.", key);
+                }
+            }
+            Ok(())
+        }
 
-                    max_total_size = parse.unwrap();
-                } else if key == "UPLOADS" {
-                    uploads = String::from(value);
-                } else if key == "KEY_FILE" {
-                    key_file = String::from(value);
-                } else {
+        fn parse_toml(&mut self, str: &str) -> Result<(), ConfigError> {
+            let table = match str.parse::<Value>().map_err(ConfigError::Toml)? {
+                Value::Table(table) => table,
+                _ => return Err(ConfigError::Invalid("TOML config is not a table")),
+            };
+            for (key, value) in &table {
+                let upper = key.to_uppercase();
+                let known = match value {
+                    Value::String(value) => self.set(&upper, value)?,
+                    Value::Integer(value) if *value >= 0 => self.set(&upper, &value.to_string())?,
+                    Value::Array(values) if upper == "LISTEN" => {
+                        for value in values {
+                            match value.as_str() {
+                                Some(addr) => self.set(&upper, addr)?,
+                                None => return Err(invalid_value(&upper, value, "address")),
+                            };
+                        }
+                        true
+                    }
+                    _ => return Err(invalid_value(&upper, value, "string or number")),
+                };
+                if !known {
                     println!("Warning! Found unknown key {} in config file.", key);
                 }
             }
+            Ok(())
+        }
 
-            Self {
-                expiration,
-                thread_count,
-                uploads,
-                max_size,
-                max_total_size,
-                key_file,
+        /// Sets the value of `key`, returns false if the key is unknown
+        fn set(&mut self, key: &str, value: &str) -> Result<bool, ConfigError> {
+            match key {
+                "EXPIRATION_TIME" => self.expiration = parse_duration(key, value)?,
+                "MAX_EXPIRATION_TIME" => self.max_expiration = parse_duration(key, value)?,
+                "THREAD_COUNT" => {
+                    self.thread_count =
+                        parse_number(key, value, "number", |value| u64::from_str(value).ok())?
+                }
+                "MAX_SIZE" => self.max_size = parse_size(key, value)?,
+                "MAX_TOTAL_SIZE" => self.max_total_size = parse_size(key, value)?,
+                "RESUME_GRACE_TIME" => self.resume_grace_time = parse_duration(key, value)?,
+                "SHUTDOWN_TIMEOUT" => self.shutdown_timeout = parse_duration(key, value)?,
+                "UPLOADS" => self.uploads = String::from(value),
+                "KEY_FILE" => self.key_file = String::from(value),
+                "LISTEN" => self.listen.push(String::from(value)),
+                _ => return Ok(false),
             }
+            Ok(true)
+        }
+
+        fn validate(&self) -> Result<(), ConfigError> {
+            if self.thread_count == 0 {
+                return Err(ConfigError::Invalid("THREAD_COUNT must be at least 1"));
+            }
+            if self.max_size == 0 || self.max_total_size == 0 {
+                return Err(ConfigError::Invalid(
+                    "MAX_SIZE and MAX_TOTAL_SIZE must be at least 1 byte",
+                ));
+            }
+            Ok(())
+        }
+
+        /// Takes the settings of `new` that can change while the server runs,
+        /// returns the config and the keys that only take effect after a restart
+        pub fn reloaded(&self, mut new: Config) -> (Config, Vec<&'static str>) {
+            let mut ignored = Vec::new();
+            if new.thread_count != self.thread_count {
+                ignored.push("THREAD_COUNT");
+                new.thread_count = self.thread_count;
+            }
+            if new.uploads != self.uploads {
+                ignored.push("UPLOADS");
+                new.uploads = self.uploads.clone();
+            }
+            if new.key_file != self.key_file {
+                ignored.push("KEY_FILE");
+                new.key_file = self.key_file.clone();
+            }
+            if new.listen != self.listen {
+                ignored.push("LISTEN");
+                new.listen = self.listen.clone();
+            }
+            (new, ignored)
         }
 
         pub fn expiration(&self) -> u64 {
             self.expiration
         }
+        pub fn max_expiration(&self) -> u64 {
+            self.max_expiration
+        }
         pub fn thread_count(&self) -> u64 {
             self.thread_count
         }
@@ -132,18 +256,121 @@ pub mod config {
         pub fn key_file(&self) -> &str {
             &self.key_file
         }
+        pub fn resume_grace_time(&self) -> u64 {
+            self.resume_grace_time
+        }
+        pub fn shutdown_timeout(&self) -> u64 {
+            self.shutdown_timeout
+        }
+        pub fn listen(&self) -> &[String] {
+            &self.listen
+        }
     }
 
     impl Clone for Config {
         fn clone(&self) -> Self {
             Self {
                 expiration: self.expiration,
+                max_expiration: self.max_expiration,
                 thread_count: self.thread_count,
                 uploads: self.uploads.clone(),
                 max_size: self.max_size,
                 max_total_size: self.max_total_size,
                 key_file: self.key_file.clone(),
+                resume_grace_time: self.resume_grace_time,
+                shutdown_timeout: self.shutdown_timeout,
+                listen: self.listen.clone(),
+            }
+        }
+    }
+
+    /// Config that can be replaced while the server runs,
+    /// transfers keep the snapshot they started with
+    #[derive(Clone)]
+    pub struct SharedConfig {
+        current: Arc<RwLock<Arc<Config>>>,
+    }
+
+    impl SharedConfig {
+        pub fn new(config: Config) -> Self {
+            Self {
+                current: Arc::new(RwLock::new(Arc::new(config))),
             }
         }
+
+        pub fn get(&self) -> Arc<Config> {
+            self.current.read().unwrap().clone()
+        }
+
+        pub fn replace(&self, config: Config) {
+            *self.current.write().unwrap() = Arc::new(config);
+        }
+    }
+
+    fn invalid_value(key: &str, value: &Value, expected: &'static str) -> ConfigError {
+        ConfigError::InvalidValue {
+            key: String::from(key),
+            value: value.to_string(),
+            expected,
+        }
+    }
+
+    /// Parses a number, spaces can be used to separate thousands
+    fn parse_number<F: Fn(&str) -> Option<u64>>(
+        key: &str,
+        value: &str,
+        expected: &'static str,
+        parse: F,
+    ) -> Result<u64, ConfigError> {
+        let mut compact = String::from(value);
+        compact.retain(|c| !c.is_whitespace());
+        parse(&compact).ok_or_else(|| ConfigError::InvalidValue {
+            key: String::from(key),
+            value: String::from(value),
+            expected,
+        })
+    }
+
+    /// Parses size such as 1048576, 500KB or 256MiB into bytes
+    fn parse_size(key: &str, value: &str) -> Result<u64, ConfigError> {
+        parse_number(key, value, "size", |value| {
+            let (value, unit) = split_unit(value);
+            let multiplier: u64 = match unit.to_lowercase().as_str() {
+                "" | "b" => 1,
+                "k" | "kb" => 1000,
+                "kib" => 1 << 10,
+                "m" | "mb" => 1000 * 1000,
+                "mib" => 1 << 20,
+                "g" | "gb" => 1000 * 1000 * 1000,
+                "gib" => 1 << 30,
+                "t" | "tb" => 1000 * 1000 * 1000 * 1000,
+                "tib" => 1 << 40,
+                _ => return None,
+            };
+            u64::from_str(value).ok()?.checked_mul(multiplier)
+        })
+    }
+
+    /// Parses duration such as 600, 30m, 3h or 7d into seconds
+    fn parse_duration(key: &str, value: &str) -> Result<u64, ConfigError> {
+        parse_number(key, value, "duration", |value| {
+            let (value, unit) = split_unit(value);
+            let multiplier = match unit.to_lowercase().as_str() {
+                "" | "s" => 1,
+                "m" => 60,
+                "h" => 60 * 60,
+                "d" => 24 * 60 * 60,
+                "w" => 7 * 24 * 60 * 60,
+                _ => return None,
+            };
+            u64::from_str(value).ok()?.checked_mul(multiplier)
+        })
+    }
+
+    fn split_unit(value: &str) -> (&str, &str) {
+        match value.find(|c: char| !c.is_ascii_digit()) {
+            Some(index) => value.split_at(index),
+            None => (value, ""),
+        }
     }
 }
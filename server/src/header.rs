@@ -0,0 +1,136 @@
+pub mod header {
+    use std::convert::TryInto;
+    use std::fs::File;
+    use std::io;
+    use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+
+    // Header of a stored upload, all integers are little-endian:
+    //   [u8; 4] magic | u8 version | u8 flags | [u8; 2] reserved
+    //   u64 created | u64 expires | u64 payload length | u64 downloads left
+    //   [u8; 32] owner token
+    // Files written before the header existed start with a bare u64 expiry instead.
+    const MAGIC: [u8; 4] = *b"SFSH";
+    const VERSION: u8 = 1;
+    const SIZE: u64 = 72;
+    const LEGACY_SIZE: u64 = 8;
+
+    const FLAG_ENCRYPTED: u8 = 1 << 0;
+    const FLAG_OWNER_TOKEN: u8 = 1 << 1;
+
+    pub struct Header {
+        /// Unix timestamp the file was uploaded at
+        pub created: u64,
+        /// Unix timestamp the file expires at
+        pub expires: u64,
+        /// Length of the payload, 0 until the upload is finished
+        pub payload_length: u64,
+        /// Number of downloads before the file is removed, 0 means unlimited
+        pub downloads_left: u64,
+        pub encrypted: bool,
+        pub owner_token: Option<[u8; 32]>,
+        legacy: bool,
+    }
+
+    impl Header {
+        pub fn new(created: u64, expires: u64) -> Self {
+            Self {
+                created,
+                expires,
+                payload_length: 0,
+                downloads_left: 0,
+                encrypted: false,
+                owner_token: None,
+                legacy: false,
+            }
+        }
+
+        /// Reads the header from the start of `file`, leaving the cursor at the payload
+        pub fn read(file: &mut File) -> io::Result<Self> {
+            file.seek(SeekFrom::Start(0))?;
+            let mut bytes = [0; SIZE as usize];
+            file.read_exact(&mut bytes[..LEGACY_SIZE as usize])?;
+
+            if bytes[..4] != MAGIC {
+                let length = file.metadata()?.len();
+                return Ok(Self {
+                    created: 0,
+                    expires: u64::from_le_bytes(bytes[..8].try_into().unwrap()),
+                    payload_length: length.saturating_sub(LEGACY_SIZE),
+                    downloads_left: 0,
+                    encrypted: false,
+                    owner_token: None,
+                    legacy: true,
+                });
+            }
+
+            if bytes[4] != VERSION {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unsupported header version {}", bytes[4]),
+                ));
+            }
+            file.read_exact(&mut bytes[LEGACY_SIZE as usize..])?;
+
+            let flags = bytes[5];
+            let read_u64 =
+                |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+            let owner_token = if flags & FLAG_OWNER_TOKEN != 0 {
+                Some(bytes[40..72].try_into().unwrap())
+            } else {
+                None
+            };
+
+            Ok(Self {
+                created: read_u64(8),
+                expires: read_u64(16),
+                payload_length: read_u64(24),
+                downloads_left: read_u64(32),
+                encrypted: flags & FLAG_ENCRYPTED != 0,
+                owner_token,
+                legacy: false,
+            })
+        }
+
+        /// Writes the header to the start of `file`, the cursor is kept where it was
+        pub fn write(&self, file: &mut File) -> io::Result<()> {
+            let position = file.stream_position()?;
+            file.seek(SeekFrom::Start(0))?;
+
+            if self.legacy {
+                file.write_all(&self.expires.to_le_bytes())?;
+            } else {
+                let mut flags = 0;
+                if self.encrypted {
+                    flags |= FLAG_ENCRYPTED;
+                }
+                if self.owner_token.is_some() {
+                    flags |= FLAG_OWNER_TOKEN;
+                }
+
+                let mut bytes = Vec::with_capacity(SIZE as usize);
+                bytes.extend_from_slice(&MAGIC);
+                bytes.push(VERSION);
+                bytes.push(flags);
+                bytes.extend_from_slice(&[0; 2]);
+                bytes.extend_from_slice(&self.created.to_le_bytes());
+                bytes.extend_from_slice(&self.expires.to_le_bytes());
+                bytes.extend_from_slice(&self.payload_length.to_le_bytes());
+                bytes.extend_from_slice(&self.downloads_left.to_le_bytes());
+                bytes.extend_from_slice(&self.owner_token.unwrap_or([0; 32]));
+                file.write_all(&bytes)?;
+            }
+
+            file.seek(SeekFrom::Start(position.max(self.size())))?;
+            Ok(())
+        }
+
+        /// Size of the header on disk, the payload starts right after it
+        pub fn size(&self) -> u64 {
+            if self.legacy {
+                LEGACY_SIZE
+            } else {
+                SIZE
+            }
+        }
+    }
+}
@@ -1,18 +1,28 @@
 mod config;
+mod header;
+mod protocol;
 mod thread_pool;
 
 extern crate simpletcp;
 
 use std::fs;
 
-use crate::config::config::Config;
-use crate::thread_pool::thread_pool::{FormatSize, ThreadPool};
+use crate::config::config::{Config, SharedConfig};
+use crate::header::header::Header;
+use crate::thread_pool::thread_pool::{
+    is_partial, reconcile_total_size, FormatSize, SuspendedUploads, ThreadPool,
+};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::flag;
+use signal_hook::iterator::Signals;
 use simpletcp::simpletcp::TcpServer;
+use simpletcp::utils::{get_fd_array, poll_set_timeout, EV_POLLIN};
 use std::env::args;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{BufRead, Read, Write};
 use std::path::Path;
 use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread::{sleep, spawn};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
@@ -20,7 +30,8 @@ use std::time::{Duration, SystemTime, UNIX_EPOCH};
 fn main() {
     let total_size = Arc::new(Mutex::new(0));
     let mut args = args().into_iter();
-    let mut config_file = String::from("config");
+    let mut config_file = None;
+    let mut listen = Vec::new();
     loop {
         let arg = args.next();
         if arg.is_none() {
@@ -34,18 +45,66 @@ fn main() {
                 println!("Expected value for option {}!", arg);
                 exit(1);
             }
-            config_file = value.unwrap();
+            config_file = value;
+        } else if arg == "--listen" || arg == "-l" {
+            let value = args.next();
+            if value.is_none() {
+                println!("Expected value for option {}!", arg);
+                exit(1);
+            }
+            listen.push(value.unwrap());
         }
     }
-    let cfg = Config::new(config_file);
+    // Without --config the default file is optional, so the server can be configured
+    // with environment variables alone
+    let config_file = config_file.or_else(|| {
+        Some(String::from("config")).filter(|config_file| Path::new(config_file).exists())
+    });
+    let cfg = match Config::load(config_file.as_ref()) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            println!("Config loading failed: {}", err);
+            exit(1);
+        }
+    };
+    if listen.is_empty() {
+        listen = cfg.listen().to_vec();
+    }
     fs::create_dir_all(cfg.uploads()).unwrap();
 
-    let cfg_clone = cfg.clone();
+    let shared_config = SharedConfig::new(cfg.clone());
+    let mut signals = Signals::new([SIGHUP]).unwrap();
+    let config_file_clone = config_file.clone();
+    let shared_config_clone = shared_config.clone();
+    spawn(move || {
+        for _ in signals.forever() {
+            reload(&config_file_clone, &shared_config_clone);
+        }
+    });
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        // A second signal terminates the server right away
+        flag::register_conditional_shutdown(signal, 1, shutdown.clone()).unwrap();
+        flag::register(signal, shutdown.clone()).unwrap();
+    }
+    let shared_config_clone = shared_config.clone();
+    let shutdown_clone = shutdown.clone();
+    spawn(move || {
+        control(&config_file, &shared_config_clone, &shutdown_clone);
+    });
+
+    let suspended_uploads = Arc::new(SuspendedUploads::new());
+    let shared_config_clone = shared_config.clone();
     let total_size_clone = total_size.clone();
+    let suspended_uploads_clone = suspended_uploads.clone();
     spawn(move || {
-        file_checker(cfg_clone, total_size_clone);
+        file_checker(
+            shared_config_clone,
+            total_size_clone,
+            suspended_uploads_clone,
+        );
     });
-    let mut pool = ThreadPool::new(&cfg, &total_size);
+    let mut pool = ThreadPool::new(&shared_config, &total_size, &suspended_uploads);
 
     let key;
     let key_file = Path::new(cfg.key_file());
@@ -57,45 +116,129 @@ fn main() {
     } else {
         key = None;
     }
-    let server = TcpServer::new_with_key("0.0.0.0:40788", key.as_deref()).unwrap();
+
+    let mut servers: Vec<TcpServer> = Vec::new();
+    for addr in &listen {
+        // All addresses share the first server's key, so the fingerprint stays the same
+        let key = key
+            .clone()
+            .or_else(|| servers.first().map(|server| server.key()));
+        match TcpServer::new_with_key(addr.as_str(), key.as_deref()) {
+            Ok(server) => {
+                servers.push(server);
+            }
+            Err(error) => {
+                println!("Failed to listen on {}: {:?}", addr, error);
+                exit(1);
+            }
+        }
+    }
     if key.is_none() {
         let mut key_file = File::create(key_file).unwrap();
-        key_file.write_all(&server.key()).unwrap();
+        key_file.write_all(&servers[0].key()).unwrap();
     }
     println!("ready");
-    loop {
-        match server.accept_blocking() {
-            Ok(socket) => {
+
+    let mut fds = get_fd_array(&servers);
+    while !shutdown.load(Ordering::Relaxed) {
+        // Wakes up regularly to notice a shutdown request
+        poll_set_timeout(&mut fds, EV_POLLIN, 500);
+        // Check every listener, so a busy one cannot starve the others
+        for server in &servers {
+            if let Ok(Some(socket)) = server.accept() {
                 pool.accept(socket);
             }
-            Err(_) => {}
+        }
+    }
+
+    drop(servers);
+    let cfg = shared_config.get();
+    println!(
+        "Shutting down, waiting up to {} s for running transfers to finish",
+        cfg.shutdown_timeout()
+    );
+    pool.shutdown(Duration::from_secs(cfg.shutdown_timeout()));
+    suspended_uploads.discard(&cfg, &total_size);
+    reconcile_total_size(&cfg, &total_size);
+    println!(
+        "Terminated, space used {} of {}",
+        (*total_size.lock().unwrap()).format_size(),
+        cfg.max_total_size().format_size()
+    );
+}
+
+/// Reads commands from stdin, "reload" does the same as SIGHUP and "shutdown" as SIGTERM
+fn control(config_file: &Option<String>, config: &SharedConfig, shutdown: &AtomicBool) {
+    for line in std::io::stdin().lock().lines() {
+        match line {
+            Ok(line) if line.trim() == "reload" => reload(config_file, config),
+            Ok(line) if line.trim() == "shutdown" => shutdown.store(true, Ordering::Relaxed),
+            Ok(line) if line.trim().is_empty() => {}
+            Ok(line) => println!("Unknown command {}", line.trim()),
+            Err(_) => break,
         }
     }
 }
 
-fn file_checker(config: Config, total_size: Arc<Mutex<u64>>) {
+/// Loads the config again, the new limits apply to transfers started afterwards
+fn reload(config_file: &Option<String>, config: &SharedConfig) {
+    match Config::load(config_file.as_ref()) {
+        Ok(new) => {
+            let (new, ignored) = config.get().reloaded(new);
+            for key in ignored {
+                println!("Warning! {} cannot be changed without a restart.", key);
+            }
+            config.replace(new);
+            println!("Config reloaded");
+        }
+        Err(err) => {
+            println!("Config reload failed, keeping the current config: {}", err);
+        }
+    }
+}
+
+fn file_checker(
+    shared_config: SharedConfig,
+    total_size: Arc<Mutex<u64>>,
+    suspended_uploads: Arc<SuspendedUploads>,
+) {
     let mut prev_total_size = 0;
     loop {
+        let config = shared_config.get();
+        suspended_uploads.purge(&config, &total_size);
+
         let dir = fs::read_dir(config.uploads()).unwrap();
         let timestamp = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_secs();
         for entry in dir {
-            let entry = entry.unwrap();
-            if entry.file_type().unwrap().is_file() {
-                let mut file = File::open(entry.path()).unwrap();
-                let mut bytes = [0; 8];
-                file.read_exact(&mut bytes).unwrap();
-
-                let expiration = u64::from_le_bytes(bytes);
-
-                if expiration < timestamp {
-                    let mut total_size = total_size.lock().unwrap();
-                    *total_size -= file.metadata().unwrap().len();
-                    fs::remove_file(entry.path()).unwrap();
-                    println!("{:?} expired", entry.path());
-                }
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            // Uploads still being written or waiting to be resumed are not expired here
+            if is_partial(&entry.path()) {
+                continue;
+            }
+            // The file may have been deleted by its uploader or its last download in the meantime,
+            // skip it instead of stopping the checker
+            if !entry.file_type().is_ok_and(|file_type| file_type.is_file()) {
+                continue;
+            }
+            let mut file = match File::open(entry.path()) {
+                Ok(file) => file,
+                Err(_) => continue,
+            };
+            let header = match Header::read(&mut file) {
+                Ok(header) => header,
+                Err(_) => continue,
+            };
+
+            if header.expires < timestamp && fs::remove_file(entry.path()).is_ok() {
+                let mut total_size = total_size.lock().unwrap();
+                *total_size -= file.metadata().unwrap().len();
+                println!("{:?} expired", entry.path());
             }
         }
 
@@ -0,0 +1,45 @@
+pub mod protocol {
+    // Every connection starts with a hello exchange:
+    //   client -> i32 COMMAND_HELLO | u32 protocol version | u64 client features
+    //   server -> i8 1 | u32 protocol version | u64 server features
+    //          or i8 -1 | error description | u32 error code (connection is closed afterwards)
+    // Fields appended to existing messages are ignored by older peers,
+    // so optional extensions are announced by feature bits instead of a version bump.
+    pub const PROTOCOL_VERSION: u32 = 1;
+    pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+    pub const FEATURE_RESUMABLE_UPLOAD: u64 = 1 << 0;
+    pub const FEATURE_RANGED_DOWNLOAD: u64 = 1 << 1;
+    pub const FEATURE_UPLOAD_EXPIRATION: u64 = 1 << 2;
+    pub const FEATURE_DOWNLOAD_LIMIT: u64 = 1 << 3;
+    pub const FEATURE_DELETE: u64 = 1 << 4;
+    pub const FEATURE_INFO: u64 = 1 << 5;
+    pub const FEATURES: u64 = FEATURE_RESUMABLE_UPLOAD
+        | FEATURE_RANGED_DOWNLOAD
+        | FEATURE_UPLOAD_EXPIRATION
+        | FEATURE_DOWNLOAD_LIMIT
+        | FEATURE_DELETE
+        | FEATURE_INFO;
+
+    pub const COMMAND_UPLOAD: i32 = 0;
+    pub const COMMAND_DOWNLOAD: i32 = 1;
+    pub const COMMAND_HELLO: i32 = 2;
+    pub const COMMAND_RESUME_UPLOAD: i32 = 3;
+    pub const COMMAND_DOWNLOAD_RANGE: i32 = 4;
+    pub const COMMAND_DELETE: i32 = 5;
+    pub const COMMAND_INFO: i32 = 6;
+
+    // Every failed request is answered with i8 -1 | error description | u32 error code.
+    // Clients act on the code, the description is only shown to the user.
+    pub const ERROR_INVALID_MESSAGE: u32 = 1;
+    pub const ERROR_IO: u32 = 2;
+    pub const ERROR_NETWORK: u32 = 3;
+    pub const ERROR_SIZE_LIMIT_EXCEEDED: u32 = 4;
+    pub const ERROR_HANDSHAKE_REQUIRED: u32 = 5;
+    pub const ERROR_INCOMPATIBLE_PROTOCOL: u32 = 6;
+    pub const ERROR_UNKNOWN_RESUME_TOKEN: u32 = 7;
+    pub const ERROR_DOWNLOAD_LIMIT_REACHED: u32 = 8;
+    pub const ERROR_INVALID_DELETE_TOKEN: u32 = 9;
+    pub const ERROR_UPLOAD_NOT_FINISHED: u32 = 10;
+    pub const ERROR_NOT_FOUND: u32 = 11;
+}
@@ -7,63 +7,78 @@ pub mod thread_pool {
     use self::rand::{RngCore, SeedableRng};
     use crate::thread_pool::thread_pool::ThreadMessage::Accept;
     use simpletcp::simpletcp::{Error, Message, MessageError, TcpStream};
-    use std::fs::{remove_file, File};
+    use std::collections::{HashMap, HashSet};
+    use std::convert::TryInto;
+    use std::fs::{remove_file, File, OpenOptions};
     use std::io::{Read, Seek, SeekFrom, Write};
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
     use std::string::FromUtf8Error;
     use std::sync::atomic::AtomicUsize;
     use std::sync::atomic::Ordering::{Acquire, Release};
     use std::sync::mpsc::{channel, Receiver, Sender};
     use std::sync::{Arc, Mutex};
-    use std::thread::{spawn, JoinHandle};
+    use std::thread::{sleep, spawn, JoinHandle};
     use std::{fmt, fs, io};
 
     #[cfg(unix)]
     use std::os::unix::io::AsRawFd;
 
-    use crate::config::config::Config;
+    use crate::config::config::{Config, SharedConfig};
+    use crate::header::header::Header;
+    use crate::protocol::protocol::{
+        COMMAND_DELETE, COMMAND_DOWNLOAD, COMMAND_DOWNLOAD_RANGE, COMMAND_HELLO, COMMAND_INFO,
+        COMMAND_RESUME_UPLOAD, COMMAND_UPLOAD, ERROR_DOWNLOAD_LIMIT_REACHED,
+        ERROR_HANDSHAKE_REQUIRED, ERROR_INCOMPATIBLE_PROTOCOL, ERROR_INVALID_DELETE_TOKEN,
+        ERROR_INVALID_MESSAGE, ERROR_IO, ERROR_NETWORK, ERROR_NOT_FOUND, ERROR_SIZE_LIMIT_EXCEEDED,
+        ERROR_UNKNOWN_RESUME_TOKEN, ERROR_UPLOAD_NOT_FINISHED, FEATURES, MIN_PROTOCOL_VERSION,
+        PROTOCOL_VERSION,
+    };
     use simpletcp::utils::{EV_POLLIN, EV_POLLOUT};
     use std::fmt::{Display, Formatter};
 
     #[cfg(windows)]
     use std::os::unix::io::AsRawSocket;
-    use std::time::{SystemTime, UNIX_EPOCH};
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
     pub struct ThreadPool<'a> {
         threads: Vec<Thread>,
-        _config: &'a Config,
+        _config: &'a SharedConfig,
         total_size: Arc<Mutex<u64>>,
     }
 
     impl<'a> ThreadPool<'a> {
-        pub fn new(config: &'a Config, total_size: &Arc<Mutex<u64>>) -> ThreadPool<'a> {
-            {
-                let mut total_size = total_size.lock().unwrap();
-                let dir = fs::read_dir(config.uploads()).unwrap();
-                for entry in dir {
-                    let entry = entry.unwrap();
-                    if entry.file_type().unwrap().is_file() {
-                        *total_size += entry.metadata().unwrap().len();
-                    }
-                }
-            }
+        pub fn new(
+            shared_config: &'a SharedConfig,
+            total_size: &Arc<Mutex<u64>>,
+            suspended_uploads: &Arc<SuspendedUploads>,
+        ) -> ThreadPool<'a> {
+            let config = shared_config.get();
+            reconcile_total_size(&config, total_size);
+            let download_limits = Arc::new(DownloadLimits::new());
+            let uploads_in_progress = Arc::new(UploadsInProgress::new());
             let mut res = ThreadPool {
                 threads: Vec::new(),
-                _config: config,
+                _config: shared_config,
                 total_size: total_size.clone(),
             };
             for i in 0..config.thread_count() {
                 let (tx, rx): (Sender<ThreadMessage>, Receiver<ThreadMessage>) = channel();
                 let sockets_alive = Arc::new(AtomicUsize::new(0));
                 let sockets_alive_clone = sockets_alive.clone();
-                let config_clone = config.clone();
+                let config_clone = shared_config.clone();
                 let total_size_clone = res.total_size.clone();
+                let suspended_uploads_clone = suspended_uploads.clone();
+                let download_limits_clone = download_limits.clone();
+                let uploads_in_progress_clone = uploads_in_progress.clone();
                 let join_handle = spawn(move || {
                     thread_loop(
                         i,
                         ThreadParams {
                             sockets_alive: sockets_alive_clone,
                             total_size: total_size_clone,
+                            suspended_uploads: suspended_uploads_clone,
+                            download_limits: download_limits_clone,
+                            uploads_in_progress: uploads_in_progress_clone,
                             config: config_clone,
                             receiver: rx,
                         },
@@ -79,6 +94,37 @@ pub mod thread_pool {
             res
         }
 
+        /// Stops the threads once their transfers finish, transfers still running
+        /// after `timeout` are interrupted and their partial files removed
+        pub fn shutdown(&mut self, timeout: Duration) {
+            for thread in &self.threads {
+                let _ = thread.sender.send(ThreadMessage::Drain);
+            }
+            let deadline = Instant::now() + timeout;
+            while Instant::now() < deadline
+                && self
+                    .threads
+                    .iter()
+                    .any(|thread| !thread.join_handle.is_finished())
+            {
+                sleep(Duration::from_millis(100));
+            }
+
+            let running: usize = self
+                .threads
+                .iter()
+                .filter(|thread| !thread.join_handle.is_finished())
+                .map(|thread| thread.sockets_alive.load(Acquire))
+                .sum();
+            if running > 0 {
+                println!("Interrupting {} transfer(s) still running", running);
+            }
+            while let Some(thread) = self.threads.pop() {
+                let _ = thread.sender.send(ThreadMessage::Terminate);
+                thread.join_handle.join().unwrap();
+            }
+        }
+
         pub fn accept(&mut self, socket: TcpStream) {
             let mut min = usize::max_value();
             let mut selected = &self.threads[0];
@@ -97,6 +143,9 @@ pub mod thread_pool {
 
     impl Drop for ThreadPool<'_> {
         fn drop(&mut self) {
+            if self.threads.is_empty() {
+                return;
+            }
             println!("Terminating threads...");
             while !self.threads.is_empty() {
                 let thread = self.threads.pop().unwrap();
@@ -106,6 +155,267 @@ pub mod thread_pool {
         }
     }
 
+    struct SuspendedUpload {
+        id: [u8; 32],
+        token: [u8; 32],
+        reserved: u64,
+        deadline: u64,
+    }
+
+    /// Uploads interrupted by a connection loss, waiting to be resumed
+    pub struct SuspendedUploads {
+        uploads: Mutex<HashMap<[u8; 32], SuspendedUpload>>,
+    }
+
+    impl SuspendedUploads {
+        pub fn new() -> Self {
+            Self {
+                uploads: Mutex::new(HashMap::new()),
+            }
+        }
+
+        fn suspend(&self, upload: &Upload, reserved: u64, config: &Config) {
+            let deadline = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+                + config.resume_grace_time();
+            self.uploads.lock().unwrap().insert(
+                upload.token,
+                SuspendedUpload {
+                    id: upload.id,
+                    token: upload.token,
+                    reserved,
+                    deadline,
+                },
+            );
+        }
+
+        fn resume(&self, token: &[u8]) -> Option<SuspendedUpload> {
+            let mut uploads = self.uploads.lock().unwrap();
+            let token: [u8; 32] = token.try_into().ok()?;
+            uploads.remove(&token)
+        }
+
+        fn contains(&self, id: &[u8]) -> bool {
+            let uploads = self.uploads.lock().unwrap();
+            uploads.values().any(|upload| upload.id == id)
+        }
+
+        /// Removes uploads that were not resumed within the grace time
+        pub fn purge(&self, config: &Config, total_size: &Arc<Mutex<u64>>) {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            self.remove_where(config, total_size, |upload| upload.deadline < timestamp);
+        }
+
+        /// Removes all uploads waiting to be resumed, when the server shuts down
+        pub fn discard(&self, config: &Config, total_size: &Arc<Mutex<u64>>) {
+            self.remove_where(config, total_size, |_| true);
+        }
+
+        fn remove_where<F: Fn(&SuspendedUpload) -> bool>(
+            &self,
+            config: &Config,
+            total_size: &Arc<Mutex<u64>>,
+            remove: F,
+        ) {
+            let mut uploads = self.uploads.lock().unwrap();
+            uploads.retain(|_, upload| {
+                if !remove(upload) {
+                    return true;
+                }
+
+                if let Err(io_err) = remove_file(partial_path(config, &upload.id)) {
+                    println!(
+                        "[{}] Failed to remove file: {:?}",
+                        hex::encode(upload.id),
+                        io_err
+                    );
+                }
+                *total_size.lock().unwrap() -= upload.reserved;
+                println!(
+                    "[{}] Interrupted upload was not resumed",
+                    hex::encode(upload.id)
+                );
+                false
+            });
+        }
+    }
+
+    /// Sets `total_size` to the size of the uploaded files, partial files are leftovers
+    /// of uploads interrupted by a crash and are removed. Files with a header this version
+    /// cannot read, for example one written by a newer server, are kept and counted.
+    ///
+    /// Reservations of running uploads are not counted, so no transfer may be running.
+    pub fn reconcile_total_size(config: &Config, total_size: &Arc<Mutex<u64>>) {
+        let mut total_size = total_size.lock().unwrap();
+        let mut size = 0;
+        let dir = fs::read_dir(config.uploads()).unwrap();
+        for entry in dir {
+            let entry = entry.unwrap();
+            if !entry.file_type().unwrap().is_file() {
+                continue;
+            }
+            if is_partial(&entry.path()) {
+                println!("Removing {:?}, upload was not completed", entry.path());
+                if let Err(io_err) = remove_file(entry.path()) {
+                    println!("Failed to remove file: {:?}", io_err);
+                }
+                continue;
+            }
+            let header = File::open(entry.path()).and_then(|mut file| Header::read(&mut file));
+            if let Err(io_err) = header {
+                println!(
+                    "Warning! Keeping {:?}, invalid header: {}",
+                    entry.path(),
+                    io_err
+                );
+            }
+            size += entry.metadata().unwrap().len();
+        }
+        *total_size = size;
+    }
+
+    /// Extension of uploads still being written
+    const PARTIAL_EXTENSION: &str = "partial";
+
+    /// Path of a committed upload
+    fn upload_path(config: &Config, id: &[u8]) -> PathBuf {
+        let mut path = PathBuf::from(config.uploads());
+        path.push(hex::encode(id));
+        path
+    }
+
+    /// Path an upload is written to until it is committed
+    fn partial_path(config: &Config, id: &[u8]) -> PathBuf {
+        upload_path(config, id).with_extension(PARTIAL_EXTENSION)
+    }
+
+    /// Persists renames in the directory, Windows cannot open a directory to sync it
+    #[cfg(unix)]
+    fn sync_dir(path: &str) -> io::Result<()> {
+        File::open(path)?.sync_all()
+    }
+
+    #[cfg(not(unix))]
+    fn sync_dir(_: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn is_partial(path: &Path) -> bool {
+        path.extension().is_some_and(|ext| ext == PARTIAL_EXTENSION)
+    }
+
+    /// Ids of uploads being received by any of the threads
+    struct UploadsInProgress {
+        ids: Mutex<HashSet<[u8; 32]>>,
+    }
+
+    impl UploadsInProgress {
+        fn new() -> Self {
+            Self {
+                ids: Mutex::new(HashSet::new()),
+            }
+        }
+
+        fn insert(&self, id: [u8; 32]) {
+            self.ids.lock().unwrap().insert(id);
+        }
+
+        fn remove(&self, id: &[u8; 32]) {
+            self.ids.lock().unwrap().remove(id);
+        }
+
+        fn contains(&self, id: &[u8]) -> bool {
+            let id: [u8; 32] = match id.try_into() {
+                Ok(id) => id,
+                Err(_) => return false,
+            };
+            self.ids.lock().unwrap().contains(&id)
+        }
+    }
+
+    /// Downloads in progress of files with a limited number of downloads
+    struct DownloadLimits {
+        active: Mutex<HashMap<Vec<u8>, u64>>,
+    }
+
+    impl DownloadLimits {
+        fn new() -> Self {
+            Self {
+                active: Mutex::new(HashMap::new()),
+            }
+        }
+
+        /// Registers a started download, fails if the downloads in progress
+        /// would exceed the downloads left
+        fn acquire(&self, download: &mut Download) -> Result<(), TransferError> {
+            let mut active = self.active.lock().unwrap();
+            let downloads_left = download.downloads_left()?;
+            if downloads_left == 0 {
+                return Ok(());
+            }
+
+            let count = active.entry(download.id.clone()).or_insert(0);
+            if *count >= downloads_left {
+                return Err(TransferError::DownloadLimitReached);
+            }
+            *count += 1;
+            download.limited = true;
+            Ok(())
+        }
+
+        /// Unregisters a download, one that reached the end of the file uses up
+        /// one of the downloads left, wherever it started, and the file is removed
+        /// after the last one
+        fn release(
+            &self,
+            download: &mut Download,
+            complete: bool,
+            config: &Config,
+            total_size: &Arc<Mutex<u64>>,
+        ) -> Result<(), TransferError> {
+            if !download.limited {
+                return Ok(());
+            }
+            download.limited = false;
+
+            let mut active = self.active.lock().unwrap();
+            if let Some(count) = active.get_mut(&download.id) {
+                *count -= 1;
+                if *count == 0 {
+                    active.remove(&download.id);
+                }
+            }
+            if !complete {
+                return Ok(());
+            }
+
+            let mut path = PathBuf::from(config.uploads());
+            path.push(hex::encode(&download.id));
+            let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+            let mut header = Header::read(&mut file)?;
+            header.downloads_left -= 1;
+            if header.downloads_left == 0 {
+                // The file may have expired or been deleted in the meantime,
+                // only the one removing it frees the space
+                let size = file.metadata()?.len();
+                remove_file(path)?;
+                *total_size.lock().unwrap() -= size;
+                println!(
+                    "[{}] Download limit reached, file removed",
+                    hex::encode(&download.id)
+                );
+            } else {
+                header.write(&mut file)?;
+            }
+            Ok(())
+        }
+    }
+
     pub trait FormatSize {
         fn format_size(self) -> String;
     }
@@ -138,20 +448,32 @@ pub mod thread_pool {
     struct ThreadParams {
         sockets_alive: Arc<AtomicUsize>,
         total_size: Arc<Mutex<u64>>,
-        config: Config,
+        suspended_uploads: Arc<SuspendedUploads>,
+        download_limits: Arc<DownloadLimits>,
+        uploads_in_progress: Arc<UploadsInProgress>,
+        config: SharedConfig,
         receiver: Receiver<ThreadMessage>,
     }
 
     enum ThreadMessage {
         Terminate,
+        /// Finish running transfers without starting new ones, then terminate
+        Drain,
         Accept(TcpStream),
     }
 
     enum TransferError {
         InvalidMessage,
         IOError,
+        NotFound,
         NetworkError,
         SizeLimitExceeded,
+        HandshakeRequired,
+        IncompatibleProtocol(u32),
+        UnknownResumeToken,
+        DownloadLimitReached,
+        InvalidDeleteToken,
+        UploadNotFinished,
     }
 
     impl Display for TransferError {
@@ -161,14 +483,57 @@ pub mod thread_pool {
                     f.write_str("TransferError::InvalidMessage: Received an invalid message")
                 },
                 TransferError::IOError => {
-                    f.write_str("TransferError::IOError: An error occurred during a file operation. Maybe your file expired?")
+                    f.write_str("TransferError::IOError: An error occurred during a file operation")
+                },
+                TransferError::NotFound => {
+                    f.write_str("TransferError::NotFound: File does not exist. Maybe it expired?")
                 },
                 TransferError::NetworkError => {
                     f.write_str("TransferError::NetworkError")
                 },
                 TransferError::SizeLimitExceeded => {
                     f.write_str("TransferError::SizeLimitExceeded")
+                },
+                TransferError::HandshakeRequired => {
+                    f.write_str("TransferError::HandshakeRequired: Client did not send a protocol hello. Please update your client.")
+                },
+                TransferError::IncompatibleProtocol(version) => {
+                    f.write_str(&format!(
+                        "TransferError::IncompatibleProtocol: Client protocol version {} is not supported, server supports versions {} to {}",
+                        version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                    ))
+                },
+                TransferError::UnknownResumeToken => {
+                    f.write_str("TransferError::UnknownResumeToken: Upload cannot be resumed. Maybe the grace time passed?")
+                },
+                TransferError::DownloadLimitReached => {
+                    f.write_str("TransferError::DownloadLimitReached: File is already being downloaded the maximum number of times")
+                },
+                TransferError::InvalidDeleteToken => {
+                    f.write_str("TransferError::InvalidDeleteToken: Delete token does not match the file")
                 }
+                TransferError::UploadNotFinished => {
+                    f.write_str("TransferError::UploadNotFinished: File is still being uploaded, try again later")
+                }
+            }
+        }
+    }
+
+    impl TransferError {
+        /// Code sent to the client along with the description
+        fn code(&self) -> u32 {
+            match self {
+                TransferError::InvalidMessage => ERROR_INVALID_MESSAGE,
+                TransferError::IOError => ERROR_IO,
+                TransferError::NotFound => ERROR_NOT_FOUND,
+                TransferError::NetworkError => ERROR_NETWORK,
+                TransferError::SizeLimitExceeded => ERROR_SIZE_LIMIT_EXCEEDED,
+                TransferError::HandshakeRequired => ERROR_HANDSHAKE_REQUIRED,
+                TransferError::IncompatibleProtocol(_) => ERROR_INCOMPATIBLE_PROTOCOL,
+                TransferError::UnknownResumeToken => ERROR_UNKNOWN_RESUME_TOKEN,
+                TransferError::DownloadLimitReached => ERROR_DOWNLOAD_LIMIT_REACHED,
+                TransferError::InvalidDeleteToken => ERROR_INVALID_DELETE_TOKEN,
+                TransferError::UploadNotFinished => ERROR_UPLOAD_NOT_FINISHED,
             }
         }
     }
@@ -186,8 +551,11 @@ pub mod thread_pool {
     }
 
     impl From<io::Error> for TransferError {
-        fn from(_: io::Error) -> Self {
-            TransferError::IOError
+        fn from(error: io::Error) -> Self {
+            match error.kind() {
+                io::ErrorKind::NotFound => TransferError::NotFound,
+                _ => TransferError::IOError,
+            }
         }
     }
 
@@ -202,6 +570,7 @@ pub mod thread_pool {
         state: ClientState,
         params: &'a ThreadParams,
         file_size_reserved: u64,
+        protocol_version: Option<u32>,
     }
 
     impl<'a> Client<'a> {
@@ -211,6 +580,7 @@ pub mod thread_pool {
                 state: ClientState::Idle,
                 params,
                 file_size_reserved: 0,
+                protocol_version: None,
             }
         }
 
@@ -241,20 +611,47 @@ pub mod thread_pool {
         }
 
         fn process_message(&mut self, msg: &mut Message) -> Result<(), TransferError> {
+            // Limits are read when a transfer starts, a reload only affects new ones
+            let config = self.params.config.get();
             let mut new_state = None;
+            let mut committed = false;
             match &mut self.state {
                 ClientState::Idle => {
                     let command = msg.read_i32()?;
+                    if command != COMMAND_HELLO && self.protocol_version.is_none() {
+                        return Err(TransferError::HandshakeRequired);
+                    }
                     match command {
-                        0 => {
-                            let upload = Upload::begin(&self.params.config)?;
+                        COMMAND_HELLO => {
+                            let version = msg.read_u32()?;
+                            let _client_features = msg.read_u64()?;
+                            if version < MIN_PROTOCOL_VERSION {
+                                return Err(TransferError::IncompatibleProtocol(version));
+                            }
+                            self.protocol_version = Some(version.min(PROTOCOL_VERSION));
 
-                            let mut max_size = self.params.config.max_size();
+                            let mut response = Message::new();
+                            response.write_i8(1);
+                            response.write_u32(PROTOCOL_VERSION);
+                            response.write_u64(FEATURES);
+                            self.socket.write(&response)?;
+                        }
+                        COMMAND_UPLOAD => {
+                            // Older clients do not send a lifetime, 0 means the default one
+                            let lifetime = msg.read_u64().unwrap_or(0);
+                            let max_downloads = msg.read_u64().unwrap_or(0);
+                            let encrypted = msg.read_u8().unwrap_or(0) != 0;
+                            let upload =
+                                Upload::begin(&config, lifetime, max_downloads, encrypted)?;
+
+                            let mut max_size = config.max_size();
                             {
                                 let mut total_size = self.params.total_size.lock().unwrap();
-                                let max_total = self.params.config.max_total_size();
-                                if (max_total - *total_size) < max_size {
-                                    max_size = max_total - *total_size;
+                                let max_total = config.max_total_size();
+                                // The limit may have been lowered below the size in use by a reload
+                                let available = max_total.saturating_sub(*total_size);
+                                if available < max_size {
+                                    max_size = available;
                                 }
 
                                 *total_size += max_size;
@@ -264,30 +661,100 @@ pub mod thread_pool {
 
                             let mut response = Message::new();
                             response.write_buffer(&upload.id);
-                            response.write_u64(self.params.config.max_size());
+                            response.write_u64(config.max_size());
+                            response.write_buffer(&upload.token);
+                            response.write_u64(upload.header.expires);
+                            response.write_buffer(&upload.header.owner_token.unwrap());
+                            self.params.uploads_in_progress.insert(upload.id);
+                            // Entered before responding, so a failed write releases the upload
+                            self.state = ClientState::Upload(upload);
+                            self.socket.write(&response)?;
+                        }
+                        COMMAND_RESUME_UPLOAD => {
+                            let token = msg.read_buffer()?;
+                            let suspended = self
+                                .params
+                                .suspended_uploads
+                                .resume(token)
+                                .ok_or(TransferError::UnknownResumeToken)?;
+                            let mut upload = match Upload::resume(&config, &suspended) {
+                                Ok(upload) => upload,
+                                Err(err) => {
+                                    let mut total_size = self.params.total_size.lock().unwrap();
+                                    *total_size -= suspended.reserved;
+                                    return Err(err);
+                                }
+                            };
+                            self.file_size_reserved += suspended.reserved;
+
+                            let mut response = Message::new();
+                            response.write_i8(1);
+                            let position = upload.position()?;
+                            response.write_u64(position - upload.header.size());
+                            println!("[{}] Upload resumed", hex::encode(upload.id));
+                            self.params.uploads_in_progress.insert(upload.id);
+                            self.state = ClientState::Upload(upload);
                             self.socket.write(&response)?;
-                            new_state = Some(ClientState::Upload(upload));
                         }
-                        1 => {
+                        COMMAND_DOWNLOAD => {
                             let id = msg.read_buffer()?;
-                            let download = Download::begin(&self.params.config, id.to_vec())?;
+                            self.check_upload_finished(id)?;
+                            let mut download = Download::begin(&config, id.to_vec())?;
+                            self.params.download_limits.acquire(&mut download)?;
+                            new_state = Some(ClientState::Download(download));
+                        }
+                        COMMAND_DOWNLOAD_RANGE => {
+                            let id = msg.read_buffer()?.to_vec();
+                            let offset = msg.read_u64()?;
+                            let length = msg.read_u64()?;
+                            self.check_upload_finished(&id)?;
+                            let mut download = Download::begin_range(&config, id, offset, length)?;
+                            self.params.download_limits.acquire(&mut download)?;
                             new_state = Some(ClientState::Download(download));
                         }
+                        COMMAND_DELETE => {
+                            let id = msg.read_buffer()?.to_vec();
+                            let token = msg.read_buffer()?.to_vec();
+                            delete_file(&config, &self.params.total_size, &id, &token)?;
+
+                            let mut response = Message::new();
+                            response.write_i8(1);
+                            self.socket.write(&response)?;
+                            println!("[{}] Deleted by the uploader", hex::encode(&id));
+                        }
+                        COMMAND_INFO => {
+                            let id = msg.read_buffer()?;
+                            self.check_upload_finished(id)?;
+                            let mut path = PathBuf::from(config.uploads());
+                            path.push(hex::encode(id));
+                            let mut file = File::open(path)?;
+                            let header = Header::read(&mut file)?;
+
+                            let mut response = Message::new();
+                            response.write_i8(1);
+                            response.write_u64(file.metadata()?.len() - header.size());
+                            response.write_u64(header.created);
+                            response.write_u64(header.expires);
+                            response.write_u64(header.downloads_left);
+                            self.socket.write(&response)?;
+                        }
                         _ => {}
                     }
                 }
                 ClientState::Upload(upload) => {
                     let cont = msg.read_u8()?;
                     if cont == 0 {
-                        let mut confirm_msg = Message::new();
-                        confirm_msg.write_i8(1);
-                        self.socket.write(&confirm_msg)?;
+                        let size = upload.position()?;
+                        upload.finish(&config)?;
+                        self.params.uploads_in_progress.remove(&upload.id);
                         new_state = Some(ClientState::Idle);
+                        committed = true;
 
                         //Free unused allocated space
                         {
                             let mut total_size = self.params.total_size.lock().unwrap();
-                            *total_size -= self.file_size_reserved - upload.position()?;
+                            *total_size -= self.file_size_reserved - size;
+                            self.file_size_reserved = 0;
                         }
                     } else {
                         let buffer = msg.read_buffer()?;
@@ -307,10 +774,18 @@ pub mod thread_pool {
                     self.state = new;
                 }
             }
+
+            // Confirmed once the client left the upload, so a failure cannot undo the commit
+            if committed {
+                let mut confirm_msg = Message::new();
+                confirm_msg.write_i8(1);
+                self.socket.write(&confirm_msg)?;
+            }
             Ok(())
         }
 
         fn flush_and_process(&mut self, buffer: &mut Vec<u8>) -> Result<(), TransferError> {
+            let config = self.params.config.get();
             let flushed = self.socket.flush()?;
 
             if flushed {
@@ -330,6 +805,24 @@ pub mod thread_pool {
                         }
 
                         self.socket.write(&message)?;
+
+                        // The client has every byte already, failing to count
+                        // the download must not turn it into an error
+                        if bytes_read == 0 {
+                            let complete = download.position()? >= download.size;
+                            if let Err(err) = self.params.download_limits.release(
+                                download,
+                                complete,
+                                &config,
+                                &self.params.total_size,
+                            ) {
+                                println!(
+                                    "[{}] Failed to count the download: {}",
+                                    hex::encode(&download.id),
+                                    err
+                                );
+                            }
+                        }
                     }
                     _ => {}
                 }
@@ -346,20 +839,48 @@ pub mod thread_pool {
         }
 
         #[allow(unused_must_use)]
-        fn send_error(&mut self, description: String) -> () {
+        fn send_error(&mut self, error: &TransferError) -> () {
             let mut message = Message::new();
             message.write_i8(-1);
-            message.write_buffer(description.as_bytes());
+            message.write_buffer(format!("{}", error).as_bytes());
+            message.write_u32(error.code());
 
             self.socket.write(&message);
         }
 
-        fn break_operation(&mut self) -> () {
+        /// Fails if the file of `id` is incomplete, because its upload is running
+        /// or waiting to be resumed
+        fn check_upload_finished(&self, id: &[u8]) -> Result<(), TransferError> {
+            if self.params.uploads_in_progress.contains(id)
+                || self.params.suspended_uploads.contains(id)
+            {
+                return Err(TransferError::UploadNotFinished);
+            }
+            Ok(())
+        }
+
+        /// Keeps an interrupted upload for the client to resume it
+        fn suspend_operation(&mut self) {
+            let config = self.params.config.get();
             match &self.state {
                 ClientState::Upload(upload) => {
-                    let mut path = PathBuf::from(self.params.config.uploads());
-                    path.push(hex::encode(upload.id));
-                    match remove_file(path) {
+                    self.params
+                        .suspended_uploads
+                        .suspend(upload, self.file_size_reserved, &config);
+                    self.params.uploads_in_progress.remove(&upload.id);
+                    self.file_size_reserved = 0;
+                    println!("[{}] Upload interrupted", hex::encode(upload.id));
+                    self.state = ClientState::Idle;
+                }
+                _ => self.break_operation(),
+            }
+        }
+
+        fn break_operation(&mut self) -> () {
+            let config = self.params.config.get();
+            match &mut self.state {
+                ClientState::Upload(upload) => {
+                    match remove_file(partial_path(&config, &upload.id)) {
                         Err(io_err) => {
                             println!(
                                 "[{}] Failed to remove file: {:?}",
@@ -369,6 +890,7 @@ pub mod thread_pool {
                         }
                         _ => {}
                     }
+                    self.params.uploads_in_progress.remove(&upload.id);
 
                     //Free allocated space
                     {
@@ -377,6 +899,14 @@ pub mod thread_pool {
                         self.file_size_reserved = 0;
                     }
                 }
+                ClientState::Download(download) => {
+                    let _ = self.params.download_limits.release(
+                        download,
+                        false,
+                        &config,
+                        &self.params.total_size,
+                    );
+                }
                 _ => {}
             }
             self.state = ClientState::Idle;
@@ -412,30 +942,88 @@ pub mod thread_pool {
     struct Upload {
         file: File,
         id: [u8; 32],
+        token: [u8; 32],
+        header: Header,
     }
 
     impl Upload {
-        fn begin(config: &Config) -> Result<Self, TransferError> {
+        /// Creates a new upload, which expires in `lifetime` seconds
+        /// clamped to MAX_EXPIRATION_TIME or in EXPIRATION_TIME if `lifetime` is 0
+        /// and can be downloaded `max_downloads` times or unlimited if it is 0
+        fn begin(
+            config: &Config,
+            lifetime: u64,
+            max_downloads: u64,
+            encrypted: bool,
+        ) -> Result<Self, TransferError> {
+            let mut rng = StdRng::from_entropy();
             let mut id = [0; 32];
-            StdRng::from_entropy().fill_bytes(&mut id);
-
-            let mut path = PathBuf::from(config.uploads());
-            path.push(hex::encode(id));
-            let file = File::create(path)?;
-
-            let mut upload = Self { file, id };
-            upload.write_expiration(config)?;
-
-            Ok(upload)
-        }
+            let mut token = [0; 32];
+            let mut owner_token = [0; 32];
+            rng.fill_bytes(&mut id);
+            rng.fill_bytes(&mut token);
+            rng.fill_bytes(&mut owner_token);
+
+            let mut file = File::create(partial_path(config, &id))?;
+
+            let mut lifetime = if lifetime == 0 {
+                config.expiration()
+            } else {
+                lifetime
+            };
+            lifetime = lifetime.min(config.max_expiration());
 
-        fn write_expiration(&mut self, config: &Config) -> Result<(), TransferError> {
             let timestamp = SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
-                .as_secs()
-                + config.expiration();
-            self.file.write_all(&timestamp.to_le_bytes())?;
+                .as_secs();
+            let mut header = Header::new(timestamp, timestamp + lifetime);
+            header.downloads_left = max_downloads;
+            header.encrypted = encrypted;
+            header.owner_token = Some(owner_token);
+            header.write(&mut file)?;
+
+            Ok(Self {
+                file,
+                id,
+                token,
+                header,
+            })
+        }
+
+        fn resume(config: &Config, suspended: &SuspendedUpload) -> Result<Self, TransferError> {
+            let path = partial_path(config, &suspended.id);
+            let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+            let header = Header::read(&mut file)?;
+            file.seek(SeekFrom::End(0))?;
+
+            Ok(Self {
+                file,
+                id: suspended.id,
+                token: suspended.token,
+                header,
+            })
+        }
+
+        /// Records the payload length once all data was received and moves the file
+        /// into place, so only complete uploads can be downloaded
+        fn finish(&mut self, config: &Config) -> Result<(), TransferError> {
+            self.header.payload_length = self.position()? - self.header.size();
+            self.header.write(&mut self.file)?;
+            self.file.sync_all()?;
+            fs::rename(
+                partial_path(config, &self.id),
+                upload_path(config, &self.id),
+            )?;
+            // The upload is committed at this point, failing to persist the rename
+            // must not make the caller remove it
+            if let Err(io_err) = sync_dir(config.uploads()) {
+                println!(
+                    "[{}] Failed to sync uploads directory: {:?}",
+                    hex::encode(self.id),
+                    io_err
+                );
+            }
             Ok(())
         }
 
@@ -451,27 +1039,98 @@ pub mod thread_pool {
 
     struct Download {
         file: File,
-        #[allow(dead_code)]
         id: Vec<u8>,
+        remaining: Option<u64>,
+        size: u64,
+        limited: bool,
     }
 
     impl Download {
         fn begin(config: &Config, id: Vec<u8>) -> Result<Self, TransferError> {
+            Self::begin_range(config, id, 0, 0)
+        }
+
+        /// Starts a download of `length` bytes at `offset` of the payload,
+        /// zero `length` means until the end
+        fn begin_range(
+            config: &Config,
+            id: Vec<u8>,
+            offset: u64,
+            length: u64,
+        ) -> Result<Self, TransferError> {
             let mut path = PathBuf::from(config.uploads());
             path.push(hex::encode(&id));
             let mut file = File::open(path)?;
-            file.seek(SeekFrom::Start(8))?;
-            Ok(Self { file, id })
+            let size = file.metadata()?.len();
+            let header = Header::read(&mut file)?;
+            file.seek(SeekFrom::Start(header.size() + offset))?;
+            let remaining = if length == 0 { None } else { Some(length) };
+            Ok(Self {
+                file,
+                id,
+                remaining,
+                size,
+                limited: false,
+            })
         }
 
         fn read(&mut self, buffer: &mut [u8]) -> Result<usize, TransferError> {
-            Ok(self.file.read(buffer)?)
+            let buffer = match self.remaining {
+                Some(remaining) if remaining < buffer.len() as u64 => {
+                    &mut buffer[..remaining as usize]
+                }
+                _ => buffer,
+            };
+            let bytes_read = self.file.read(buffer)?;
+            if let Some(remaining) = &mut self.remaining {
+                *remaining -= bytes_read as u64;
+            }
+            Ok(bytes_read)
         }
 
-        #[allow(dead_code)]
         fn position(&mut self) -> Result<u64, TransferError> {
             Ok(self.file.seek(SeekFrom::Current(0))?)
         }
+
+        fn downloads_left(&mut self) -> Result<u64, TransferError> {
+            let position = self.position()?;
+            let header = Header::read(&mut self.file)?;
+            self.file.seek(SeekFrom::Start(position))?;
+            Ok(header.downloads_left)
+        }
+    }
+
+    /// Removes an uploaded file if `token` matches its owner token
+    fn delete_file(
+        config: &Config,
+        total_size: &Arc<Mutex<u64>>,
+        id: &[u8],
+        token: &[u8],
+    ) -> Result<(), TransferError> {
+        let mut path = PathBuf::from(config.uploads());
+        path.push(hex::encode(id));
+        let mut file = File::open(&path)?;
+        let header = Header::read(&mut file)?;
+
+        let owner_token = header
+            .owner_token
+            .ok_or(TransferError::InvalidDeleteToken)?;
+        if token.len() != owner_token.len() {
+            return Err(TransferError::InvalidDeleteToken);
+        }
+        // Compare without an early exit, so the time does not reveal the matching prefix
+        let difference = owner_token
+            .iter()
+            .zip(token)
+            .fold(0, |difference, (a, b)| difference | (a ^ b));
+        if difference != 0 {
+            return Err(TransferError::InvalidDeleteToken);
+        }
+
+        let size = file.metadata()?.len();
+        remove_file(path)?;
+        *total_size.lock().unwrap() -= size;
+        Ok(())
     }
 
     fn thread_loop(thread_id: u64, params: ThreadParams) {
@@ -482,12 +1141,16 @@ pub mod thread_pool {
         let mut clients = Vec::new();
         let mut fds = Vec::new();
         let mut events = Vec::new();
+        let mut draining = false;
         loop {
             match receiver.try_recv() {
                 Ok(message) => match message {
                     ThreadMessage::Terminate => {
                         break;
                     }
+                    ThreadMessage::Drain => {
+                        draining = true;
+                    }
                     ThreadMessage::Accept(mut socket) => {
                         if socket.get_ready().is_ok() {
                             clients.push(Client::new(socket, &params));
@@ -499,6 +1162,23 @@ pub mod thread_pool {
                 Err(_) => {}
             }
 
+            if draining {
+                // Connections without a running transfer are closed once the last response
+                // is sent, so no new transfer starts
+                let count = clients.len();
+                clients.retain_mut(|client| match client.state {
+                    ClientState::Idle => matches!(client.socket.flush(), Ok(false)),
+                    _ => true,
+                });
+                if clients.len() != count {
+                    update_poll_params(&clients, &mut fds, &mut events);
+                    sockets_alive.store(clients.len(), Release);
+                }
+                if clients.is_empty() {
+                    break;
+                }
+            }
+
             let index = simpletcp::utils::poll_set_ev_timeout(&mut fds, &mut events, 50);
 
             match index {
@@ -509,9 +1189,14 @@ pub mod thread_pool {
 
                     match client.read_and_process() {
                         Err(error) => {
-                            client.send_error(format!("{}", error));
+                            client.send_error(&error);
                             match error {
                                 TransferError::NetworkError => {
+                                    client.suspend_operation();
+                                    remove = true;
+                                }
+                                TransferError::HandshakeRequired
+                                | TransferError::IncompatibleProtocol(_) => {
                                     remove = true;
                                 }
                                 _ => {}
@@ -523,9 +1208,10 @@ pub mod thread_pool {
 
                     match client.flush_and_process(&mut thread_buffer) {
                         Err(error) => {
-                            client.send_error(format!("{}", error));
+                            client.send_error(&error);
                             match error {
                                 TransferError::NetworkError => {
+                                    client.suspend_operation();
                                     remove = true;
                                 }
                                 _ => {}
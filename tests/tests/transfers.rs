@@ -1,15 +1,22 @@
 #[macro_use]
 extern crate lazy_static;
 
+use sfshr::protocol::protocol::{COMMAND_DOWNLOAD, COMMAND_HELLO, COMMAND_UPLOAD};
+use simpletcp::simpletcp::Message;
+use std::convert::TryInto;
 use std::fs;
-use std::fs::File;
-use std::io::{Read, Write};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
 use std::ops::Deref;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::{Child, Command, Stdio};
-use std::sync::Mutex;
-use std::thread::sleep;
-use std::time::Duration;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 static mut SERVER: Option<Child> = None;
 lazy_static! {
@@ -61,6 +68,7 @@ fn encrypted_transfer() {
         .args(&[
             "run",
             "--",
+            "--yes",
             "--quiet",
             "--fingerprint",
             "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
@@ -128,6 +136,7 @@ fn unencrypted_transfer() {
         .args(&[
             "run",
             "--",
+            "--yes",
             "--quiet",
             "--fingerprint",
             "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
@@ -196,6 +205,7 @@ fn expired() {
         .args(&[
             "run",
             "--",
+            "--yes",
             "--quiet",
             "--fingerprint",
             "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
@@ -217,15 +227,2109 @@ fn expired() {
     clean_up();
 }
 
+#[test]
+fn requested_expiration() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    unsafe {
+        SERVER = Some(start_server("../tests/tests/normal-config"));
+    }
+    wait_for_server();
+    generate_test_file();
+    let sender = Command::new("cargo")
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .args(&[
+            "run",
+            "--",
+            "--quiet",
+            "--server",
+            "localhost:40788",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--no-encryption",
+            "--expire",
+            "1s",
+            "test-file",
+        ])
+        .current_dir("../client")
+        .spawn()
+        .unwrap_or_else(unwrap_clean_up);
+    let sender_output = sender.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !sender_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(sender_output.stdout).unwrap()
+        );
+        panic!("Sender exited with a non-zero exit code.");
+    }
+    remove_test_file();
+    let mut link = String::from_utf8(sender_output.stdout).unwrap_or_else(unwrap_clean_up);
+    link = link.replace('\n', "");
+    let link_args: Vec<&str> = link.split(' ').collect();
+
+    sleep(Duration::from_secs(10));
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--quiet",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if receiver_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(receiver_output.stdout).unwrap()
+        );
+        panic!("Receiver exited with a zero exit code.");
+    }
+
+    // Longer than MAX_EXPIRATION_TIME, which defaults to 7 days,
+    // units ignore case like in the server config
+    generate_test_file();
+    let sender = Command::new("cargo")
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .args(&[
+            "run",
+            "--",
+            "--server",
+            "localhost:40788",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--no-encryption",
+            "--expire",
+            "30D",
+            "test-file",
+        ])
+        .current_dir("../client")
+        .spawn()
+        .unwrap_or_else(unwrap_clean_up);
+    let sender_output = sender.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    remove_test_file();
+    let sender_stdout = String::from_utf8(sender_output.stdout).unwrap_or_else(unwrap_clean_up);
+    // Progress is printed before the link
+    let encoded_key = sender_stdout
+        .lines()
+        .find(|line| line.contains(" -r "))
+        .and_then(|line| line.rsplit(' ').next())
+        .unwrap_or_default();
+    let download_key = base64::decode(encoded_key).unwrap_or_else(unwrap_clean_up);
+    let info = sfshr::info("localhost:40788", &download_key[..32], Some(fingerprint()))
+        .unwrap_or_else(unwrap_clean_up);
+
+    let info_output = Command::new("cargo")
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--quiet",
+            "--server",
+            "localhost:40788",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--info",
+            encoded_key,
+        ])
+        .current_dir("../client")
+        .output()
+        .unwrap_or_else(unwrap_clean_up);
+    let info_stdout = String::from_utf8(info_output.stdout).unwrap_or_else(unwrap_clean_up);
+    let expires_line = info_stdout
+        .lines()
+        .find(|line| line.starts_with("Expires at"))
+        .map(String::from)
+        .unwrap_or_default();
+    clean_up();
+
+    assert!(sender_output.status.success());
+    assert_eq!(info.expires - info.created, 7 * 24 * 60 * 60);
+    assert!(!expires_line.is_empty(), "{}", info_stdout);
+    assert!(
+        sender_stdout.contains(&expires_line),
+        "Printed expiration differs from the server's: {}",
+        sender_stdout
+    );
+}
+
 #[test]
 fn size_exceeded() {
     let _guard = MUTEX.deref().lock().unwrap();
     unsafe {
-        SERVER = Some(start_server("../tests/tests/size-exceed-config"));
+        SERVER = Some(start_server("../tests/tests/size-exceed-config"));
+    }
+    wait_for_server();
+    generate_test_file();
+    let sender = Command::new("cargo")
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .args(&[
+            "run",
+            "--",
+            "--quiet",
+            "--server",
+            "localhost:40788",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--no-encryption",
+            "test-file",
+        ])
+        .current_dir("../client")
+        .spawn()
+        .unwrap_or_else(unwrap_clean_up);
+    let sender_output = sender.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if sender_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(sender_output.stdout).unwrap()
+        );
+        panic!("Sender exited with a zero exit code.");
+    }
+    remove_test_file();
+    clean_up();
+}
+
+#[test]
+fn toml_config() {
+    let _guard = MUTEX.deref().lock().unwrap();
+
+    // Invalid values are reported instead of being ignored
+    let invalid = Command::new("cargo")
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .env("SFSHR_MAX_TOTAL_SIZE", "lots")
+        .args(&["run", "--", "--config", "../tests/tests/toml-config.toml"])
+        .current_dir("../server")
+        .spawn()
+        .unwrap();
+    let invalid_output = invalid.wait_with_output().unwrap();
+    let invalid_stdout = String::from_utf8(invalid_output.stdout).unwrap();
+    if invalid_output.status.success() || !invalid_stdout.contains("MAX_TOTAL_SIZE") {
+        println!("---stdout---\n {}", invalid_stdout);
+        panic!("Server accepted an invalid config.");
+    }
+
+    // Environment overrides the limit of the file
+    unsafe {
+        SERVER = Some(
+            Command::new("cargo")
+                .stderr(Stdio::inherit())
+                .stdout(Stdio::piped())
+                .env("SFSHR_MAX_SIZE", "1KiB")
+                // Longer than the maximum, so it is clamped, units ignore case
+                .env("SFSHR_EXPIRATION_TIME", "4W")
+                .env("SFSHR_MAX_EXPIRATION_TIME", "1H")
+                .args(&["run", "--", "--config", "../tests/tests/toml-config.toml"])
+                .current_dir("../server")
+                .spawn()
+                .unwrap(),
+        );
+    }
+    wait_for_server();
+    generate_test_file();
+    let sender = Command::new("cargo")
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .args(&[
+            "run",
+            "--",
+            "--quiet",
+            "--server",
+            "localhost:40788",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--compression",
+            "none",
+            "test-file",
+        ])
+        .current_dir("../client")
+        .spawn()
+        .unwrap_or_else(unwrap_clean_up);
+    let sender_output = sender.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if sender_output.status.code() != Some(5) {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(sender_output.stdout).unwrap()
+        );
+        panic!("Sender was not refused by the size limit.");
+    }
+
+    let mut upload = sfshr::Upload::new(
+        "localhost:40788",
+        false,
+        0,
+        sfshr::ShareOptions::default(),
+        &[],
+        Some(fingerprint()),
+    )
+    .unwrap_or_else(unwrap_clean_up);
+    upload.finalize().unwrap_or_else(unwrap_clean_up);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let lifetime = upload.expiration().unwrap_or(0).saturating_sub(now);
+    clean_up();
+
+    assert!(
+        lifetime > 3500 && lifetime <= 3600,
+        "Expiration was not clamped, lifetime {}",
+        lifetime
+    );
+}
+
+#[test]
+fn config_reload() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    let config = fs::read_to_string("../tests/tests/normal-config").unwrap();
+    fs::write("../server/test-reload-config", &config).unwrap();
+    unsafe {
+        SERVER = Some(
+            Command::new("cargo")
+                .stdin(Stdio::piped())
+                .stderr(Stdio::inherit())
+                .stdout(Stdio::piped())
+                .args(&["run", "--", "--config", "test-reload-config"])
+                .current_dir("../server")
+                .spawn()
+                .unwrap(),
+        );
+    }
+    wait_for_server();
+
+    let mut codes = Vec::new();
+    for max_size in &[None, Some("10")] {
+        if let Some(max_size) = max_size {
+            // Lower the limit and reload it through the control command
+            let config =
+                config.replace("MAX_SIZE=2 000 000 000", &format!("MAX_SIZE={}", max_size));
+            fs::write("../server/test-reload-config", config).unwrap();
+            let mut output = Vec::new();
+            unsafe {
+                let server = SERVER.as_mut().unwrap();
+                server
+                    .stdin
+                    .as_mut()
+                    .unwrap()
+                    .write_all(b"reload\n")
+                    .unwrap();
+                let stdout = server.stdout.as_mut().unwrap();
+                while !String::from_utf8_lossy(&output).contains("Config reloaded") {
+                    let mut byte = [0];
+                    stdout.read_exact(&mut byte).unwrap_or_else(unwrap_clean_up);
+                    output.push(byte[0]);
+                }
+            }
+        }
+        generate_test_file();
+        let sender = Command::new("cargo")
+            .stderr(Stdio::inherit())
+            .stdout(Stdio::piped())
+            .args(&[
+                "run",
+                "--",
+                "--quiet",
+                "--server",
+                "localhost:40788",
+                "--fingerprint",
+                "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+                "--compression",
+                "none",
+                "test-file",
+            ])
+            .current_dir("../client")
+            .spawn()
+            .unwrap_or_else(unwrap_clean_up);
+        let sender_output = sender.wait_with_output().unwrap_or_else(unwrap_clean_up);
+        codes.push(sender_output.status.code());
+        remove_test_file();
+    }
+    clean_up();
+    fs::remove_file("../server/test-reload-config").unwrap();
+
+    assert_eq!(codes, [Some(0), Some(5)]);
+}
+
+#[test]
+fn graceful_shutdown() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    unsafe {
+        SERVER = Some(
+            Command::new("cargo")
+                .stdin(Stdio::piped())
+                .stderr(Stdio::inherit())
+                .stdout(Stdio::piped())
+                .env("SFSHR_SHUTDOWN_TIMEOUT", "2s")
+                .args(&["run", "--", "--config", "../tests/tests/small-size-config"])
+                .current_dir("../server")
+                .spawn()
+                .unwrap(),
+        );
+    }
+    wait_for_server();
+    let fingerprint = fingerprint();
+    let upload = || {
+        sfshr::Upload::new(
+            "localhost:40788",
+            false,
+            0,
+            sfshr::ShareOptions::default(),
+            &[],
+            Some(fingerprint.clone()),
+        )
+    };
+    let data = vec![0x55; 100 * 1024];
+
+    // One upload finishes during the shutdown, the other one stalls past the timeout
+    let mut finished = upload().unwrap_or_else(unwrap_clean_up);
+    let mut stalled = upload().unwrap_or_else(unwrap_clean_up);
+    finished.write_all(&data).unwrap_or_else(unwrap_clean_up);
+    stalled.write_all(&data).unwrap_or_else(unwrap_clean_up);
+    unsafe {
+        SERVER
+            .as_mut()
+            .unwrap()
+            .stdin
+            .as_mut()
+            .unwrap()
+            .write_all(b"shutdown\n")
+            .unwrap();
+    }
+    sleep(Duration::from_secs(1));
+    let refused = upload().is_err();
+    finished.write_all(&data).unwrap_or_else(unwrap_clean_up);
+    let finalized = finished.finalize().is_ok();
+
+    let status = unsafe { SERVER.as_mut().unwrap().wait().unwrap() };
+    let mut files: Vec<String> = fs::read_dir("../server/test-uploads")
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    files.sort();
+    clean_up();
+
+    assert!(refused, "Connection accepted during shutdown");
+    assert!(finalized, "Running upload was interrupted");
+    assert!(status.success(), "Server exited with {}", status);
+    let id: String = finished.id().iter().map(|b| format!("{:02x}", b)).collect();
+    assert_eq!(files, [id]);
+}
+
+#[test]
+fn staged_upload() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    // Left behind by an upload interrupted by a crash
+    let stale = format!("../server/test-uploads/{}.partial", "ab".repeat(32));
+    fs::create_dir_all("../server/test-uploads").unwrap();
+    fs::write(&stale, vec![0; 1024]).unwrap();
+    // Written by a newer server with a header version this one cannot read
+    let newer = format!("../server/test-uploads/{}", "cd".repeat(32));
+    let mut newer_header = b"SFSH\x02".to_vec();
+    newer_header.resize(1024, 0);
+    fs::write(&newer, newer_header).unwrap();
+    unsafe {
+        SERVER = Some(start_server("../tests/tests/normal-config"));
+    }
+
+    wait_for_server();
+    let stale_removed = !Path::new(&stale).exists();
+    let newer_kept = Path::new(&newer).exists();
+    let fingerprint = fingerprint();
+    let download = |id: &Vec<u8>| {
+        let mut content = Vec::new();
+        sfshr::Download::new(
+            "localhost:40788",
+            &id[..].try_into().unwrap(),
+            None,
+            Some(fingerprint.clone()),
+        )
+        .and_then(|mut download| Ok(download.read_to_end(&mut content)?))
+        .map(|_| content)
+    };
+
+    let mut upload = sfshr::Upload::new(
+        "localhost:40788",
+        false,
+        0,
+        sfshr::ShareOptions::default(),
+        &[],
+        Some(fingerprint.clone()),
+    )
+    .unwrap_or_else(unwrap_clean_up);
+    upload
+        .write_all(&vec![0x33; 100 * 1024])
+        .unwrap_or_else(unwrap_clean_up);
+    sleep(Duration::from_millis(500));
+    let id = upload.id().clone();
+    let hex_id: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+    let staged = Path::new(&format!("../server/test-uploads/{}.partial", hex_id)).exists();
+    let early = download(&id);
+    upload.finalize().unwrap_or_else(unwrap_clean_up);
+    let committed = Path::new(&format!("../server/test-uploads/{}", hex_id)).exists();
+    let content = download(&id);
+    clean_up();
+
+    assert!(stale_removed, "Stale partial upload was not removed");
+    assert!(newer_kept, "Upload with a newer header was removed");
+    assert!(staged, "Upload is not written to a partial file");
+    assert!(early.is_err(), "Unfinished upload was downloaded");
+    assert!(committed, "Finished upload was not moved into place");
+    let content = content.unwrap();
+    assert_eq!(content.len(), 100 * 1024);
+    assert!(content.iter().all(|&byte| byte == 0x33));
+}
+
+#[test]
+fn unfinished_upload() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    unsafe {
+        SERVER = Some(start_server("../tests/tests/small-size-config"));
+    }
+
+    wait_for_server();
+    let fingerprint = fingerprint();
+    let upload = || {
+        sfshr::Upload::new(
+            "localhost:40788",
+            false,
+            0,
+            sfshr::ShareOptions::default(),
+            &[],
+            Some(fingerprint.clone()),
+        )
+        .unwrap_or_else(unwrap_clean_up)
+    };
+    // Whether the download failed because the upload is not finished
+    let not_finished = |id: &Vec<u8>| {
+        let mut content = Vec::new();
+        let result = sfshr::Download::new(
+            "localhost:40788",
+            &id[..].try_into().unwrap(),
+            None,
+            Some(fingerprint.clone()),
+        )
+        .unwrap_or_else(unwrap_clean_up)
+        .read_to_end(&mut content);
+        match result.map_err(|err| err.into_inner().map(|inner| inner.downcast())) {
+            Err(Some(Ok(err))) => matches!(*err, sfshr::TransferError::UploadNotFinished(_)),
+            _ => false,
+        }
+    };
+
+    let mut running = upload();
+    running
+        .write_all(&vec![0x44; 100 * 1024])
+        .unwrap_or_else(unwrap_clean_up);
+    let mut interrupted = upload();
+    interrupted
+        .write_all(&vec![0x44; 100 * 1024])
+        .unwrap_or_else(unwrap_clean_up);
+    let interrupted_id = interrupted.id().clone();
+    // The connection is lost, the upload waits to be resumed
+    drop(interrupted);
+    sleep(Duration::from_millis(500));
+
+    let running_refused = not_finished(running.id());
+    let info = sfshr::info("localhost:40788", running.id(), Some(fingerprint.clone()));
+    let info_refused = matches!(info, Err(sfshr::TransferError::UploadNotFinished(_)));
+    let interrupted_refused = not_finished(&interrupted_id);
+    running.finalize().unwrap_or_else(unwrap_clean_up);
+    let finished_refused = not_finished(running.id());
+
+    clean_up();
+
+    assert!(
+        running_refused,
+        "Running upload was not reported as unfinished"
+    );
+    assert!(
+        info_refused,
+        "Info of a running upload was not reported as unfinished"
+    );
+    assert!(
+        interrupted_refused,
+        "Interrupted upload was not reported as unfinished"
+    );
+    assert!(
+        !finished_refused,
+        "Finished upload was reported as unfinished"
+    );
+}
+
+#[test]
+fn protocol_handshake() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    unsafe {
+        SERVER = Some(start_server("../tests/tests/normal-config"));
+    }
+
+    wait_for_server();
+    // Sends `message` as the first message of a connection, returns the error description
+    let first_message = |message: Message| {
+        let mut conn = simpletcp::simpletcp::TcpStream::connect("localhost:40788")
+            .unwrap_or_else(unwrap_clean_up);
+        conn.wait_until_ready().unwrap_or_else(unwrap_clean_up);
+        conn.write_blocking(&message)
+            .unwrap_or_else(unwrap_clean_up);
+        let mut response = conn
+            .read_timeout(5000)
+            .unwrap_or_else(unwrap_clean_up)
+            .unwrap_or_else(|| unwrap_clean_up("no response"));
+        let status = response.read_i8().unwrap_or_else(unwrap_clean_up);
+        let description = response.read_buffer().unwrap_or_else(unwrap_clean_up);
+        (status, String::from_utf8_lossy(description).into_owned())
+    };
+
+    // Client that skips the hello
+    let mut download = Message::new();
+    download.write_i32(COMMAND_DOWNLOAD);
+    download.write_buffer(&[0; 32]);
+    let skipped = first_message(download);
+
+    // Client older than the server supports
+    let mut hello = Message::new();
+    hello.write_i32(COMMAND_HELLO);
+    hello.write_u32(0);
+    hello.write_u64(0);
+    let too_old = first_message(hello);
+
+    // Server older than the client supports
+    let fake_server =
+        simpletcp::simpletcp::TcpServer::new("localhost:40790").unwrap_or_else(unwrap_clean_up);
+    let old_server = {
+        let accepted = spawn(move || {
+            let mut conn = fake_server.accept_blocking().unwrap();
+            conn.wait_until_ready().unwrap();
+            conn.read_blocking().unwrap();
+            let mut response = Message::new();
+            response.write_i8(1);
+            response.write_u32(0);
+            response.write_u64(0);
+            conn.write_blocking(&response).unwrap();
+            conn.read_timeout(5000).ok();
+        });
+        let upload = sfshr::Upload::new(
+            "localhost:40790",
+            false,
+            0,
+            sfshr::ShareOptions::default(),
+            &[],
+            None,
+        );
+        accepted.join().unwrap();
+        upload.err()
+    };
+    clean_up();
+
+    assert_eq!(skipped.0, -1);
+    assert!(
+        skipped.1.starts_with("TransferError::HandshakeRequired"),
+        "{}",
+        skipped.1
+    );
+    assert_eq!(too_old.0, -1);
+    assert!(
+        too_old.1.starts_with("TransferError::IncompatibleProtocol"),
+        "{}",
+        too_old.1
+    );
+    assert!(
+        matches!(
+            old_server,
+            Some(sfshr::TransferError::IncompatibleServer(_))
+        ),
+        "Old server was not reported as incompatible"
+    );
+}
+
+#[test]
+fn directory() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    unsafe {
+        SERVER = Some(start_server("../tests/tests/normal-config"));
+    }
+
+    wait_for_server();
+    generate_test_dir();
+    let sender = Command::new("cargo")
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .args(&[
+            "run",
+            "--",
+            "--quiet",
+            "--server",
+            "localhost:40788",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "test-dir",
+        ])
+        .current_dir("../client")
+        .spawn()
+        .unwrap_or_else(unwrap_clean_up);
+    let sender_output = sender.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !sender_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(sender_output.stdout).unwrap()
+        );
+        panic!("Sender exited with non-zero exit code.");
+    }
+    remove_test_dir();
+    let mut link = String::from_utf8(sender_output.stdout).unwrap_or_else(unwrap_clean_up);
+    link = link.replace('\n', "");
+    let link_args: Vec<&str> = link.split(' ').collect();
+
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--quiet",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !receiver_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(receiver_output.stdout).unwrap()
+        );
+        panic!("Receiver exited with a non-zero exit code.");
+    }
+    check_test_dir();
+    clean_up();
+}
+
+#[test]
+fn fingerprint_mismatch() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    unsafe {
+        SERVER = Some(start_server("../tests/tests/normal-config"));
+    }
+    wait_for_server();
+    generate_test_file();
+    let sender = Command::new("cargo")
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .args(&[
+            "run",
+            "--",
+            "--quiet",
+            "--server",
+            "localhost:40788",
+            "--fingerprint",
+            "0aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--no-encryption",
+            "test-file",
+        ])
+        .current_dir("../client")
+        .spawn()
+        .unwrap_or_else(unwrap_clean_up);
+    let sender_output = sender.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if sender_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(sender_output.stdout).unwrap()
+        );
+        panic!("Sender exited with a zero exit code.");
+    }
+    remove_test_file();
+    clean_up();
+}
+
+#[test]
+fn tampered_transfer() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    unsafe {
+        SERVER = Some(start_server("../tests/tests/normal-config"));
+    }
+
+    wait_for_server();
+    generate_test_file();
+    let sender = Command::new("cargo")
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .args(&[
+            "run",
+            "--",
+            "--quiet",
+            "--server",
+            "localhost:40788",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--compression",
+            "none",
+            "test-file",
+        ])
+        .current_dir("../client")
+        .spawn()
+        .unwrap_or_else(unwrap_clean_up);
+    let sender_output = sender.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !sender_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(sender_output.stdout).unwrap()
+        );
+        panic!("Sender exited with non-zero exit code.");
+    }
+    remove_test_file();
+    let mut link = String::from_utf8(sender_output.stdout).unwrap_or_else(unwrap_clean_up);
+    link = link.replace('\n', "");
+    let link_args: Vec<&str> = link.split(' ').collect();
+
+    tamper_uploaded_file(1024);
+
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--quiet",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if receiver_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(receiver_output.stdout).unwrap()
+        );
+        panic!("Receiver exited with a zero exit code.");
+    }
+    if Path::new("../client/test-file").exists() {
+        clean_up();
+        panic!("Receiver unpacked tampered data.");
+    }
+    clean_up();
+}
+
+#[test]
+fn resumed_upload() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    unsafe {
+        SERVER = Some(start_server("../tests/tests/normal-config"));
+    }
+
+    wait_for_server();
+    start_flaky_proxy(
+        "localhost:40789",
+        "localhost:40788",
+        Some(8 * 1024 * 1024),
+        None,
+    );
+    generate_test_file();
+    let sender = Command::new("cargo")
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .args(&[
+            "run",
+            "--",
+            "--quiet",
+            "--server",
+            "localhost:40789",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--compression",
+            "none",
+            "test-file",
+        ])
+        .current_dir("../client")
+        .spawn()
+        .unwrap_or_else(unwrap_clean_up);
+    let sender_output = sender.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !sender_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(sender_output.stdout).unwrap()
+        );
+        panic!("Sender exited with non-zero exit code.");
+    }
+    remove_test_file();
+    let mut link = String::from_utf8(sender_output.stdout).unwrap_or_else(unwrap_clean_up);
+    link = link.replace('\n', "");
+    let link_args: Vec<&str> = link.split(' ').collect();
+
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--quiet",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !receiver_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(receiver_output.stdout).unwrap()
+        );
+        panic!("Receiver exited with a non-zero exit code.");
+    }
+    check_test_file("../client/test-file");
+    clean_up();
+}
+
+#[test]
+fn resumed_download() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    unsafe {
+        SERVER = Some(start_server("../tests/tests/normal-config"));
+    }
+
+    wait_for_server();
+    generate_test_file();
+    let sender = Command::new("cargo")
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .args(&[
+            "run",
+            "--",
+            "--quiet",
+            "--server",
+            "localhost:40788",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--compression",
+            "none",
+            "test-file",
+        ])
+        .current_dir("../client")
+        .spawn()
+        .unwrap_or_else(unwrap_clean_up);
+    let sender_output = sender.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !sender_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(sender_output.stdout).unwrap()
+        );
+        panic!("Sender exited with non-zero exit code.");
+    }
+    remove_test_file();
+    let mut link = String::from_utf8(sender_output.stdout).unwrap_or_else(unwrap_clean_up);
+    link = link.replace('\n', "");
+    let link_args: Vec<&str> = link.split(' ').collect();
+
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--quiet",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--tar",
+            "test.tar",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !receiver_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(receiver_output.stdout).unwrap()
+        );
+        panic!("Receiver exited with a non-zero exit code.");
+    }
+    let complete = fs::read("../client/test.tar").unwrap_or_else(unwrap_clean_up);
+
+    // Simulate an interrupted download in the middle of a chunk
+    let file = OpenOptions::new()
+        .write(true)
+        .open("../client/test.tar")
+        .unwrap_or_else(unwrap_clean_up);
+    file.set_len(10 * 1024 * 1024 + 123)
+        .unwrap_or_else(unwrap_clean_up);
+
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--quiet",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--tar",
+            "test.tar",
+            "--resume",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !receiver_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(receiver_output.stdout).unwrap()
+        );
+        panic!("Resumed receiver exited with a non-zero exit code.");
+    }
+    let resumed = fs::read("../client/test.tar").unwrap_or_else(unwrap_clean_up);
+    clean_up();
+    assert!(resumed == complete);
+}
+
+#[test]
+fn resumed_compressed_download() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    unsafe {
+        SERVER = Some(start_server("../tests/tests/normal-config"));
+    }
+
+    wait_for_server();
+    start_flaky_proxy(
+        "localhost:40791",
+        "localhost:40788",
+        None,
+        Some(4 * 1024 * 1024),
+    );
+    // Data that does not compress well, so the download is cut in the middle
+    let mut state: u32 = 1;
+    let data: Vec<u8> = (0..8 * 1024 * 1024)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16) as u8
+        })
+        .collect();
+    fs::write("../client/test-file", &data).unwrap_or_else(unwrap_clean_up);
+    let sender = Command::new("cargo")
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .args(&[
+            "run",
+            "--",
+            "--quiet",
+            "--server",
+            "localhost:40788",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "test-file",
+        ])
+        .current_dir("../client")
+        .spawn()
+        .unwrap_or_else(unwrap_clean_up);
+    let sender_output = sender.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !sender_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(sender_output.stdout).unwrap()
+        );
+        panic!("Sender exited with non-zero exit code.");
+    }
+    remove_test_file();
+    let mut link = String::from_utf8(sender_output.stdout).unwrap_or_else(unwrap_clean_up);
+    link = link.replace('\n', "");
+    let link_args: Vec<&str> = link.split(' ').collect();
+
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--quiet",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--tar",
+            "test.tar",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !receiver_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(receiver_output.stdout).unwrap()
+        );
+        panic!("Receiver exited with a non-zero exit code.");
+    }
+    let complete = fs::read("../client/test.tar").unwrap_or_else(unwrap_clean_up);
+    remove_test_tar();
+
+    // The proxy cuts the download, the compressed payload received so far is kept
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--quiet",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--tar",
+            "test.tar",
+        ])
+        .args(&link_args[1..])
+        .args(&["--server", "localhost:40791"])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    let interrupted = !receiver_output.status.success();
+    let kept = fs::metadata("../client/test.tar.sfshr-partial").map_or(0, |kept| kept.len());
+
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--quiet",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--tar",
+            "test.tar",
+            "--resume",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !receiver_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(receiver_output.stdout).unwrap()
+        );
+        panic!("Resumed receiver exited with a non-zero exit code.");
+    }
+    let leftover = Path::new("../client/test.tar.sfshr-partial").exists();
+    let resumed = fs::read("../client/test.tar").unwrap_or_else(unwrap_clean_up);
+    clean_up();
+    assert!(interrupted);
+    assert!(kept > 0);
+    assert!(!leftover);
+    assert!(resumed == complete);
+}
+
+#[test]
+fn resumed_recipient_download() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    unsafe {
+        SERVER = Some(start_server("../tests/tests/normal-config"));
+    }
+
+    wait_for_server();
+    generate_test_file();
+    let sender = Command::new("cargo")
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .args(&[
+            "run",
+            "--",
+            "--quiet",
+            "--server",
+            "localhost:40788",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--compression",
+            "none",
+            "--to",
+            "../tests/tests/recipient-a.pub.pem",
+            "test-file",
+        ])
+        .current_dir("../client")
+        .spawn()
+        .unwrap_or_else(unwrap_clean_up);
+    let sender_output = sender.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !sender_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(sender_output.stdout).unwrap()
+        );
+        panic!("Sender exited with non-zero exit code.");
+    }
+    remove_test_file();
+    let mut link = String::from_utf8(sender_output.stdout).unwrap_or_else(unwrap_clean_up);
+    link = link.replace('\n', "");
+    let link_args: Vec<&str> = link.split(' ').collect();
+
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--quiet",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--identity",
+            "../tests/tests/recipient-a.pem",
+            "--tar",
+            "test.tar",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !receiver_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(receiver_output.stdout).unwrap()
+        );
+        panic!("Receiver exited with a non-zero exit code.");
+    }
+    let complete = fs::read("../client/test.tar").unwrap_or_else(unwrap_clean_up);
+
+    // Simulate an interrupted download in the middle of a chunk
+    let file = OpenOptions::new()
+        .write(true)
+        .open("../client/test.tar")
+        .unwrap_or_else(unwrap_clean_up);
+    file.set_len(10 * 1024 * 1024 + 123)
+        .unwrap_or_else(unwrap_clean_up);
+
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--quiet",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--identity",
+            "../tests/tests/recipient-a.pem",
+            "--tar",
+            "test.tar",
+            "--resume",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !receiver_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(receiver_output.stdout).unwrap()
+        );
+        panic!("Resumed receiver exited with a non-zero exit code.");
+    }
+    let resumed = fs::read("../client/test.tar").unwrap_or_else(unwrap_clean_up);
+    clean_up();
+    assert!(resumed == complete);
+}
+
+#[test]
+fn multiple_listen_addresses() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    unsafe {
+        SERVER = Some(start_server("../tests/tests/listen-config"));
+    }
+
+    wait_for_server();
+    generate_test_file();
+    let sender = Command::new("cargo")
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .args(&[
+            "run",
+            "--",
+            "--quiet",
+            "--server",
+            "[::1]:40790",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "test-file",
+        ])
+        .current_dir("../client")
+        .spawn()
+        .unwrap_or_else(unwrap_clean_up);
+    let sender_output = sender.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !sender_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(sender_output.stdout).unwrap()
+        );
+        panic!("Sender exited with non-zero exit code.");
+    }
+    remove_test_file();
+    let mut link = String::from_utf8(sender_output.stdout).unwrap_or_else(unwrap_clean_up);
+    link = link.replace('\n', "");
+    let link_args: Vec<&str> = link.split(' ').collect();
+
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--quiet",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+        ])
+        .args(&link_args[1..])
+        .args(&["--server", "127.0.0.1:40788"])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !receiver_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(receiver_output.stdout).unwrap()
+        );
+        panic!("Receiver exited with a non-zero exit code.");
+    }
+    check_test_file("../client/test-file");
+    clean_up();
+}
+
+#[test]
+fn download_limit() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    unsafe {
+        SERVER = Some(start_server("../tests/tests/normal-config"));
+    }
+
+    wait_for_server();
+    generate_test_file();
+    let sender = Command::new("cargo")
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .args(&[
+            "run",
+            "--",
+            "--quiet",
+            "--server",
+            "localhost:40788",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--max-downloads",
+            "2",
+            "test-file",
+        ])
+        .current_dir("../client")
+        .spawn()
+        .unwrap_or_else(unwrap_clean_up);
+    let sender_output = sender.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !sender_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(sender_output.stdout).unwrap()
+        );
+        panic!("Sender exited with non-zero exit code.");
+    }
+    remove_test_file();
+    let mut link = String::from_utf8(sender_output.stdout).unwrap_or_else(unwrap_clean_up);
+    link = link.replace('\n', "");
+    let link_args: Vec<&str> = link.split(' ').collect();
+
+    // Downloading only the end of the file uses up a download as well,
+    // otherwise ranged downloads would read the file without a limit
+    let download_key =
+        base64::decode(link_args[link_args.len() - 1]).unwrap_or_else(unwrap_clean_up);
+    let info = sfshr::info("localhost:40788", &download_key[..32], Some(fingerprint()))
+        .unwrap_or_else(unwrap_clean_up);
+    let mut tail = Vec::new();
+    sfshr::Download::resume(
+        "localhost:40788",
+        &download_key[..32].try_into().unwrap(),
+        None,
+        info.size - 1,
+        Some(fingerprint()),
+    )
+    .unwrap_or_else(unwrap_clean_up)
+    .read_to_end(&mut tail)
+    .unwrap_or_else(unwrap_clean_up);
+    let downloads_left = sfshr::info("localhost:40788", &download_key[..32], Some(fingerprint()))
+        .unwrap_or_else(unwrap_clean_up)
+        .downloads_left;
+
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--quiet",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !receiver_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(receiver_output.stdout).unwrap()
+        );
+        panic!("Receiver exited with a non-zero exit code.");
+    }
+    check_test_file("../client/test-file");
+    remove_test_file();
+
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--quiet",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if receiver_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(receiver_output.stdout).unwrap()
+        );
+        panic!("Second receiver exited with a zero exit code.");
+    }
+    clean_up();
+    assert_eq!(downloads_left, 1);
+}
+
+#[test]
+fn legacy_header() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    unsafe {
+        SERVER = Some(start_server("../tests/tests/normal-config"));
+    }
+    wait_for_server();
+    generate_test_file();
+    let sender = Command::new("cargo")
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .args(&[
+            "run",
+            "--",
+            "--quiet",
+            "--server",
+            "localhost:40788",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--no-encryption",
+            "test-file",
+        ])
+        .current_dir("../client")
+        .spawn()
+        .unwrap_or_else(unwrap_clean_up);
+    let sender_output = sender.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !sender_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(sender_output.stdout).unwrap()
+        );
+        panic!("Sender exited with non-zero exit code.");
+    }
+    remove_test_file();
+    convert_to_legacy_header();
+    let mut link = String::from_utf8(sender_output.stdout).unwrap_or_else(unwrap_clean_up);
+    link = link.replace('\n', "");
+    let link_args: Vec<&str> = link.split(' ').collect();
+
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--quiet",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !receiver_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(receiver_output.stdout).unwrap()
+        );
+        panic!("Receiver exited with non-zero exit code.");
+    }
+    check_test_file("../client/test-file");
+    clean_up();
+}
+
+#[test]
+fn deleted_upload() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    unsafe {
+        SERVER = Some(start_server("../tests/tests/normal-config"));
+    }
+
+    wait_for_server();
+    generate_test_file();
+    let sender = Command::new("cargo")
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .args(&[
+            "run",
+            "--",
+            "--server",
+            "localhost:40788",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "test-file",
+        ])
+        .current_dir("../client")
+        .spawn()
+        .unwrap_or_else(unwrap_clean_up);
+    let sender_output = sender.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !sender_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(sender_output.stdout).unwrap()
+        );
+        panic!("Sender exited with non-zero exit code.");
+    }
+    remove_test_file();
+    let output = String::from_utf8(sender_output.stdout).unwrap_or_else(unwrap_clean_up);
+    let link = output
+        .lines()
+        .find(|line| line.starts_with("sfshr"))
+        .unwrap_or_else(|| unwrap_clean_up(()));
+    let link_args: Vec<&str> = link.split(' ').collect();
+    let delete_command = output
+        .lines()
+        .find_map(|line| line.strip_prefix("Delete with "))
+        .unwrap_or_else(|| unwrap_clean_up(()));
+    let delete_args: Vec<&str> = delete_command.split(' ').collect();
+
+    let deleter = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--quiet",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+        ])
+        .args(&delete_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+
+    let deleter_output = deleter.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !deleter_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(deleter_output.stdout).unwrap()
+        );
+        panic!("Deleter exited with a non-zero exit code.");
+    }
+
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--quiet",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if receiver_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(receiver_output.stdout).unwrap()
+        );
+        panic!("Receiver exited with a zero exit code.");
+    }
+    clean_up();
+}
+
+#[test]
+fn share_info() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    unsafe {
+        SERVER = Some(start_server("../tests/tests/normal-config"));
+    }
+
+    wait_for_server();
+    generate_test_file();
+    let sender = Command::new("cargo")
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .args(&[
+            "run",
+            "--",
+            "--quiet",
+            "--server",
+            "localhost:40788",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--max-downloads",
+            "3",
+            "test-file",
+        ])
+        .current_dir("../client")
+        .spawn()
+        .unwrap_or_else(unwrap_clean_up);
+    let sender_output = sender.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !sender_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(sender_output.stdout).unwrap()
+        );
+        panic!("Sender exited with non-zero exit code.");
+    }
+    remove_test_file();
+    let mut link = String::from_utf8(sender_output.stdout).unwrap_or_else(unwrap_clean_up);
+    link = link.replace('\n', "");
+    let link = link.replace(" -r ", " --info ");
+    let link_args: Vec<&str> = link.split(' ').collect();
+
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !receiver_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(receiver_output.stdout).unwrap()
+        );
+        panic!("Receiver exited with a non-zero exit code.");
+    }
+    let output = String::from_utf8(receiver_output.stdout).unwrap();
+    clean_up();
+    assert!(output.contains("Size: "));
+    assert!(output.contains("Expires at "));
+    assert!(output.contains("Downloads left: 3"));
+}
+
+#[test]
+fn password_transfer() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    unsafe {
+        SERVER = Some(start_server("../tests/tests/normal-config"));
+    }
+
+    wait_for_server();
+    generate_test_file();
+    let sender = Command::new("cargo")
+        .env("SFSHR_PASSWORD", "correct horse")
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .args(&[
+            "run",
+            "--",
+            "--quiet",
+            "--server",
+            "localhost:40788",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--password",
+            "test-file",
+        ])
+        .current_dir("../client")
+        .spawn()
+        .unwrap_or_else(unwrap_clean_up);
+    let sender_output = sender.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !sender_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(sender_output.stdout).unwrap()
+        );
+        panic!("Sender exited with non-zero exit code.");
+    }
+    remove_test_file();
+    let mut link = String::from_utf8(sender_output.stdout).unwrap_or_else(unwrap_clean_up);
+    link = link.replace('\n', "");
+    let link_args: Vec<&str> = link.split(' ').collect();
+
+    let receiver = Command::new("cargo")
+        .env("SFSHR_PASSWORD", "wrong horse")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--quiet",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if receiver_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(receiver_output.stdout).unwrap()
+        );
+        panic!("Receiver with a wrong password exited with a zero exit code.");
+    }
+    let receiver = Command::new("cargo")
+        .env("SFSHR_PASSWORD", "correct horse")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--quiet",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !receiver_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(receiver_output.stdout).unwrap()
+        );
+        panic!("Receiver exited with a non-zero exit code.");
+    }
+    check_test_file("../client/test-file");
+    clean_up();
+}
+
+#[test]
+fn recipient_transfer() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    unsafe {
+        SERVER = Some(start_server("../tests/tests/normal-config"));
+    }
+
+    wait_for_server();
+    generate_test_file();
+    let sender = Command::new("cargo")
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .args(&[
+            "run",
+            "--",
+            "--quiet",
+            "--server",
+            "localhost:40788",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--to",
+            "../tests/tests/recipient-a.pub.pem",
+            "--to",
+            "../tests/tests/recipient-b.pub.pem",
+            "test-file",
+        ])
+        .current_dir("../client")
+        .spawn()
+        .unwrap_or_else(unwrap_clean_up);
+    let sender_output = sender.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !sender_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(sender_output.stdout).unwrap()
+        );
+        panic!("Sender exited with non-zero exit code.");
+    }
+    remove_test_file();
+    let mut link = String::from_utf8(sender_output.stdout).unwrap_or_else(unwrap_clean_up);
+    link = link.replace('\n', "");
+    let link_args: Vec<&str> = link.split(' ').collect();
+
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--quiet",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--identity",
+            "../tests/tests/stranger.pem",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if receiver_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(receiver_output.stdout).unwrap()
+        );
+        panic!("Receiver with a foreign identity exited with a zero exit code.");
+    }
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--quiet",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--identity",
+            "../tests/tests/recipient-b.pem",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !receiver_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(receiver_output.stdout).unwrap()
+        );
+        panic!("Receiver exited with a non-zero exit code.");
+    }
+    check_test_file("../client/test-file");
+    clean_up();
+}
+
+#[test]
+fn manifest_confirmation() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    unsafe {
+        SERVER = Some(start_server("../tests/tests/normal-config"));
+    }
+
+    wait_for_server();
+    generate_test_file();
+    let sender = Command::new("cargo")
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .args(&[
+            "run",
+            "--",
+            "--quiet",
+            "--server",
+            "localhost:40788",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--message",
+            "Here is the test file",
+            "test-file",
+        ])
+        .current_dir("../client")
+        .spawn()
+        .unwrap_or_else(unwrap_clean_up);
+    let sender_output = sender.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !sender_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(sender_output.stdout).unwrap()
+        );
+        panic!("Sender exited with non-zero exit code.");
+    }
+    remove_test_file();
+    let mut link = String::from_utf8(sender_output.stdout).unwrap_or_else(unwrap_clean_up);
+    link = link.replace('\n', "");
+    let link_args: Vec<&str> = link.split(' ').collect();
+
+    // Without a terminal to ask on, the receiver needs --yes
+    let receiver = Command::new("cargo")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--quiet",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--output-dir",
+            "test-out",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    let output = String::from_utf8(receiver_output.stdout).unwrap_or_else(unwrap_clean_up);
+    if receiver_output.status.code() != Some(2) {
+        clean_up();
+        println!("---stdout---\n {}", output);
+        panic!("Receiver without a terminal did not refuse to ask.");
+    }
+    if !output.contains("Name: test-file") || !output.contains("Message: Here is the test file") {
+        clean_up();
+        println!("---stdout---\n {}", output);
+        panic!("Receiver did not show the manifest.");
+    }
+    if Path::new("../client/test-out").exists() {
+        clean_up();
+        panic!("Unconfirmed receiver created the output directory.");
+    }
+
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !receiver_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(receiver_output.stdout).unwrap()
+        );
+        panic!("Receiver exited with a non-zero exit code.");
+    }
+    check_test_file("../client/test-file");
+    clean_up();
+}
+
+#[test]
+fn compressed_transfer() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    unsafe {
+        SERVER = Some(start_server("../tests/tests/small-size-config"));
+    }
+
+    wait_for_server();
+    generate_test_file();
+    let sender = Command::new("cargo")
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .args(&[
+            "run",
+            "--",
+            "--quiet",
+            "--server",
+            "localhost:40788",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--compression",
+            "none",
+            "test-file",
+        ])
+        .current_dir("../client")
+        .spawn()
+        .unwrap_or_else(unwrap_clean_up);
+    let sender_output = sender.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if sender_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(sender_output.stdout).unwrap()
+        );
+        panic!("Uncompressed sender exited with a zero exit code.");
+    }
+
+    let sender = Command::new("cargo")
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .args(&[
+            "run",
+            "--",
+            "--quiet",
+            "--server",
+            "localhost:40788",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "--compression",
+            "gzip",
+            "--compress-level",
+            "9",
+            "test-file",
+        ])
+        .current_dir("../client")
+        .spawn()
+        .unwrap_or_else(unwrap_clean_up);
+    let sender_output = sender.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !sender_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(sender_output.stdout).unwrap()
+        );
+        panic!("Sender exited with non-zero exit code.");
+    }
+    remove_test_file();
+    let mut link = String::from_utf8(sender_output.stdout).unwrap_or_else(unwrap_clean_up);
+    link = link.replace('\n', "");
+    let link_args: Vec<&str> = link.split(' ').collect();
+
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--quiet",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !receiver_output.status.success() {
+        clean_up();
+        println!(
+            "---stdout---\n {}",
+            String::from_utf8(receiver_output.stdout).unwrap()
+        );
+        panic!("Receiver exited with a non-zero exit code.");
+    }
+    check_test_file("../client/test-file");
+    assert_eq!(
+        fs::metadata("../client/test-file")
+            .unwrap_or_else(unwrap_clean_up)
+            .len(),
+        1024 * 1024 * 64
+    );
+    clean_up();
+}
+
+#[test]
+fn stdin_stdout_transfer() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    unsafe {
+        SERVER = Some(start_server("../tests/tests/normal-config"));
     }
+
     wait_for_server();
-    generate_test_file();
-    let sender = Command::new("cargo")
+    let data: Vec<u8> = (0..8 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let mut sender = Command::new("cargo")
+        .stdin(Stdio::piped())
         .stderr(Stdio::inherit())
         .stdout(Stdio::piped())
         .args(&[
@@ -236,33 +2340,70 @@ fn size_exceeded() {
             "localhost:40788",
             "--fingerprint",
             "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
-            "--no-encryption",
-            "test-file",
+            "-",
         ])
         .current_dir("../client")
         .spawn()
         .unwrap_or_else(unwrap_clean_up);
+    sender
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(&data)
+        .unwrap_or_else(unwrap_clean_up);
     let sender_output = sender.wait_with_output().unwrap_or_else(unwrap_clean_up);
-    if sender_output.status.success() {
+    if !sender_output.status.success() {
         clean_up();
         println!(
             "---stdout---\n {}",
             String::from_utf8(sender_output.stdout).unwrap()
         );
-        panic!("Sender exited with a zero exit code.");
+        panic!("Sender exited with non-zero exit code.");
     }
-    remove_test_file();
+    let mut link = String::from_utf8(sender_output.stdout).unwrap_or_else(unwrap_clean_up);
+    link = link.replace('\n', "");
+    let link_args: Vec<&str> = link.split(' ').collect();
+
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--stdout",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if !receiver_output.status.success() {
+        clean_up();
+        println!(
+            "---stderr---\n {}",
+            String::from_utf8_lossy(&receiver_output.stderr)
+        );
+        panic!("Receiver exited with a non-zero exit code.");
+    }
+    let progress = String::from_utf8_lossy(&receiver_output.stderr);
     clean_up();
+    assert!(progress.contains("Name: stdin"));
+    assert!(receiver_output.stdout == data);
 }
 
 #[test]
-fn directory() {
+fn multiple_paths() {
     let _guard = MUTEX.deref().lock().unwrap();
     unsafe {
         SERVER = Some(start_server("../tests/tests/normal-config"));
     }
 
     wait_for_server();
+    generate_test_file();
     generate_test_dir();
     let sender = Command::new("cargo")
         .stderr(Stdio::inherit())
@@ -275,7 +2416,8 @@ fn directory() {
             "localhost:40788",
             "--fingerprint",
             "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
-            "test-dir",
+            "test-file",
+            "test-d*",
         ])
         .current_dir("../client")
         .spawn()
@@ -289,6 +2431,7 @@ fn directory() {
         );
         panic!("Sender exited with non-zero exit code.");
     }
+    remove_test_file();
     remove_test_dir();
     let mut link = String::from_utf8(sender_output.stdout).unwrap_or_else(unwrap_clean_up);
     link = link.replace('\n', "");
@@ -300,6 +2443,7 @@ fn directory() {
         .args(&[
             "run",
             "--",
+            "--yes",
             "--quiet",
             "--fingerprint",
             "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
@@ -318,16 +2462,68 @@ fn directory() {
         );
         panic!("Receiver exited with a non-zero exit code.");
     }
+    check_test_file("../client/test-file");
     check_test_dir();
+    remove_test_dir();
+
+    // A single existing destination stops the whole download
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--quiet",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    if receiver_output.status.success() {
+        clean_up();
+        panic!("Receiver overwrote an existing file.");
+    }
+    let dir_written = Path::new("../client/test-dir").exists();
     clean_up();
+    assert!(!dir_written);
 }
 
 #[test]
-fn fingerprint_mismatch() {
+fn manifest_limits() {
+    // Names that do not fit into the manifest are refused instead of being cut off
+    let long_name = sfshr::Manifest::stream("a".repeat(65536), None, sfshr::Codec::None);
+    let many_roots = sfshr::Manifest::new(
+        vec![String::from("a"); 65537],
+        0,
+        0,
+        None,
+        sfshr::Codec::None,
+    );
+    let most_roots = sfshr::Manifest::new(
+        vec![String::from("a"); 65536],
+        0,
+        0,
+        None,
+        sfshr::Codec::None,
+    );
+
+    assert!(long_name.is_err());
+    assert!(many_roots.is_err());
+    assert!(most_roots.is_ok());
+}
+
+#[test]
+fn unsafe_archive() {
     let _guard = MUTEX.deref().lock().unwrap();
     unsafe {
         SERVER = Some(start_server("../tests/tests/normal-config"));
     }
+
     wait_for_server();
     generate_test_file();
     let sender = Command::new("cargo")
@@ -340,24 +2536,711 @@ fn fingerprint_mismatch() {
             "--server",
             "localhost:40788",
             "--fingerprint",
-            "0aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
             "--no-encryption",
+            "--compression",
+            "none",
             "test-file",
         ])
         .current_dir("../client")
         .spawn()
         .unwrap_or_else(unwrap_clean_up);
     let sender_output = sender.wait_with_output().unwrap_or_else(unwrap_clean_up);
-    if sender_output.status.success() {
+    if !sender_output.status.success() {
         clean_up();
         println!(
             "---stdout---\n {}",
             String::from_utf8(sender_output.stdout).unwrap()
         );
-        panic!("Sender exited with a zero exit code.");
+        panic!("Sender exited with non-zero exit code.");
+    }
+    remove_test_file();
+    let mut link = String::from_utf8(sender_output.stdout).unwrap_or_else(unwrap_clean_up);
+    link = link.replace('\n', "");
+    let link_args: Vec<&str> = link.split(' ').collect();
+
+    // Replace the upload with an archive a malicious sender could have made
+    let mut archive = Vec::new();
+    archive.extend(tar_entry("safe/file.txt", b'0', "", b"ok"));
+    archive.extend(tar_entry("safe/inner", b'2', "file.txt", b""));
+    archive.extend(tar_entry("safe/outer", b'2', "../../outside", b""));
+    // Each link stays inside on its own, but the second one leads out through the first
+    archive.extend(tar_entry("safe/up", b'2', "..", b""));
+    archive.extend(tar_entry("safe/chained", b'2', "up/../secret", b""));
+    archive.extend(tar_entry("safe/device", b'3', "", b""));
+    archive.extend(tar_entry("../escaped.txt", b'0', "", b"escaped"));
+    archive.extend(tar_entry("/tmp/sfshr-absolute.txt", b'0', "", b"absolute"));
+    archive.extend_from_slice(&[0; 1024]);
+    let entry = fs::read_dir("../server/test-uploads")
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap();
+    let content = fs::read(entry.path()).unwrap();
+    let mut legacy = content[16..24].to_vec();
+    legacy.extend_from_slice(&archive);
+    fs::write(entry.path(), legacy).unwrap();
+
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--quiet",
+            "--output-dir",
+            "test-out",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    let output = String::from_utf8(receiver_output.stdout).unwrap_or_else(unwrap_clean_up);
+    if receiver_output.status.success() {
+        clean_up();
+        println!("---stdout---\n {}", output);
+        panic!("Receiver did not report the rejected entries.");
+    }
+    let extracted = fs::read("../client/test-out/safe/file.txt").unwrap_or_default();
+    let inner = fs::read("../client/test-out/safe/inner").unwrap_or_default();
+    let outer = Path::new("../client/test-out/safe/outer").symlink_metadata();
+    let chained = Path::new("../client/test-out/safe/chained").symlink_metadata();
+    let device = Path::new("../client/test-out/safe/device").exists();
+    let escaped = Path::new("../client/escaped.txt").exists();
+    let absolute = Path::new("/tmp/sfshr-absolute.txt").exists();
+
+    // Existing names are kept apart with --rename
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--quiet",
+            "--output-dir",
+            "test-out",
+            "--rename",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+    receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    let renamed = fs::read("../client/test-out/safe (1)/file.txt").unwrap_or_default();
+
+    // Names already in the output directory can neither lead out nor be linked to
+    fs::create_dir_all("../client/test-outside").unwrap_or_else(unwrap_clean_up);
+    std::os::unix::fs::symlink("../test-outside", "../client/test-out/escape")
+        .unwrap_or_else(unwrap_clean_up);
+    fs::write("../client/test-out/existing.txt", b"mine").unwrap_or_else(unwrap_clean_up);
+    let mut archive = Vec::new();
+    archive.extend(tar_entry("escape/sub/file.txt", b'0', "", b"escaped"));
+    archive.extend(tar_entry("linked/file.txt", b'1', "existing.txt", b""));
+    archive.extend_from_slice(&[0; 1024]);
+    let mut legacy = content[16..24].to_vec();
+    legacy.extend_from_slice(&archive);
+    fs::write(entry.path(), legacy).unwrap();
+
+    let receiver = Command::new("cargo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .args(&[
+            "run",
+            "--",
+            "--yes",
+            "--quiet",
+            "--output-dir",
+            "test-out",
+            "--skip-existing",
+            "--fingerprint",
+            "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+        ])
+        .args(&link_args[1..])
+        .current_dir("../client")
+        .spawn()
+        .unwrap();
+    let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+    let existing_output = String::from_utf8(receiver_output.stdout).unwrap_or_else(unwrap_clean_up);
+    let outside = Path::new("../client/test-outside/sub").exists();
+    let linked = Path::new("../client/test-out/linked/file.txt").exists();
+    let _ = fs::remove_dir_all("../client/test-outside");
+    clean_up();
+
+    assert_eq!(extracted, b"ok");
+    assert_eq!(inner, b"ok");
+    assert!(outer.is_err());
+    assert!(chained.is_err());
+    assert!(!device);
+    assert!(!escaped);
+    assert!(!absolute);
+    assert_eq!(output.matches("Rejected").count(), 5);
+    assert_eq!(renamed, b"ok");
+    assert!(!outside);
+    assert!(!linked);
+    assert_eq!(existing_output.matches("Rejected").count(), 2);
+}
+
+#[test]
+fn library_api() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    unsafe {
+        SERVER = Some(start_server("../tests/tests/normal-config"));
     }
+
+    wait_for_server();
+    generate_test_file();
+    let fingerprint = fingerprint();
+
+    let uploaded = Arc::new(AtomicU64::new(0));
+    let reported = uploaded.clone();
+    let share = sfshr::share_path(
+        "localhost:40788",
+        &[PathBuf::from("../client/test-file")],
+        sfshr::UploadOptions {
+            message: Some(String::from("Sent from a library")),
+            fingerprint: Some(fingerprint.clone()),
+            ..Default::default()
+        },
+        Some(Box::new(move |progress| {
+            if let sfshr::Progress::Transferred { bytes, .. } = progress {
+                reported.store(bytes, Ordering::Relaxed);
+            }
+        })),
+    )
+    .unwrap_or_else(unwrap_clean_up);
     remove_test_file();
+
+    // Declining the manifest writes nothing
+    let declined = sfshr::fetch_to(
+        "localhost:40788",
+        &share.download_key,
+        sfshr::Target::Directory {
+            path: PathBuf::from("../client/test-out"),
+            policy: sfshr::ExistingPolicy::Refuse,
+        },
+        sfshr::FetchOptions {
+            fingerprint: Some(fingerprint.clone()),
+            confirm: Some(Box::new(|_| false)),
+            ..Default::default()
+        },
+        None,
+    );
+    let cancelled = matches!(declined, Err(sfshr::ShareError::Cancelled));
+    let written = Path::new("../client/test-out").exists();
+
+    // Resuming asks for the same confirmation
+    let uncompressed = sfshr::share_reader(
+        "localhost:40788",
+        "resumable",
+        &b"resumable stream"[..],
+        sfshr::UploadOptions {
+            codec: sfshr::Codec::None,
+            fingerprint: Some(fingerprint.clone()),
+            ..Default::default()
+        },
+        None,
+    )
+    .unwrap_or_else(unwrap_clean_up);
+    let resume_declined = sfshr::fetch_to(
+        "localhost:40788",
+        &uncompressed.download_key,
+        sfshr::Target::ResumeFile(PathBuf::from("../client/test.tar")),
+        sfshr::FetchOptions {
+            fingerprint: Some(fingerprint.clone()),
+            confirm: Some(Box::new(|_| false)),
+            ..Default::default()
+        },
+        None,
+    );
+    let resume_cancelled = matches!(resume_declined, Err(sfshr::ShareError::Cancelled));
+    let resume_written = Path::new("../client/test.tar").exists();
+
+    // Compressed share cannot continue a partial file without its kept payload,
+    // the partial file is left alone
+    fs::write("../client/test.tar", b"partial").unwrap_or_else(unwrap_clean_up);
+    let resume_compressed = sfshr::fetch_to(
+        "localhost:40788",
+        &share.download_key,
+        sfshr::Target::ResumeFile(PathBuf::from("../client/test.tar")),
+        sfshr::FetchOptions {
+            fingerprint: Some(fingerprint.clone()),
+            confirm: Some(Box::new(|_| true)),
+            ..Default::default()
+        },
+        None,
+    );
+    let resume_refused = matches!(resume_compressed, Err(sfshr::ShareError::Unsupported(_)));
+    let partial = fs::read("../client/test.tar").unwrap_or_default();
+
+    // Identity is only for downloading, an upload with it could never be decrypted
+    let identity = openssl::pkey::PKey::private_key_from_pem(
+        &fs::read("../tests/tests/recipient-a.pem").unwrap_or_else(unwrap_clean_up),
+    )
+    .unwrap_or_else(unwrap_clean_up);
+    let identity_upload = sfshr::share_reader(
+        "localhost:40788",
+        "undecryptable",
+        &b"undecryptable stream"[..],
+        sfshr::UploadOptions {
+            encryption: sfshr::Encryption::Identity(identity),
+            fingerprint: Some(fingerprint.clone()),
+            ..Default::default()
+        },
+        None,
+    );
+    let identity_refused = matches!(identity_upload, Err(sfshr::ShareError::Unsupported(_)));
+
+    // Flushing the compressor flushes the upload under it
+    let upload = sfshr::Upload::new(
+        "localhost:40788",
+        true,
+        0,
+        sfshr::ShareOptions::default(),
+        &[],
+        Some(fingerprint.clone()),
+    )
+    .unwrap_or_else(unwrap_clean_up);
+    let mut compressor = sfshr::compression::compression::Compressor::new(
+        sfshr::Codec::Zstd,
+        sfshr::Codec::Zstd.default_level(),
+        upload,
+    )
+    .unwrap_or_else(unwrap_clean_up);
+    compressor
+        .write_all(b"flushed ")
+        .unwrap_or_else(unwrap_clean_up);
+    compressor.flush().unwrap_or_else(unwrap_clean_up);
+    compressor
+        .write_all(b"upload")
+        .unwrap_or_else(unwrap_clean_up);
+    let mut upload = compressor.finish().unwrap_or_else(unwrap_clean_up);
+    upload.finalize().unwrap_or_else(unwrap_clean_up);
+    let mut flushed = Vec::new();
+    sfshr::compression::compression::Decompressor::new(
+        sfshr::Codec::Zstd,
+        sfshr::Download::new(
+            "localhost:40788",
+            &upload.id()[..].try_into().unwrap(),
+            upload.key().copied(),
+            Some(fingerprint.clone()),
+        )
+        .unwrap_or_else(unwrap_clean_up),
+    )
+    .unwrap_or_else(unwrap_clean_up)
+    .read_to_end(&mut flushed)
+    .unwrap_or_else(unwrap_clean_up);
+
+    let fetched = sfshr::fetch_to(
+        "localhost:40788",
+        &share.download_key,
+        sfshr::Target::Directory {
+            path: PathBuf::from("../client/test-out"),
+            policy: sfshr::ExistingPolicy::Refuse,
+        },
+        sfshr::FetchOptions {
+            fingerprint: Some(fingerprint),
+            confirm: Some(Box::new(|manifest| {
+                manifest.message.as_deref() == Some("Sent from a library")
+            })),
+            ..Default::default()
+        },
+        None,
+    )
+    .unwrap_or_else(unwrap_clean_up);
+    let roots = fetched.roots.len();
+    let rejected = fetched.rejected.len();
+    let content = fs::read("../client/test-out/test-file").unwrap_or_default();
+    clean_up();
+
+    assert!(share.delete_key.is_some());
+    assert!(uploaded.load(Ordering::Relaxed) > 0);
+    assert!(cancelled);
+    assert!(!written);
+    assert!(resume_cancelled);
+    assert!(!resume_written);
+    assert!(resume_refused);
+    assert_eq!(partial, b"partial");
+    assert!(identity_refused);
+    assert_eq!(flushed, b"flushed upload");
+    assert_eq!(roots, 1);
+    assert_eq!(rejected, 0);
+    assert_eq!(content.len(), 1024 * 1024 * 64);
+    assert!(content.iter().all(|&byte| byte == 12));
+}
+
+#[test]
+fn async_transfers() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    unsafe {
+        SERVER = Some(start_server("../tests/tests/small-size-config"));
+    }
+
+    wait_for_server();
+    let fingerprint = fingerprint();
+    let runtime = tokio::runtime::Runtime::new().unwrap_or_else(unwrap_clean_up);
+
+    // Several uploads and downloads share the runtime's threads
+    let uploads: Vec<_> = (0..4u8)
+        .map(|i| {
+            let fingerprint = fingerprint.clone();
+            runtime.spawn(async move {
+                let mut upload = sfshr::AsyncUpload::new(
+                    "localhost:40788",
+                    true,
+                    0,
+                    sfshr::ShareOptions::default(),
+                    &[],
+                    Some(fingerprint),
+                )
+                .await?;
+                upload.write_all(&vec![i; 512 * 1024]).await?;
+                upload.finalize().await?;
+                Ok::<_, sfshr::TransferError>((upload.id().clone(), *upload.key().unwrap()))
+            })
+        })
+        .collect();
+    let mut keys = Vec::new();
+    for upload in uploads {
+        match runtime.block_on(upload).unwrap_or_else(unwrap_clean_up) {
+            Ok(key) => keys.push(key),
+            Err(err) => {
+                clean_up();
+                panic!("Upload failed: {}", err);
+            }
+        }
+    }
+
+    let downloads: Vec<_> = keys
+        .iter()
+        .map(|(id, key)| {
+            let fingerprint = fingerprint.clone();
+            let id: [u8; 32] = id[..].try_into().unwrap();
+            let key = *key;
+            runtime.spawn(async move {
+                let mut download =
+                    sfshr::AsyncDownload::new("localhost:40788", &id, Some(key), Some(fingerprint))
+                        .await?;
+                let mut content = Vec::new();
+                download.read_to_end(&mut content).await?;
+                Ok::<_, io::Error>(content)
+            })
+        })
+        .collect();
+    let mut contents = Vec::new();
+    for download in downloads {
+        contents.push(
+            runtime
+                .block_on(download)
+                .unwrap_or_else(unwrap_clean_up)
+                .unwrap_or_else(unwrap_clean_up),
+        );
+    }
+
+    // The blocking client reads what the async one uploaded
+    let (id, key) = &keys[3];
+    let mut blocking = Vec::new();
+    sfshr::Download::new(
+        "localhost:40788",
+        &id[..].try_into().unwrap(),
+        Some(*key),
+        Some(fingerprint),
+    )
+    .unwrap_or_else(unwrap_clean_up)
+    .read_to_end(&mut blocking)
+    .unwrap_or_else(unwrap_clean_up);
     clean_up();
+
+    for (i, content) in contents.iter().enumerate() {
+        assert_eq!(content.len(), 512 * 1024);
+        assert!(content.iter().all(|&byte| byte == i as u8));
+    }
+    assert_eq!(blocking, contents[3]);
+}
+
+#[test]
+fn async_framing() {
+    let _guard = MUTEX.deref().lock().unwrap();
+
+    // simpletcp's own server stores one upload and serves it back, so the async
+    // transfers are checked against the framing they reimplement
+    let payload: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let server = simpletcp::simpletcp::TcpServer::new("localhost:40790").unwrap();
+    let served = spawn(move || {
+        let accept = || {
+            let mut conn = server.accept_blocking().unwrap();
+            conn.wait_until_ready().unwrap();
+            let mut hello = conn.read_blocking().unwrap();
+            assert_eq!(hello.read_i32().unwrap(), COMMAND_HELLO);
+            let mut response = Message::new();
+            response.write_i8(1);
+            response.write_u32(1);
+            response.write_u64(0);
+            conn.write_blocking(&response).unwrap();
+            conn
+        };
+
+        let mut conn = accept();
+        let mut request = conn.read_blocking().unwrap();
+        assert_eq!(request.read_i32().unwrap(), COMMAND_UPLOAD);
+        let mut response = Message::new();
+        response.write_buffer(&[7; 32]);
+        response.write_u64(u64::MAX);
+        conn.write_blocking(&response).unwrap();
+        let mut stored = Vec::new();
+        loop {
+            let mut message = conn.read_blocking().unwrap();
+            if message.read_u8().unwrap() == 0 {
+                break;
+            }
+            stored.extend_from_slice(message.read_buffer().unwrap());
+        }
+        let mut confirmation = Message::new();
+        confirmation.write_i8(1);
+        conn.write_blocking(&confirmation).unwrap();
+
+        let mut conn = accept();
+        let mut request = conn.read_blocking().unwrap();
+        assert_eq!(request.read_i32().unwrap(), COMMAND_DOWNLOAD);
+        assert_eq!(request.read_buffer().unwrap(), &[7; 32]);
+        for part in stored.chunks(1024 * 1024) {
+            let mut message = Message::new();
+            message.write_u8(1);
+            message.write_buffer(part);
+            conn.write_blocking(&message).unwrap();
+        }
+        let mut end = Message::new();
+        end.write_u8(0);
+        conn.write_blocking(&end).unwrap();
+        conn.read_timeout(5000).ok();
+        stored
+    });
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let uploaded = payload.clone();
+    let downloaded = runtime.block_on(async move {
+        let mut upload = sfshr::AsyncUpload::new(
+            "localhost:40790",
+            false,
+            0,
+            sfshr::ShareOptions::default(),
+            &[],
+            None,
+        )
+        .await?;
+        upload.write_all(&uploaded).await?;
+        upload.finalize().await?;
+
+        let id: [u8; 32] = upload.id()[..].try_into().unwrap();
+        let mut download = sfshr::AsyncDownload::new("localhost:40790", &id, None, None).await?;
+        let mut content = Vec::new();
+        download.read_to_end(&mut content).await?;
+        Ok::<_, io::Error>(content)
+    });
+    let stored = served.join().unwrap();
+
+    assert!(stored == payload, "Server stored different data");
+    assert!(downloaded.unwrap() == payload, "Downloaded different data");
+}
+
+// Forwards connections from `from` to `to`, the first connection is dropped
+// after the client sent `limit` bytes
+#[test]
+fn exit_codes() {
+    let _guard = MUTEX.deref().lock().unwrap();
+    unsafe {
+        SERVER = Some(start_server("../tests/tests/normal-config"));
+    }
+
+    wait_for_server();
+    let missing_key = format!("{}==", "A".repeat(86));
+    // Share the server fails to read, a directory stands in place of its file
+    let broken_key = base64::encode([[0x11u8; 32], [0u8; 32]].concat());
+    fs::create_dir_all(format!("../server/test-uploads/{}", "11".repeat(32)))
+        .unwrap_or_else(unwrap_clean_up);
+    let cases: Vec<(Vec<&str>, i32)> = vec![
+        // Share that does not exist is reported like an expired one
+        (
+            vec![
+                "--server",
+                "localhost:40788",
+                "--fingerprint",
+                "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+                "-r",
+                &missing_key,
+            ],
+            6,
+        ),
+        // Failed file operation on the server is a server error, not an expired link
+        (
+            vec![
+                "--server",
+                "localhost:40788",
+                "--fingerprint",
+                "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+                "-r",
+                &broken_key,
+            ],
+            5,
+        ),
+        (
+            vec![
+                "--server",
+                "localhost:40789",
+                "--fingerprint",
+                "8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1",
+                "-r",
+                &missing_key,
+            ],
+            3,
+        ),
+        (
+            vec![
+                "--server",
+                "localhost:40788",
+                "--fingerprint",
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                "-r",
+                &missing_key,
+            ],
+            4,
+        ),
+        (vec!["--max-downloads", "none"], 2),
+    ];
+
+    let mut codes = Vec::new();
+    for (args, _) in &cases {
+        let receiver = Command::new("cargo")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .args(&["run", "--", "--quiet"])
+            .args(args)
+            .current_dir("../client")
+            .spawn()
+            .unwrap_or_else(unwrap_clean_up);
+        let receiver_output = receiver.wait_with_output().unwrap_or_else(unwrap_clean_up);
+        codes.push(receiver_output.status.code());
+    }
+    clean_up();
+
+    for ((args, expected), code) in cases.iter().zip(codes) {
+        assert_eq!(code, Some(*expected), "Unexpected exit code for {:?}", args);
+    }
+}
+
+/// Forwards `from` to `to`, the first connection is cut after `upload_limit` bytes
+/// sent to the server or `download_limit` bytes sent back
+fn start_flaky_proxy(
+    from: &str,
+    to: &str,
+    upload_limit: Option<usize>,
+    download_limit: Option<usize>,
+) {
+    let listener = TcpListener::bind(from).unwrap();
+    let to = to.to_string();
+    spawn(move || {
+        let mut limits = Some((upload_limit, download_limit));
+        for client in listener.incoming() {
+            let client = client.unwrap();
+            let server = TcpStream::connect(&to).unwrap();
+            let mut client_read = client.try_clone().unwrap();
+            let mut server_write = server.try_clone().unwrap();
+            let mut server_read = server.try_clone().unwrap();
+            let mut client_write = client.try_clone().unwrap();
+            let (connection_limit, download_limit) = limits.take().unwrap_or((None, None));
+            spawn(move || {
+                let mut buffer = [0; 4096];
+                let mut forwarded = 0;
+                loop {
+                    let bytes_read = client_read.read(&mut buffer).unwrap_or(0);
+                    if bytes_read == 0 || server_write.write_all(&buffer[..bytes_read]).is_err() {
+                        break;
+                    }
+                    forwarded += bytes_read;
+                    if connection_limit.is_some_and(|limit| forwarded >= limit) {
+                        break;
+                    }
+                }
+                let _ = client.shutdown(Shutdown::Both);
+                let _ = server.shutdown(Shutdown::Both);
+            });
+            spawn(move || match download_limit {
+                None => {
+                    let _ = io::copy(&mut server_read, &mut client_write);
+                }
+                Some(limit) => {
+                    let _ = io::copy(
+                        &mut (&mut server_read).take(limit as u64),
+                        &mut client_write,
+                    );
+                    let _ = client_write.shutdown(Shutdown::Both);
+                }
+            });
+        }
+    });
+}
+
+fn tamper_uploaded_file(offset: u64) {
+    let entry = fs::read_dir("../server/test-uploads")
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap();
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(entry.path())
+        .unwrap();
+    let mut byte = [0; 1];
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.read_exact(&mut byte).unwrap();
+    byte[0] ^= 1;
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.write_all(&byte).unwrap();
+}
+
+// Rewrites the uploaded file to the format used before the versioned header,
+// which started with just the expiration timestamp
+fn convert_to_legacy_header() {
+    let entry = fs::read_dir("../server/test-uploads")
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap();
+    let content = fs::read(entry.path()).unwrap();
+    let mut legacy = content[16..24].to_vec();
+    legacy.extend_from_slice(&content[72..]);
+    fs::write(entry.path(), legacy).unwrap();
+}
+
+// Builds a ustar entry without any of the checks a tar library would do
+fn tar_entry(path: &str, kind: u8, link: &str, data: &[u8]) -> Vec<u8> {
+    let mut header = [0; 512];
+    header[..path.len()].copy_from_slice(path.as_bytes());
+    header[100..108].copy_from_slice(b"0000644\0");
+    header[108..116].copy_from_slice(b"0000000\0");
+    header[116..124].copy_from_slice(b"0000000\0");
+    header[124..136].copy_from_slice(format!("{:011o}\0", data.len()).as_bytes());
+    header[136..148].copy_from_slice(b"00000000000\0");
+    header[148..156].copy_from_slice(b"        ");
+    header[156] = kind;
+    header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    let checksum: u32 = header.iter().map(|&byte| byte as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+
+    let mut entry = header.to_vec();
+    entry.extend_from_slice(data);
+    entry.resize(entry.len().div_ceil(512) * 512, 0);
+    entry
 }
 
 fn remove_test_file() {
@@ -367,6 +3250,17 @@ fn remove_test_file() {
     }
 }
 
+fn remove_test_tar() {
+    let p = Path::new("../client/test.tar");
+    if p.exists() {
+        fs::remove_file(p).unwrap();
+    }
+    let p = Path::new("../client/test.tar.sfshr-partial");
+    if p.exists() {
+        fs::remove_file(p).unwrap();
+    }
+}
+
 fn remove_test_dir() {
     let p = Path::new("../client/test-dir");
     if p.exists() {
@@ -374,6 +3268,13 @@ fn remove_test_dir() {
     }
 }
 
+fn remove_test_out() {
+    let p = Path::new("../client/test-out");
+    if p.exists() {
+        fs::remove_dir_all(p).unwrap();
+    }
+}
+
 fn generate_test_file() {
     let mut file = File::create("../client/test-file").unwrap();
     let mut buffer = Vec::new();
@@ -422,7 +3323,9 @@ fn unwrap_clean_up<T, E>(_: E) -> T {
 
 fn clean_up() {
     remove_test_dir();
+    remove_test_out();
     remove_test_file();
+    remove_test_tar();
     let uploads = Path::new("../server/test-uploads");
     if uploads.exists() {
         fs::remove_dir_all(uploads).unwrap();
@@ -434,6 +3337,20 @@ fn clean_up() {
     }
 }
 
+/// Fingerprint of the test server key, as the library expects it
+fn fingerprint() -> Vec<u8> {
+    (0..64)
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(
+                &"8aa10297d4d6e3534f834a64c60c749e4941d6731be45f4dbd1da221f25607f1"[i..i + 2],
+                16,
+            )
+            .unwrap()
+        })
+        .collect()
+}
+
 fn start_server(config: &str) -> Child {
     Command::new("cargo")
         .stderr(Stdio::inherit())
@@ -451,16 +3368,21 @@ fn start_server(config: &str) -> Child {
         .unwrap_or_else(unwrap_clean_up)
 }
 
+// Reads the output of the server until it is listening
 fn wait_for_server() {
-    let mut buffer = [0; 6];
-    unsafe {
-        SERVER
-            .as_mut()
-            .unwrap()
-            .stdout
-            .as_mut()
-            .unwrap()
-            .read_exact(&mut buffer)
-            .unwrap();
+    let mut output = Vec::new();
+    let mut buffer = [0; 1];
+    while !output.ends_with(b"ready\n") {
+        unsafe {
+            SERVER
+                .as_mut()
+                .unwrap()
+                .stdout
+                .as_mut()
+                .unwrap()
+                .read_exact(&mut buffer)
+                .unwrap();
+        }
+        output.push(buffer[0]);
     }
 }